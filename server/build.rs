@@ -9,7 +9,7 @@ use catte_tl_compiler::{
     tokenizer::tokenize,
 };
 
-fn generate_invoke_code(definitions: &Vec<Definition>) -> String {
+fn generate_invoke_code(definitions: &[Definition]) -> String {
     format!(
         r#"use std::sync::Arc;
 use tokio::sync::Mutex;
@@ -1,44 +1,206 @@
-PRAGMA user_version = 1;
+PRAGMA user_version = 27;
 PRAGMA journal_mode = WAL;
 
 CREATE TABLE IF NOT EXISTS auth_keys (
     id INTEGER PRIMARY KEY,
-    auth_key BLOB
+    auth_key BLOB NOT NULL,
+    dc_id INTEGER NOT NULL,
+    temp INTEGER NOT NULL,
+    expires_at INTEGER,
+    created_at INTEGER NOT NULL,
+    last_used_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS exported_authorizations (
+    id INTEGER PRIMARY KEY NOT NULL,
+    bytes BLOB NOT NULL,
+    user_id INTEGER NOT NULL,
+    dc_id INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS login_tokens (
+    token BLOB PRIMARY KEY NOT NULL,
+    auth_key_id INTEGER NOT NULL,
+    user_id INTEGER,
+    expires_at INTEGER NOT NULL
 );
 
 CREATE TABLE IF NOT EXISTS messages (
-    id INTEGER PRIMARY KEY AUTOINCREMENT,
-    mb_key_primary INTEGER NOT NULL,
-    mb_key_secondary INTEGER,
-    message_id INTEGER NOT NULL,
+    -- Alias of the rowid, which keeps it stable across VACUUM for the full-text index
+    row_id INTEGER PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    id INTEGER NOT NULL,
     peer_id INTEGER NOT NULL,
-    from_id INTEGER,
+    from_id INTEGER NOT NULL,
+    out INTEGER NOT NULL,
+    mentioned INTEGER NOT NULL DEFAULT 0,
+    media_unread INTEGER NOT NULL DEFAULT 0,
     message TEXT NOT NULL,
-    date INTEGER NOT NULL
+    date INTEGER NOT NULL,
+    edit_date INTEGER,
+    reply_to_msg_id INTEGER,
+    reply_to_top_id INTEGER,
+    fwd_from BLOB,
+    entities BLOB,
+    has_url INTEGER NOT NULL DEFAULT 0,
+    pinned INTEGER NOT NULL DEFAULT 0,
+    from_scheduled INTEGER NOT NULL DEFAULT 0,
+    -- Set for service messages, which have no text
+    action BLOB,
+    peer_message_id INTEGER,
+    UNIQUE (user_id, id)
+);
+
+-- Full-text index of message texts, kept in sync with messages by the triggers below
+CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5(
+    message,
+    content = 'messages',
+    content_rowid = 'row_id',
+    tokenize = 'unicode61 remove_diacritics 2'
+);
+
+CREATE TRIGGER IF NOT EXISTS messages_fts_insert AFTER INSERT ON messages BEGIN
+    INSERT INTO messages_fts (rowid, message) VALUES (new.row_id, new.message);
+END;
+
+CREATE TRIGGER IF NOT EXISTS messages_fts_delete AFTER DELETE ON messages BEGIN
+    INSERT INTO messages_fts (messages_fts, rowid, message) VALUES ('delete', old.row_id, old.message);
+END;
+
+CREATE TRIGGER IF NOT EXISTS messages_fts_update AFTER UPDATE OF message ON messages BEGIN
+    INSERT INTO messages_fts (messages_fts, rowid, message) VALUES ('delete', old.row_id, old.message);
+    INSERT INTO messages_fts (rowid, message) VALUES (new.row_id, new.message);
+END;
+
+-- Messages waiting to be sent, their ids are separate from regular message ids
+CREATE TABLE IF NOT EXISTS scheduled_messages (
+    user_id INTEGER NOT NULL,
+    id INTEGER NOT NULL,
+    peer_id INTEGER NOT NULL,
+    message TEXT NOT NULL,
+    entities BLOB,
+    reply_to_msg_id INTEGER,
+    -- 0x7FFFFFFE sends the message once the peer comes online
+    date INTEGER NOT NULL,
+    silent INTEGER NOT NULL,
+    -- 1 while the message is being sent
+    sending INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (user_id, id)
+);
+
+CREATE INDEX IF NOT EXISTS scheduled_messages_date ON scheduled_messages (date);
+
+CREATE TABLE IF NOT EXISTS random_ids (
+    user_id INTEGER NOT NULL,
+    random_id INTEGER NOT NULL,
+    -- 0 while the message is being sent
+    message_id INTEGER NOT NULL,
+    pts INTEGER NOT NULL,
+    PRIMARY KEY (user_id, random_id)
 );
 
-CREATE TABLE IF NOT EXISTS mb_metadata (
-    mb_key_primary INTEGER NOT NULL,
-    mb_key_secondary INTEGER,
-    last_message_id INTEGER NOT NULL
+CREATE TABLE IF NOT EXISTS dialogs (
+    user_id INTEGER NOT NULL,
+    peer_id INTEGER NOT NULL,
+    top_message INTEGER NOT NULL,
+    top_message_date INTEGER NOT NULL,
+    read_inbox_max_id INTEGER NOT NULL DEFAULT 0,
+    read_outbox_max_id INTEGER NOT NULL DEFAULT 0,
+    unread_count INTEGER NOT NULL DEFAULT 0,
+    unread_mentions_count INTEGER NOT NULL DEFAULT 0,
+    unread_mark INTEGER NOT NULL DEFAULT 0,
+    pinned INTEGER NOT NULL DEFAULT 0,
+    folder_id INTEGER NOT NULL DEFAULT 0,
+    draft BLOB,
+    show_previews INTEGER,
+    silent INTEGER,
+    mute_until INTEGER,
+    PRIMARY KEY (user_id, peer_id)
+);
+
+CREATE TABLE IF NOT EXISTS user_state (
+    user_id INTEGER PRIMARY KEY NOT NULL,
+    pts INTEGER NOT NULL,
+    qts INTEGER NOT NULL,
+    seq INTEGER NOT NULL,
+    date INTEGER NOT NULL,
+    message_id INTEGER NOT NULL,
+    scheduled_message_id INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE TABLE IF NOT EXISTS updates (
+    user_id INTEGER NOT NULL,
+    pts INTEGER NOT NULL,
+    pts_count INTEGER NOT NULL,
+    date INTEGER NOT NULL,
+    data BLOB NOT NULL,
+    PRIMARY KEY (user_id, pts)
 );
 
-CREATE TABLE IF NOT EXISTS mb_pts (
-    mb_key_primary INTEGER NOT NULL,
-    mb_key_secondary INTEGER,
+CREATE TABLE IF NOT EXISTS seq_updates (
     user_id INTEGER NOT NULL,
-    pts INTEGER NOT NULL
+    seq INTEGER NOT NULL,
+    date INTEGER NOT NULL,
+    data BLOB NOT NULL,
+    PRIMARY KEY (user_id, seq)
+);
+
+CREATE TABLE IF NOT EXISTS phone_codes (
+    phone_code_hash TEXT PRIMARY KEY NOT NULL,
+    phone TEXT NOT NULL,
+    code TEXT NOT NULL,
+    expires_at INTEGER NOT NULL,
+    verified INTEGER NOT NULL DEFAULT 0,
+    -- Wrong codes entered for this hash, kept across auth.resendCode
+    attempts INTEGER NOT NULL DEFAULT 0
 );
 
 CREATE TABLE IF NOT EXISTS sessions (
     id INTEGER PRIMARY KEY NOT NULL,
-    user_id INTEGER NOT NULL
+    user_id INTEGER NOT NULL,
+    password_pending INTEGER NOT NULL DEFAULT 0,
+    -- Logged in without a 2FA password while one is required, can only set it up
+    setup_password_required INTEGER NOT NULL DEFAULT 0,
+    -- Seq the session got from its last updates.getState or updates.getDifference
+    seq INTEGER NOT NULL DEFAULT 0,
+    hash INTEGER NOT NULL,
+    api_id INTEGER NOT NULL,
+    device_model TEXT NOT NULL,
+    platform TEXT NOT NULL,
+    system_version TEXT NOT NULL,
+    app_version TEXT NOT NULL,
+    ip TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    active_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS privacy_rules (
+    user_id INTEGER NOT NULL,
+    -- Serialized PrivacyKey
+    key BLOB NOT NULL,
+    rules BLOB NOT NULL,
+    PRIMARY KEY (user_id, key)
+);
+
+CREATE TABLE IF NOT EXISTS passwords (
+    user_id INTEGER PRIMARY KEY NOT NULL,
+    salt1 BLOB NOT NULL,
+    salt2 BLOB NOT NULL,
+    verifier BLOB NOT NULL,
+    hint TEXT,
+    email TEXT,
+    -- Wrong SRP answers in a row, reset by a correct one or a new password
+    failed_attempts INTEGER NOT NULL DEFAULT 0,
+    failed_at INTEGER NOT NULL DEFAULT 0
 );
 
 CREATE TABLE IF NOT EXISTS users (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
     first_name TEXT NOT NULL,
     last_name TEXT,
-    phone TEXT NOT NULL,
-    username TEXT
+    phone TEXT,
+    username TEXT,
+    bot INTEGER NOT NULL DEFAULT 0,
+    bot_token TEXT UNIQUE
 );
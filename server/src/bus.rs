@@ -0,0 +1,174 @@
+use crate::storage::Storage;
+use crate::{println_yellow, time, v};
+use catte_tl_schema::{
+    SchemaObject, SendMessageActionVariant, SendMessageCancelAction, UpdateShort, UpdateUserTyping,
+    UpdateVariant, Updates, UpdatesCombined, UserVariant,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Mutex;
+
+/// Clients repeat chat actions every few seconds while they last
+const CHAT_ACTION_TTL: Duration = Duration::from_secs(6);
+
+pub enum BusEvent {
+    /// Sends the object to the client as is
+    Push(Box<SchemaObject>),
+    /// Closes the connection
+    Terminate,
+}
+
+/// Routes events to live connections by their auth key or user
+pub struct Bus {
+    storage: Storage,
+    connections: Mutex<HashMap<i64, Vec<UnboundedSender<BusEvent>>>>,
+    /// Generation of the latest chat action by (user, peer), tells refreshed actions from expired ones
+    chat_actions: Mutex<HashMap<(i64, i64), u64>>,
+}
+
+impl Bus {
+    pub fn new(storage: Storage) -> Self {
+        Self {
+            storage,
+            connections: Mutex::new(HashMap::new()),
+            chat_actions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Registers a connection, it stays registered until the receiver is dropped
+    pub async fn register(&self, auth_key_id: i64) -> UnboundedReceiver<BusEvent> {
+        let (sender, receiver) = unbounded_channel();
+        self.connections
+            .lock()
+            .await
+            .entry(auth_key_id)
+            .or_default()
+            .push(sender);
+        receiver
+    }
+
+    /// Drops all live connections using the auth key
+    pub async fn terminate(&self, auth_key_id: i64) {
+        if let Some(senders) = self.connections.lock().await.remove(&auth_key_id) {
+            for sender in senders {
+                let _ = sender.send(BusEvent::Terminate);
+            }
+        }
+    }
+
+    /// Pushes the object to every authorized connection of the user,
+    /// `except` is usually the auth key of the connection that caused the event
+    pub async fn publish(
+        &self,
+        user_id: i64,
+        object: SchemaObject,
+        except: Option<i64>,
+    ) -> Result<(), sqlx::Error> {
+        for user_session in self.storage.get_user_sessions(user_id).await? {
+            if user_session.password_pending
+                || user_session.setup_password_required
+                || Some(user_session.id) == except
+            {
+                continue;
+            }
+            self.push(user_session.id, object.clone()).await;
+        }
+        Ok(())
+    }
+
+    /// Pushes updates that are not tied to pts, stamping them with the user's next seq.
+    /// Every session gets them, skipping one would leave a gap in its seq
+    pub async fn publish_seq(
+        &self,
+        user_id: i64,
+        updates: Vec<UpdateVariant>,
+        users: Vec<UserVariant>,
+    ) -> Result<(), sqlx::Error> {
+        let (seq_start, seq) = self.storage.log_seq_updates(user_id, &updates).await?;
+        let object = if seq_start == seq {
+            SchemaObject::Updates(Updates {
+                updates,
+                users,
+                chats: vec![],
+                date: time!(),
+                seq,
+            })
+        } else {
+            SchemaObject::UpdatesCombined(UpdatesCombined {
+                updates,
+                users,
+                chats: vec![],
+                date: time!(),
+                seq_start,
+                seq,
+            })
+        };
+        self.publish(user_id, object, None).await
+    }
+
+    /// Pushes a chat action of the user to the peer, actions are never stored.
+    /// An action that `expires` and isn't repeated within CHAT_ACTION_TTL gets cancelled
+    pub async fn publish_chat_action(
+        self: &Arc<Self>,
+        user_id: i64,
+        peer_id: i64,
+        action: SendMessageActionVariant,
+        expires: bool,
+    ) -> Result<(), sqlx::Error> {
+        let generation = {
+            let mut chat_actions = self.chat_actions.lock().await;
+            if expires {
+                let generation = chat_actions.entry((user_id, peer_id)).or_default();
+                *generation += 1;
+                Some(*generation)
+            } else {
+                chat_actions.remove(&(user_id, peer_id));
+                None
+            }
+        };
+        self.publish(peer_id, Bus::user_typing(user_id, action), None)
+            .await?;
+
+        if let Some(generation) = generation {
+            let bus = self.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(CHAT_ACTION_TTL).await;
+                {
+                    let mut chat_actions = bus.chat_actions.lock().await;
+                    if chat_actions.get(&(user_id, peer_id)) != Some(&generation) {
+                        return;
+                    }
+                    chat_actions.remove(&(user_id, peer_id));
+                }
+                let cancel = v!(SendMessageActionVariant::SendMessageCancelAction {});
+                if let Err(e) = bus
+                    .publish(peer_id, Bus::user_typing(user_id, cancel), None)
+                    .await
+                {
+                    println_yellow!("BUS", "failed to cancel a chat action: {}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn user_typing(user_id: i64, action: SendMessageActionVariant) -> SchemaObject {
+        SchemaObject::UpdateShort(UpdateShort {
+            update: v!(UpdateVariant::UpdateUserTyping { user_id, action }),
+            date: time!(),
+        })
+    }
+
+    pub async fn push(&self, auth_key_id: i64, object: SchemaObject) {
+        let mut connections = self.connections.lock().await;
+        let Some(senders) = connections.get_mut(&auth_key_id) else {
+            return;
+        };
+        senders.retain(|s| s.send(BusEvent::Push(Box::new(object.clone()))).is_ok());
+        if senders.is_empty() {
+            connections.remove(&auth_key_id);
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use crate::{hex_string, http, println_yellow};
+use async_trait::async_trait;
+use rand::Rng;
+use serde::Deserialize;
+use std::error::Error;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+
+pub const CODE_LENGTH: usize = 5;
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum CodeSenderConfig {
+    Log,
+    File { path: String },
+    Http { url: String },
+}
+
+/// Delivers login codes to the user
+#[async_trait]
+pub trait CodeSender: Send + Sync {
+    async fn send(
+        &self,
+        phone_number: &str,
+        code: &str,
+    ) -> Result<(), Box<dyn Error + Send + Sync>>;
+}
+
+/// Prints codes to the server log
+pub struct LogCodeSender;
+
+#[async_trait]
+impl CodeSender for LogCodeSender {
+    async fn send(
+        &self,
+        phone_number: &str,
+        code: &str,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        println_yellow!("LOGIN CODE", "{} for +{}", code, phone_number);
+        Ok(())
+    }
+}
+
+/// Appends `<phone_number> <code>` lines to a file
+pub struct FileCodeSender {
+    pub path: String,
+}
+
+#[async_trait]
+impl CodeSender for FileCodeSender {
+    async fn send(
+        &self,
+        phone_number: &str,
+        code: &str,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?
+            .write_all(format!("{} {}\n", phone_number, code).as_bytes())
+            .await?;
+        Ok(())
+    }
+}
+
+/// POSTs `{"phone_number": "...", "code": "..."}` to an HTTP endpoint
+pub struct HttpCodeSender {
+    pub url: String,
+}
+
+#[async_trait]
+impl CodeSender for HttpCodeSender {
+    async fn send(
+        &self,
+        phone_number: &str,
+        code: &str,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        // Both values only ever contain digits, so there is nothing to escape
+        let body = format!(r#"{{"phone_number":"{}","code":"{}"}}"#, phone_number, code);
+        let response = http::request(
+            "POST",
+            &self.url,
+            Some(("application/json", body.as_bytes())),
+        )
+        .await?;
+        if !(200..300).contains(&response.status) {
+            return Err(format!("code endpoint returned {}", response.status).into());
+        }
+        Ok(())
+    }
+}
+
+pub fn from_config(config: &CodeSenderConfig) -> Box<dyn CodeSender> {
+    match config {
+        CodeSenderConfig::Log => Box::new(LogCodeSender),
+        CodeSenderConfig::File { path } => Box::new(FileCodeSender { path: path.clone() }),
+        CodeSenderConfig::Http { url } => Box::new(HttpCodeSender { url: url.clone() }),
+    }
+}
+
+pub fn generate_code() -> String {
+    let mut rng = rand::thread_rng();
+    (0..CODE_LENGTH)
+        .map(|_| char::from(b'0' + rng.gen_range(0..10)))
+        .collect()
+}
+
+pub fn generate_phone_code_hash() -> String {
+    hex_string!(rand::random::<[u8; 16]>())
+}
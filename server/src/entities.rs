@@ -0,0 +1,271 @@
+use catte_tl_schema::*;
+
+const URL_PREFIXES: [&str; 3] = ["http://", "https://", "www."];
+const MENTION_MIN_LENGTH: usize = 5;
+const MENTION_MAX_LENGTH: usize = 32;
+
+/// Length of the text in UTF-16 code units, which entity offsets and lengths are measured in
+pub fn utf16_len(text: &str) -> i32 {
+    text.encode_utf16().count() as i32
+}
+
+/// Offset and length of an entity
+pub fn bounds(entity: &MessageEntityVariant) -> (i32, i32) {
+    macro_rules! bounds {
+        ($($variant:ident),*) => {
+            match entity {
+                $(MessageEntityVariant::$variant(e) => (e.offset, e.length),)*
+            }
+        };
+    }
+    bounds!(
+        MessageEntityUnknown,
+        MessageEntityMention,
+        MessageEntityHashtag,
+        MessageEntityBotCommand,
+        MessageEntityUrl,
+        MessageEntityEmail,
+        MessageEntityBold,
+        MessageEntityItalic,
+        MessageEntityCode,
+        MessageEntityPre,
+        MessageEntityTextUrl,
+        MessageEntityMentionName,
+        InputMessageEntityMentionName,
+        MessageEntityPhone,
+        MessageEntityCashtag,
+        MessageEntityUnderline,
+        MessageEntityStrike,
+        MessageEntityBlockquote,
+        MessageEntityBankCard,
+        MessageEntitySpoiler,
+        MessageEntityCustomEmoji
+    )
+}
+
+/// Checks that every entity is non-empty and lies within the text
+pub fn validate(text: &str, entities: &[MessageEntityVariant]) -> Result<(), &'static str> {
+    let text_length = utf16_len(text);
+    for entity in entities {
+        let (offset, length) = bounds(entity);
+        if offset < 0 || length <= 0 || offset as i64 + length as i64 > text_length as i64 {
+            return Err("ENTITY_BOUNDS_INVALID");
+        }
+    }
+    Ok(())
+}
+
+/// Detects URLs, @mentions and #hashtags in a text that was sent without entities
+pub fn parse(text: &str) -> Vec<MessageEntityVariant> {
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    // UTF-16 offset of every char, plus the total length at the end
+    let mut offsets = Vec::with_capacity(chars.len() + 1);
+    let mut offset = 0;
+    for (_, c) in &chars {
+        offsets.push(offset);
+        offset += c.len_utf16() as i32;
+    }
+    offsets.push(offset);
+
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let mut entities = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        if i > 0 && is_word(chars[i - 1].1) {
+            i += 1;
+            continue;
+        }
+        let rest = &text[chars[i].0..];
+        let end = if let Some(end) = match_url(&chars, i, rest) {
+            entities.push(MessageEntityVariant::MessageEntityUrl(Box::new(
+                MessageEntityUrl {
+                    offset: offsets[i],
+                    length: offsets[end] - offsets[i],
+                },
+            )));
+            end
+        } else if chars[i].1 == '@' {
+            let end = (i + 1..chars.len())
+                .find(|j| !(chars[*j].1.is_ascii_alphanumeric() || chars[*j].1 == '_'))
+                .unwrap_or(chars.len());
+            let length = end - i - 1;
+            let followed_by_word = end < chars.len() && is_word(chars[end].1);
+            if (MENTION_MIN_LENGTH..=MENTION_MAX_LENGTH).contains(&length) && !followed_by_word {
+                entities.push(MessageEntityVariant::MessageEntityMention(Box::new(
+                    MessageEntityMention {
+                        offset: offsets[i],
+                        length: offsets[end] - offsets[i],
+                    },
+                )));
+            }
+            end
+        } else if chars[i].1 == '#' {
+            let end = (i + 1..chars.len())
+                .find(|j| !is_word(chars[*j].1))
+                .unwrap_or(chars.len());
+            // Purely numeric tags like #1 are not hashtags
+            if chars[i + 1..end].iter().any(|(_, c)| !c.is_ascii_digit()) {
+                entities.push(MessageEntityVariant::MessageEntityHashtag(Box::new(
+                    MessageEntityHashtag {
+                        offset: offsets[i],
+                        length: offsets[end] - offsets[i],
+                    },
+                )));
+            }
+            end
+        } else {
+            i + 1
+        };
+        i = end.max(i + 1);
+    }
+    entities
+}
+
+/// End char index of a URL starting at `start`, if there is one
+fn match_url(chars: &[(usize, char)], start: usize, rest: &str) -> Option<usize> {
+    let prefix = URL_PREFIXES.iter().find(|p| {
+        rest.get(..p.len())
+            .is_some_and(|s| s.eq_ignore_ascii_case(p))
+    })?;
+    let mut end = (start..chars.len())
+        .find(|j| chars[*j].1.is_whitespace())
+        .unwrap_or(chars.len());
+    // Punctuation right after a link almost always belongs to the sentence
+    while end > start
+        && matches!(
+            chars[end - 1].1,
+            '.' | ',' | ';' | ':' | '!' | '?' | ')' | '"' | '\''
+        )
+    {
+        end -= 1;
+    }
+    let host = chars[start + prefix.len()..end.max(start + prefix.len())]
+        .iter()
+        .map(|(_, c)| *c)
+        .take_while(|c| *c != '/')
+        .collect::<String>();
+    if host.is_empty() || (*prefix == "www." && !host.contains('.')) {
+        return None;
+    }
+    Some(end)
+}
+
+/// Whether the entities mention the user, either by id or by @username
+pub fn mentions(text: &str, entities: &[MessageEntityVariant], user: &User) -> bool {
+    let utf16: Vec<u16> = text.encode_utf16().collect();
+    entities.iter().any(|entity| match entity {
+        MessageEntityVariant::MessageEntityMentionName(e) => e.user_id == user.id,
+        MessageEntityVariant::MessageEntityMention(e) => {
+            let end = (e.offset + e.length) as usize;
+            let mention = utf16
+                .get(e.offset as usize..end)
+                .map(String::from_utf16_lossy);
+            match (mention, &user.username) {
+                (Some(mention), Some(username)) => mention
+                    .strip_prefix('@')
+                    .is_some_and(|mention| mention.eq_ignore_ascii_case(username)),
+                _ => false,
+            }
+        }
+        _ => false,
+    })
+}
+
+/// Text of every URL and text URL entity, in order
+pub fn urls(text: &str, entities: &[MessageEntityVariant]) -> Vec<String> {
+    let utf16: Vec<u16> = text.encode_utf16().collect();
+    entities
+        .iter()
+        .filter_map(|entity| match entity {
+            MessageEntityVariant::MessageEntityUrl(e) => {
+                let end = (e.offset + e.length) as usize;
+                utf16
+                    .get(e.offset as usize..end)
+                    .map(String::from_utf16_lossy)
+            }
+            MessageEntityVariant::MessageEntityTextUrl(e) => Some(e.url.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bold(offset: i32, length: i32) -> MessageEntityVariant {
+        MessageEntityVariant::MessageEntityBold(Box::new(MessageEntityBold { offset, length }))
+    }
+
+    /// Kind, offset and length of every entity
+    fn parsed(text: &str) -> Vec<(&'static str, i32, i32)> {
+        parse(text)
+            .iter()
+            .map(|entity| {
+                let kind = match entity {
+                    MessageEntityVariant::MessageEntityUrl(_) => "url",
+                    MessageEntityVariant::MessageEntityMention(_) => "mention",
+                    MessageEntityVariant::MessageEntityHashtag(_) => "hashtag",
+                    _ => "other",
+                };
+                let (offset, length) = bounds(entity);
+                (kind, offset, length)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn offsets_count_astral_plane_chars_twice() {
+        assert_eq!(utf16_len("😀"), 2);
+        assert_eq!(
+            parsed("😀 @username 👍 #tag"),
+            [("mention", 3, 9), ("hashtag", 16, 4)]
+        );
+        let text = "😀😀 https://example.com/😀";
+        assert_eq!(parsed(text), [("url", 5, 22)]);
+        assert_eq!(urls(text, &parse(text)), ["https://example.com/😀"]);
+    }
+
+    #[test]
+    fn entity_may_end_exactly_at_the_end_of_the_text() {
+        assert_eq!(validate("hello 😀", &[bold(6, 2)]), Ok(()));
+        assert_eq!(
+            validate("hello 😀", &[bold(6, 3)]),
+            Err("ENTITY_BOUNDS_INVALID")
+        );
+        assert_eq!(parsed("see www.example.com"), [("url", 4, 15)]);
+    }
+
+    #[test]
+    fn validate_rejects_empty_and_negative_entities() {
+        assert_eq!(
+            validate("hello", &[bold(0, 0)]),
+            Err("ENTITY_BOUNDS_INVALID")
+        );
+        assert_eq!(
+            validate("hello", &[bold(-1, 2)]),
+            Err("ENTITY_BOUNDS_INVALID")
+        );
+        assert_eq!(
+            validate("hello", &[bold(i32::MAX, i32::MAX)]),
+            Err("ENTITY_BOUNDS_INVALID")
+        );
+    }
+
+    #[test]
+    fn bare_url_prefixes_are_not_links() {
+        assert!(parse("https://").is_empty());
+        assert!(parse("http://.").is_empty());
+        assert!(parse("www.").is_empty());
+        assert!(parse("#").is_empty());
+        assert!(parse("@").is_empty());
+    }
+
+    #[test]
+    fn parse_skips_trailing_punctuation_and_invalid_tags() {
+        assert_eq!(parsed("(https://example.com)."), [("url", 1, 19)]);
+        assert_eq!(parsed("@abc @abcde"), [("mention", 5, 6)]);
+        assert_eq!(parsed("#1 #1a a#b"), [("hashtag", 3, 3)]);
+        assert!(parse("mail@example.com").is_empty());
+    }
+}
@@ -0,0 +1,212 @@
+use std::error::Error;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpStream};
+use tokio::time::timeout;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_RESPONSE_SIZE: u64 = 1024 * 1024;
+
+pub struct Response {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+///
+/// Minimal HTTP/1.0 client meant for talking to local services
+///
+/// Only plain `http://` URLs are supported, the response is
+/// truncated to 1 MiB and the whole request times out after 10 seconds
+///
+pub async fn request(
+    method: &str,
+    url: &str,
+    body: Option<(&str, &[u8])>,
+) -> Result<Response, Box<dyn Error + Send + Sync>> {
+    send(method, url, body, false).await
+}
+
+///
+/// Same as `request`, but for URLs that come from users
+///
+/// The host is resolved once and refused if any of its addresses isn't public,
+/// then the connection goes to the address that was checked so the answer
+/// can't change in between
+///
+pub async fn request_public(
+    method: &str,
+    url: &str,
+    body: Option<(&str, &[u8])>,
+) -> Result<Response, Box<dyn Error + Send + Sync>> {
+    send(method, url, body, true).await
+}
+
+async fn send(
+    method: &str,
+    url: &str,
+    body: Option<(&str, &[u8])>,
+    public_only: bool,
+) -> Result<Response, Box<dyn Error + Send + Sync>> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or("only http:// URLs are supported")?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let address = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{host}:80")
+    };
+
+    let mut request = format!(
+        "{method} {path} HTTP/1.0\r\nHost: {host}\r\nUser-Agent: cattegram\r\nConnection: close\r\n"
+    )
+    .into_bytes();
+    if let Some((content_type, data)) = body {
+        request.extend(
+            format!(
+                "Content-Type: {content_type}\r\nContent-Length: {}\r\n",
+                data.len()
+            )
+            .as_bytes(),
+        );
+        request.extend(b"\r\n");
+        request.extend(data);
+    } else {
+        request.extend(b"\r\n");
+    }
+
+    let raw = timeout(TIMEOUT, async {
+        let addresses: Vec<_> = lookup_host(&address).await?.collect();
+        if public_only && !addresses.iter().all(|a| is_public(a.ip())) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!("{host} resolves to a non-public address"),
+            ));
+        }
+        let mut socket = TcpStream::connect(&addresses[..]).await?;
+        socket.write_all(&request).await?;
+        let mut raw = vec![];
+        socket.take(MAX_RESPONSE_SIZE).read_to_end(&mut raw).await?;
+        Ok::<_, std::io::Error>(raw)
+    })
+    .await??;
+
+    let header_end = raw
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or("malformed HTTP response")?;
+    let status = String::from_utf8_lossy(&raw[..header_end])
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse::<u16>().ok())
+        .ok_or("malformed HTTP status line")?;
+
+    Ok(Response {
+        status,
+        body: raw[header_end + 4..].to_vec(),
+    })
+}
+
+/// Whether the address is reachable on the internet, rather than belonging
+/// to this machine, a private network or a special-purpose range
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => is_public_v6(ip),
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        // "This network" 0.0.0.0/8
+        || a == 0
+        // Carrier-grade NAT 100.64.0.0/10
+        || (a == 100 && b & 0xc0 == 64)
+        // Reserved 240.0.0.0/4
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    // ::ffff:a.b.c.d and NAT64 64:ff9b::a.b.c.d reach the embedded IPv4 address
+    if let Some(v4) = ip.to_ipv4_mapped() {
+        return is_public_v4(v4);
+    }
+    let segments = ip.segments();
+    if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        let [.., a, b, c, d] = ip.octets();
+        return is_public_v4(Ipv4Addr::new(a, b, c, d));
+    }
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        // Deprecated IPv4-compatible ::a.b.c.d
+        || segments[..6] == [0; 6]
+        || ip.is_multicast()
+        // Unique local fc00::/7
+        || segments[0] & 0xfe00 == 0xfc00
+        // Link-local fe80::/10
+        || segments[0] & 0xffc0 == 0xfe80)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn non_public_addresses_are_refused() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "0.0.0.0",
+            "100.64.0.1",
+            "224.0.0.1",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+            "64:ff9b::10.0.0.1",
+            "::10.0.0.1",
+            "fd00::1",
+            "fe80::1",
+            "ff02::1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn public_addresses_are_allowed() {
+        for ip in [
+            "1.1.1.1",
+            "93.184.216.34",
+            "::ffff:1.1.1.1",
+            "64:ff9b::1.1.1.1",
+            "2606:4700::1111",
+        ] {
+            assert!(is_public(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[tokio::test]
+    async fn public_requests_dont_connect_to_local_hosts() {
+        let error = request_public("GET", "http://127.0.0.1:9/", None)
+            .await
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("non-public"), "{error}");
+    }
+}
@@ -3,9 +3,39 @@ use syn::{parse_macro_input, ItemFn};
 
 #[proc_macro_attribute]
 pub fn auth(
-    _attr: proc_macro::TokenStream,
+    attr: proc_macro::TokenStream,
     item: proc_macro::TokenStream,
 ) -> proc_macro::TokenStream {
+    // #[auth(bots)] also lets bots call the function, #[auth(setup_password)] lets
+    // sessions that still have to set up a required 2FA password call it
+    let mut allow_bots = false;
+    let mut allow_setup_password = false;
+    for arg in attr.to_string().split(',').map(str::trim) {
+        match arg {
+            "" => {}
+            "bots" => allow_bots = true,
+            "setup_password" => allow_setup_password = true,
+            other => panic!("unknown #[auth] argument: {other}"),
+        }
+    }
+    let bot_check = if allow_bots {
+        quote! {}
+    } else {
+        quote! {
+            if session.lock().await.bot {
+                err!(message, 400, "BOT_METHOD_INVALID");
+            }
+        }
+    };
+    let setup_password_check = if allow_setup_password {
+        quote! {}
+    } else {
+        quote! {
+            if session.lock().await.password_setup_pending().await? {
+                err!(message, 403, "PASSWORD_REQUIRED");
+            }
+        }
+    };
     let ItemFn {
         attrs,
         vis,
@@ -18,6 +48,8 @@ pub fn auth(
             if !session.lock().await.authorized {
                 err!(message, 401, "UNAUTHORIZED");
             }
+            #bot_check
+            #setup_password_check
             #(#stmts)*
         }
     }
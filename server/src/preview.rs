@@ -0,0 +1,129 @@
+use crate::http;
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::error::Error;
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum PreviewFetcherConfig {
+    None,
+    Http,
+}
+
+pub struct Preview {
+    pub url: String,
+    pub site_name: Option<String>,
+    pub title: Option<String>,
+    pub description: Option<String>,
+}
+
+/// Builds link previews for URLs found in messages
+#[async_trait]
+pub trait PreviewFetcher: Send + Sync {
+    async fn fetch(&self, url: &str) -> Result<Option<Preview>, Box<dyn Error + Send + Sync>>;
+}
+
+/// Never builds previews, for servers without internet access
+pub struct NoPreviewFetcher;
+
+#[async_trait]
+impl PreviewFetcher for NoPreviewFetcher {
+    async fn fetch(&self, _url: &str) -> Result<Option<Preview>, Box<dyn Error + Send + Sync>> {
+        Ok(None)
+    }
+}
+
+/// Downloads the page and reads its `<title>` and Open Graph tags,
+/// links to local or private addresses are never downloaded
+pub struct HttpPreviewFetcher;
+
+#[async_trait]
+impl PreviewFetcher for HttpPreviewFetcher {
+    async fn fetch(&self, url: &str) -> Result<Option<Preview>, Box<dyn Error + Send + Sync>> {
+        let url = if url.starts_with("www.") {
+            format!("http://{url}")
+        } else {
+            url.to_string()
+        };
+        // http::request only speaks plain HTTP
+        if !url.starts_with("http://") {
+            return Ok(None);
+        }
+        let response = http::request_public("GET", &url, None).await?;
+        if !(200..300).contains(&response.status) {
+            return Ok(None);
+        }
+
+        let html = String::from_utf8_lossy(&response.body);
+        let mut preview = Preview {
+            url,
+            site_name: None,
+            title: None,
+            description: None,
+        };
+        for tag in html.split('<').skip(1) {
+            let tag = &tag[..tag.find('>').unwrap_or(tag.len())];
+            if !tag.starts_with("meta ") {
+                continue;
+            }
+            let key = attribute(tag, "property").or_else(|| attribute(tag, "name"));
+            let Some(content) = attribute(tag, "content") else {
+                continue;
+            };
+            match key.as_deref() {
+                Some("og:site_name") => preview.site_name = Some(content),
+                Some("og:title") => preview.title = Some(content),
+                Some("og:description") => preview.description = Some(content),
+                Some("description") if preview.description.is_none() => {
+                    preview.description = Some(content)
+                }
+                _ => {}
+            }
+        }
+        if preview.title.is_none() {
+            if let Some(start) = html.find("<title>") {
+                let title = &html[start + 7..];
+                preview.title = Some(unescape(&title[..title.find('<').unwrap_or(title.len())]));
+            }
+        }
+
+        if preview.title.is_none() && preview.description.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(preview))
+    }
+}
+
+/// Value of a quoted attribute inside an HTML tag
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let pattern = format!("{name}=");
+    let mut rest = tag;
+    loop {
+        let start = rest.find(&pattern)?;
+        // Make sure `name=` doesn't match the end of another attribute, e.g. `og-name=`
+        let at_boundary = rest[..start].ends_with(char::is_whitespace);
+        rest = &rest[start + pattern.len()..];
+        if !at_boundary {
+            continue;
+        }
+        let quote = rest.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+        let value = &rest[1..];
+        return Some(unescape(&value[..value.find(quote)?]));
+    }
+}
+
+fn unescape(text: &str) -> String {
+    text.trim()
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+pub fn from_config(config: &PreviewFetcherConfig) -> Box<dyn PreviewFetcher> {
+    match config {
+        PreviewFetcherConfig::None => Box::new(NoPreviewFetcher),
+        PreviewFetcherConfig::Http => Box::new(HttpPreviewFetcher),
+    }
+}
@@ -0,0 +1,183 @@
+use catte_tl_schema::*;
+
+/// Stored key of an input privacy key
+pub fn key(key: &InputPrivacyKeyVariant) -> PrivacyKeyVariant {
+    match key {
+        InputPrivacyKeyVariant::InputPrivacyKeyStatusTimestamp(_) => {
+            PrivacyKeyVariant::PrivacyKeyStatusTimestamp(Box::new(PrivacyKeyStatusTimestamp {}))
+        }
+        InputPrivacyKeyVariant::InputPrivacyKeyChatInvite(_) => {
+            PrivacyKeyVariant::PrivacyKeyChatInvite(Box::new(PrivacyKeyChatInvite {}))
+        }
+        InputPrivacyKeyVariant::InputPrivacyKeyPhoneCall(_) => {
+            PrivacyKeyVariant::PrivacyKeyPhoneCall(Box::new(PrivacyKeyPhoneCall {}))
+        }
+        InputPrivacyKeyVariant::InputPrivacyKeyPhoneP2P(_) => {
+            PrivacyKeyVariant::PrivacyKeyPhoneP2P(Box::new(PrivacyKeyPhoneP2P {}))
+        }
+        InputPrivacyKeyVariant::InputPrivacyKeyForwards(_) => {
+            PrivacyKeyVariant::PrivacyKeyForwards(Box::new(PrivacyKeyForwards {}))
+        }
+        InputPrivacyKeyVariant::InputPrivacyKeyProfilePhoto(_) => {
+            PrivacyKeyVariant::PrivacyKeyProfilePhoto(Box::new(PrivacyKeyProfilePhoto {}))
+        }
+        InputPrivacyKeyVariant::InputPrivacyKeyPhoneNumber(_) => {
+            PrivacyKeyVariant::PrivacyKeyPhoneNumber(Box::new(PrivacyKeyPhoneNumber {}))
+        }
+        InputPrivacyKeyVariant::InputPrivacyKeyAddedByPhone(_) => {
+            PrivacyKeyVariant::PrivacyKeyAddedByPhone(Box::new(PrivacyKeyAddedByPhone {}))
+        }
+        InputPrivacyKeyVariant::InputPrivacyKeyVoiceMessages(_) => {
+            PrivacyKeyVariant::PrivacyKeyVoiceMessages(Box::new(PrivacyKeyVoiceMessages {}))
+        }
+    }
+}
+
+fn user_ids(self_id: i64, users: &[InputUserVariant]) -> Vec<i64> {
+    users
+        .iter()
+        .filter_map(|user| match user {
+            InputUserVariant::InputUserSelf(_) => Some(self_id),
+            InputUserVariant::InputUser(user) => Some(user.user_id),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Stored rules of input privacy rules, there are no chats so chat participant rules are dropped
+pub fn rules(self_id: i64, rules: &[InputPrivacyRuleVariant]) -> Vec<PrivacyRuleVariant> {
+    rules
+        .iter()
+        .filter_map(|rule| {
+            Some(match rule {
+                InputPrivacyRuleVariant::InputPrivacyValueAllowContacts(_) => {
+                    PrivacyRuleVariant::PrivacyValueAllowContacts(Box::new(
+                        PrivacyValueAllowContacts {},
+                    ))
+                }
+                InputPrivacyRuleVariant::InputPrivacyValueAllowAll(_) => {
+                    PrivacyRuleVariant::PrivacyValueAllowAll(Box::new(PrivacyValueAllowAll {}))
+                }
+                InputPrivacyRuleVariant::InputPrivacyValueAllowUsers(rule) => {
+                    PrivacyRuleVariant::PrivacyValueAllowUsers(Box::new(PrivacyValueAllowUsers {
+                        users: user_ids(self_id, &rule.users),
+                    }))
+                }
+                InputPrivacyRuleVariant::InputPrivacyValueDisallowContacts(_) => {
+                    PrivacyRuleVariant::PrivacyValueDisallowContacts(Box::new(
+                        PrivacyValueDisallowContacts {},
+                    ))
+                }
+                InputPrivacyRuleVariant::InputPrivacyValueDisallowAll(_) => {
+                    PrivacyRuleVariant::PrivacyValueDisallowAll(Box::new(
+                        PrivacyValueDisallowAll {},
+                    ))
+                }
+                InputPrivacyRuleVariant::InputPrivacyValueDisallowUsers(rule) => {
+                    PrivacyRuleVariant::PrivacyValueDisallowUsers(Box::new(
+                        PrivacyValueDisallowUsers {
+                            users: user_ids(self_id, &rule.users),
+                        },
+                    ))
+                }
+                InputPrivacyRuleVariant::InputPrivacyValueAllowChatParticipants(_)
+                | InputPrivacyRuleVariant::InputPrivacyValueDisallowChatParticipants(_) => {
+                    return None
+                }
+            })
+        })
+        .collect()
+}
+
+/// Users listed in the rules
+pub fn users(rules: &[PrivacyRuleVariant]) -> Vec<i64> {
+    rules
+        .iter()
+        .flat_map(|rule| match rule {
+            PrivacyRuleVariant::PrivacyValueAllowUsers(rule) => rule.users.clone(),
+            PrivacyRuleVariant::PrivacyValueDisallowUsers(rule) => rule.users.clone(),
+            _ => vec![],
+        })
+        .collect()
+}
+
+/// Whether the rules let `user_id` through, the first rule that applies wins.
+/// Users that set no rules allow everyone, and there are no contacts yet
+pub fn allows(rules: &[PrivacyRuleVariant], user_id: i64) -> bool {
+    for rule in rules {
+        match rule {
+            PrivacyRuleVariant::PrivacyValueAllowAll(_) => return true,
+            PrivacyRuleVariant::PrivacyValueDisallowAll(_) => return false,
+            PrivacyRuleVariant::PrivacyValueAllowUsers(rule) if rule.users.contains(&user_id) => {
+                return true
+            }
+            PrivacyRuleVariant::PrivacyValueDisallowUsers(rule)
+                if rule.users.contains(&user_id) =>
+            {
+                return false
+            }
+            _ => {}
+        }
+    }
+    rules.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allow_users(users: Vec<i64>) -> PrivacyRuleVariant {
+        PrivacyRuleVariant::PrivacyValueAllowUsers(Box::new(PrivacyValueAllowUsers { users }))
+    }
+
+    fn disallow_all() -> PrivacyRuleVariant {
+        PrivacyRuleVariant::PrivacyValueDisallowAll(Box::new(PrivacyValueDisallowAll {}))
+    }
+
+    #[test]
+    fn no_rules_allow_everyone() {
+        assert!(allows(&[], 2));
+    }
+
+    #[test]
+    fn exceptions_come_before_the_base_rule() {
+        let rules = [allow_users(vec![2]), disallow_all()];
+        assert!(allows(&rules, 2));
+        assert!(!allows(&rules, 3));
+    }
+
+    #[test]
+    fn contacts_rules_never_match() {
+        let rules = [PrivacyRuleVariant::PrivacyValueAllowContacts(Box::new(
+            PrivacyValueAllowContacts {},
+        ))];
+        assert!(!allows(&rules, 2));
+    }
+
+    #[test]
+    fn input_rules_resolve_self_and_drop_chats() {
+        let stored = rules(
+            1,
+            &[
+                InputPrivacyRuleVariant::InputPrivacyValueDisallowUsers(Box::new(
+                    InputPrivacyValueDisallowUsers {
+                        users: vec![
+                            InputUserVariant::InputUserSelf(Box::new(InputUserSelf {})),
+                            InputUserVariant::InputUser(Box::new(InputUser {
+                                user_id: 5,
+                                access_hash: 0,
+                            })),
+                        ],
+                    },
+                )),
+                InputPrivacyRuleVariant::InputPrivacyValueAllowChatParticipants(Box::new(
+                    InputPrivacyValueAllowChatParticipants { chats: vec![7] },
+                )),
+            ],
+        );
+        assert_eq!(stored.len(), 1);
+        assert_eq!(users(&stored), [1, 5]);
+        assert!(!allows(&stored, 5));
+        assert!(!allows(&stored, 6));
+    }
+}
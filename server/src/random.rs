@@ -0,0 +1,8 @@
+use rand::RngCore;
+
+/// Cryptographically secure random bytes, for salts, nonces and tokens
+pub fn random_bytes(length: usize) -> Vec<u8> {
+    let mut out = vec![0u8; length];
+    rand::thread_rng().fill_bytes(&mut out);
+    out
+}
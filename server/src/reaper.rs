@@ -0,0 +1,25 @@
+use crate::println_yellow;
+use crate::storage::Storage;
+use std::time::Duration;
+use tokio::time::interval;
+
+const REAPER_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Periodically removes auth keys left behind by abandoned handshakes
+/// and sessions that haven't connected for `authorization_ttl` seconds
+pub async fn run(storage: Storage, auth_key_ttl: i32, authorization_ttl: i32) {
+    let mut interval = interval(REAPER_INTERVAL);
+    loop {
+        interval.tick().await;
+        match storage.delete_inactive_sessions(authorization_ttl).await {
+            Ok(0) => {}
+            Ok(count) => println_yellow!("REAPER", "removed {} inactive sessions", count),
+            Err(e) => println_yellow!("REAPER", "failed to remove inactive sessions: {}", e),
+        }
+        match storage.delete_stale_auth_keys(auth_key_ttl).await {
+            Ok(0) => {}
+            Ok(count) => println_yellow!("REAPER", "removed {} stale auth keys", count),
+            Err(e) => println_yellow!("REAPER", "failed to remove stale auth keys: {}", e),
+        }
+    }
+}
@@ -40,6 +40,16 @@ macro_rules! ok_user {
     };
 }
 
+#[macro_export]
+macro_rules! ok_obj {
+    ($message:tt, $obj:expr) => {
+        return Ok(SchemaObject::RpcResult(RpcResult {
+            req_msg_id: $message.msg_id,
+            result: Box::new($obj),
+        }))
+    };
+}
+
 #[macro_export]
 macro_rules! err {
     ($message:tt, $code:expr, $emessage:expr) => {
@@ -1,9 +1,53 @@
+use crate::random::random_bytes;
+use crate::rpc::CURRENT_PRIME;
 use crate::session::Session;
-use crate::{err, ok_user, rpc};
+use crate::srp;
+use crate::storage::{Password, UserSession};
+use crate::{err, ok, ok_obj, ok_user, privacy, rpc, time, v};
+use catte_server::auth;
 use catte_tl_schema::*;
 use std::{error::Error, sync::Arc};
 use tokio::sync::Mutex;
 
+/// Clients refresh their online status every few minutes
+const ONLINE_STATUS_TTL: i32 = 300;
+
+/// Guesses the app name from the lang_pack sent in initConnection
+fn app_name(platform: &str) -> String {
+    match platform {
+        "tdesktop" => "Telegram Desktop",
+        "android" => "Telegram Android",
+        "android_x" => "Telegram Android X",
+        "ios" => "Telegram iOS",
+        "macos" => "Telegram macOS",
+        _ => platform,
+    }
+    .to_string()
+}
+
+pub fn authorization(user_session: UserSession, current: bool) -> Authorization {
+    Authorization {
+        current,
+        official_app: false,
+        password_pending: user_session.password_pending,
+        encrypted_requests_disabled: false,
+        call_requests_disabled: false,
+        hash: if current { 0 } else { user_session.hash },
+        device_model: user_session.info.device_model,
+        app_name: app_name(&user_session.info.platform),
+        platform: user_session.info.platform,
+        system_version: user_session.info.system_version,
+        api_id: user_session.info.api_id,
+        app_version: user_session.info.app_version,
+        date_created: user_session.created_at,
+        date_active: user_session.active_at,
+        ip: user_session.info.ip,
+        country: String::new(),
+        region: String::new(),
+    }
+}
+
+#[auth]
 pub async fn rpc_account_update_username(
     session: Arc<Mutex<Session>>,
     message: rpc::Message<AccountUpdateUsername>,
@@ -11,10 +55,11 @@ pub async fn rpc_account_update_username(
     let session = session.lock().await;
     let self_user = session.get_self().await?;
 
-    if let Ok(_) = session
+    if session
         .storage
         .get_user_by_username(&message.obj.username)
         .await
+        .is_ok()
     {
         err!(message, 400, "USERNAME_OCCUPIED")
     }
@@ -24,5 +69,483 @@ pub async fn rpc_account_update_username(
         .update_username(self_user.id, &message.obj.username)
         .await?;
 
-    ok_user!(message, session.get_self().await?)
+    let user = session.get_self().await?;
+    session
+        .bus
+        .publish_seq(
+            user.id,
+            vec![v!(UpdateVariant::UpdateUserName {
+                user_id: user.id,
+                first_name: user.first_name.clone().unwrap_or_default(),
+                last_name: user.last_name.clone().unwrap_or_default(),
+                usernames: vec![Username {
+                    editable: true,
+                    active: true,
+                    username: message.obj.username,
+                }],
+            })],
+            vec![],
+        )
+        .await?;
+
+    ok_user!(message, user)
+}
+
+///
+/// # Layer 158
+/// ## account.updateStatus#6628562c offline:Bool = Bool;
+/// Updates online user status
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | offline | Bool | If (boolTrue) is transmitted, user status will change to (userStatusOffline) |
+///
+/// ## Behavior
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * The status is only sent to the sessions of the same user and is not stored
+///
+#[auth]
+pub async fn rpc_account_update_status(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<AccountUpdateStatus>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+    let self_user = session.get_self().await?;
+
+    let status = if message.obj.offline {
+        v!(UserStatusVariant::UserStatusOffline {
+            was_online: time!()
+        })
+    } else {
+        v!(UserStatusVariant::UserStatusOnline {
+            expires: time!() + ONLINE_STATUS_TTL
+        })
+    };
+
+    session
+        .bus
+        .publish_seq(
+            self_user.id,
+            vec![v!(UpdateVariant::UpdateUserStatus {
+                user_id: self_user.id,
+                status,
+            })],
+            vec![],
+        )
+        .await?;
+    if !message.obj.offline {
+        // Messages scheduled until this user comes online are due now
+        crate::rpc::messages::spawn_send_when_online(
+            session.config.data.clone(),
+            session.bus.clone(),
+            self_user.id,
+        );
+    }
+
+    ok!(message, BoolTrue {})
+}
+
+///
+/// # Layer 158
+/// ## account.getPassword#548a30f5 = account.Password;
+/// Obtain configuration for two-factor authorization with password
+///
+/// ## Behavior
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * Secure values and password recovery are not supported, has_recovery only reflects whether an email is set
+/// * Only the SHA256_SHA256_PBKDF2_HMAC_SHA512_iter100000_SHA256_ModPow algorithm is supported
+///
+pub async fn rpc_account_get_password(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<AccountGetPassword>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let mut session = session.lock().await;
+
+    if session.bot {
+        err!(message, 400, "BOT_METHOD_INVALID");
+    }
+
+    if !session.authorized
+        && !matches!(
+            session
+                .storage
+                .get_password_pending(session.auth_key_id)
+                .await,
+            Ok(true)
+        )
+    {
+        err!(message, 401, "UNAUTHORIZED");
+    }
+
+    let self_user = session.get_self().await?;
+    let password = session.storage.get_password(self_user.id).await.ok();
+
+    let (current_algo, srp_b, srp_id) = match &password {
+        Some(password) => {
+            let flow = srp::start(&password.verifier);
+            let result = (
+                Some(PasswordKdfAlgoVariant::PasswordKdfAlgoSha256Sha256Pbkdf2Hmacsha512Iter100000Sha256ModPow(
+                    Box::new(srp::algo(password.salt1.clone(), password.salt2.clone())),
+                )),
+                Some(flow.g_b.to_bytes_be()),
+                Some(flow.srp_id),
+            );
+            session.srp_flow = Some(flow);
+            result
+        }
+        None => (None, None, None),
+    };
+
+    ok!(
+        message,
+        AccountPassword {
+            has_recovery: password.as_ref().is_some_and(|p| p.email.is_some()),
+            has_secure_values: false,
+            has_password: password.is_some(),
+            current_algo,
+            srp_b,
+            srp_id,
+            hint: password.as_ref().and_then(|p| p.hint.clone()),
+            email_unconfirmed_pattern: None,
+            new_algo: PasswordKdfAlgoVariant::PasswordKdfAlgoSha256Sha256Pbkdf2Hmacsha512Iter100000Sha256ModPow(
+                Box::new(srp::algo(random_bytes(8), random_bytes(16))),
+            ),
+            new_secure_algo: v!(
+                SecurePasswordKdfAlgoVariant::SecurePasswordKdfAlgoPbkdf2Hmacsha512Iter100000 {
+                    salt: random_bytes(8)
+                }
+            ),
+            secure_random: random_bytes(32),
+            pending_reset_date: None,
+            login_email_pattern: None,
+        }
+    )
+}
+
+///
+/// # Layer 158
+/// ## account.getPasswordSettings#9cd4eaf9 password:InputCheckPasswordSRP = account.PasswordSettings;
+/// Get private info associated to the password info (recovery email, telegram passport info & so on)
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | password | InputCheckPasswordSRP | The password |
+///
+/// ## Behavior
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * secure_settings is never returned
+///
+#[auth]
+pub async fn rpc_account_get_password_settings(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<AccountGetPasswordSettings>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let mut session = session.lock().await;
+    let self_user = session.get_self().await?;
+
+    let Ok(password) = session.storage.get_password(self_user.id).await else {
+        err!(message, 400, "PASSWORD_HASH_INVALID");
+    };
+
+    if let Err(e) = session
+        .check_password(self_user.id, &password, &message.obj.password)
+        .await?
+    {
+        err!(message, e.error_code, e.error_message);
+    }
+
+    ok!(
+        message,
+        AccountPasswordSettings {
+            email: password.email,
+            secure_settings: None,
+        }
+    )
+}
+
+///
+/// # Layer 158
+/// ## account.updatePasswordSettings#a59b102f password:InputCheckPasswordSRP new_settings:account.PasswordInputSettings = Bool;
+/// Set a new 2FA password
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | password | InputCheckPasswordSRP | The old password, or inputCheckPasswordEmpty if there is no password yet |
+/// | new_settings | account.PasswordInputSettings | The new password |
+///
+/// ## Behavior
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * The recovery email is saved as is, without sending a confirmation code
+/// * new_secure_settings is ignored
+/// * Removing the password fails with PASSWORD_REQUIRED when `require_password` is enabled
+/// * Setting a password lifts the `require_password` restriction from all sessions of the user
+///
+#[auth(setup_password)]
+pub async fn rpc_account_update_password_settings(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<AccountUpdatePasswordSettings>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let mut session = session.lock().await;
+    let self_user = session.get_self().await?;
+    let settings = message.obj.new_settings;
+
+    let current = session.storage.get_password(self_user.id).await.ok();
+    match &current {
+        Some(password) => {
+            if let Err(e) = session
+                .check_password(self_user.id, password, &message.obj.password)
+                .await?
+            {
+                err!(message, e.error_code, e.error_message);
+            }
+        }
+        None => {
+            let InputCheckPasswordSrpVariant::InputCheckPasswordEmpty(_) = message.obj.password
+            else {
+                err!(message, 400, "PASSWORD_HASH_INVALID");
+            };
+        }
+    }
+
+    match settings.new_algo {
+        Some(PasswordKdfAlgoVariant::PasswordKdfAlgoSha256Sha256Pbkdf2Hmacsha512Iter100000Sha256ModPow(algo)) => {
+            let Some(verifier) = settings.new_password_hash else {
+                err!(message, 400, "NEW_SETTINGS_INVALID");
+            };
+            if algo.g != srp::G || algo.p[..] != CURRENT_PRIME[..] {
+                err!(message, 400, "NEW_SALT_INVALID");
+            }
+            if !srp::is_valid_verifier(&verifier) {
+                err!(message, 400, "NEW_SETTINGS_INVALID");
+            }
+            session
+                .storage
+                .set_password(
+                    self_user.id,
+                    &Password {
+                        salt1: algo.salt1,
+                        salt2: algo.salt2,
+                        verifier,
+                        hint: settings.hint.filter(|h| !h.is_empty()),
+                        email: settings
+                            .email
+                            .filter(|e| !e.is_empty())
+                            .or(current.and_then(|p| p.email)),
+                        failed_attempts: 0,
+                        failed_at: 0,
+                    },
+                )
+                .await?;
+            session.storage.finish_password_setup(self_user.id).await?;
+            session.setup_password_required = false;
+        }
+        Some(PasswordKdfAlgoVariant::PasswordKdfAlgoUnknown(_)) => {
+            if session.config.require_password {
+                err!(message, 400, "PASSWORD_REQUIRED");
+            }
+            session.storage.delete_password(self_user.id).await?;
+        }
+        None => {
+            let Some(email) = settings.email else {
+                err!(message, 400, "NEW_SETTINGS_EMPTY");
+            };
+            if current.is_none() {
+                err!(message, 400, "PASSWORD_HASH_INVALID");
+            }
+            session
+                .storage
+                .update_password_email(self_user.id, &email)
+                .await?;
+        }
+    }
+
+    ok!(message, BoolTrue {})
+}
+
+///
+/// # Layer 158
+/// ## account.getAuthorizations#e320c158 = account.Authorizations;
+/// Get logged-in sessions
+///
+/// ## Behavior
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * country and region are always empty
+/// * date_active is updated on initConnection
+/// * authorization_ttl_days comes from the server config, sessions without an initConnection for that long are terminated
+///
+#[auth]
+pub async fn rpc_account_get_authorizations(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<AccountGetAuthorizations>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+    let self_user = session.get_self().await?;
+
+    let authorizations = session
+        .storage
+        .get_user_sessions(self_user.id)
+        .await?
+        .into_iter()
+        .map(|s| {
+            let current = s.id == session.auth_key_id;
+            authorization(s, current)
+        })
+        .collect();
+
+    ok!(
+        message,
+        AccountAuthorizations {
+            authorization_ttl_days: session.config.authorization_ttl_days,
+            authorizations,
+        }
+    )
+}
+
+///
+/// # Layer 158
+/// ## account.resetAuthorization#df77f3bc hash:long = Bool;
+/// Log out an active authorized session by its hash
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | hash | long | Session hash |
+///
+/// ## Behavior
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * Fresh sessions are allowed to terminate other sessions
+///
+#[auth]
+pub async fn rpc_account_reset_authorization(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<AccountResetAuthorization>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+    let self_user = session.get_self().await?;
+
+    let Ok(user_session) = session
+        .storage
+        .get_user_session_by_hash(self_user.id, message.obj.hash)
+        .await
+    else {
+        err!(message, 400, "HASH_INVALID");
+    };
+
+    if user_session.id == session.auth_key_id {
+        err!(message, 400, "HASH_INVALID");
+    }
+
+    session.storage.delete_session(user_session.id).await?;
+    session.bus.terminate(user_session.id).await;
+
+    ok!(message, BoolTrue {})
+}
+
+/// Rules along with the users they mention
+async fn privacy_rules(
+    session: &Session,
+    rules: Vec<PrivacyRuleVariant>,
+) -> Result<AccountPrivacyRules, sqlx::Error> {
+    let user_ids = privacy::users(&rules);
+    let users = if user_ids.is_empty() {
+        vec![]
+    } else {
+        session.storage.get_users(&user_ids).await?
+    };
+    Ok(AccountPrivacyRules {
+        rules,
+        chats: vec![],
+        users: users
+            .into_iter()
+            .map(|mut user| {
+                user.access_hash = Some(0);
+                UserVariant::User(Box::new(user))
+            })
+            .collect(),
+    })
+}
+
+///
+/// # Layer 158
+/// ## account.getPrivacy#dadbc950 key:InputPrivacyKey = account.PrivacyRules;
+/// Get privacy settings of current account
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | key | InputPrivacyKey | Peer category whose privacy settings should be fetched |
+///
+/// ## Behavior
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * Keys that were never set allow everyone
+///
+#[auth]
+pub async fn rpc_account_get_privacy(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<AccountGetPrivacy>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+    let self_user = session.get_self().await?;
+
+    let mut rules = session
+        .storage
+        .get_privacy_rules(self_user.id, &privacy::key(&message.obj.key))
+        .await?;
+    if rules.is_empty() {
+        rules.push(v!(PrivacyRuleVariant::PrivacyValueAllowAll {}));
+    }
+
+    ok_obj!(
+        message,
+        SchemaObject::AccountPrivacyRules(privacy_rules(&session, rules).await?)
+    )
+}
+
+///
+/// # Layer 158
+/// ## account.setPrivacy#c9f81ce8 key:InputPrivacyKey rules:Vector<InputPrivacyRule> = account.PrivacyRules;
+/// Change privacy settings of current account
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | key | InputPrivacyKey | New privacy rule |
+/// | rules | Vector<InputPrivacyRule> | Peers to which the privacy rules apply |
+///
+/// ## Behavior
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * Only the forwards key is enforced, other keys are just stored
+/// * There are no contacts and chats, contacts rules never match and chat participants rules are dropped
+///
+#[auth]
+pub async fn rpc_account_set_privacy(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<AccountSetPrivacy>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+    let self_user = session.get_self().await?;
+
+    let key = privacy::key(&message.obj.key);
+    let rules = privacy::rules(self_user.id, &message.obj.rules);
+    session
+        .storage
+        .set_privacy_rules(self_user.id, &key, &rules)
+        .await?;
+    let privacy_rules = privacy_rules(&session, rules).await?;
+    session
+        .bus
+        .publish_seq(
+            self_user.id,
+            vec![v!(UpdateVariant::UpdatePrivacy {
+                key,
+                rules: privacy_rules.rules.clone(),
+            })],
+            privacy_rules.users.clone(),
+        )
+        .await?;
+
+    ok_obj!(message, SchemaObject::AccountPrivacyRules(privacy_rules))
 }
@@ -1,33 +1,261 @@
+use crate::code_sender::{generate_code, generate_phone_code_hash, CODE_LENGTH};
+use crate::random::random_bytes;
 use crate::session::Session;
-use crate::{err, ok, rpc, v};
+use crate::storage::{ClientInfo, ExportedAuthorization};
+use crate::{err, ok, ok_obj, println_yellow, rpc, time, v};
+use catte_server::auth;
 use catte_tl_schema::*;
 use std::{error::Error, sync::Arc};
 use tokio::sync::Mutex;
 
+/// Strips formatting from a phone number, returns None if its not a valid number
+fn normalize_phone(phone_number: &str) -> Option<String> {
+    let phone = phone_number
+        .chars()
+        .filter(|c| !matches!(c, '+' | ' ' | '-' | '(' | ')'))
+        .collect::<String>();
+    if phone.len() < 5 || phone.len() > 15 || !phone.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    Some(phone)
+}
+
+fn authorization(mut user: User, setup_password_required: bool) -> AuthAuthorization {
+    user.is_self = true;
+    AuthAuthorization {
+        setup_password_required,
+        otherwise_relogin_days: None,
+        tmp_sessions: None,
+        future_auth_token: None,
+        user: UserVariant::User(Box::new(user)),
+    }
+}
+
+/// Wrong codes allowed per phone_code_hash, codes are short enough to be guessed otherwise
+const PHONE_CODE_ATTEMPTS: i32 = 5;
+
+/// Restricts a new login to setting up a 2FA password if the user must have one
+/// and doesn't yet, returns whether it did
+async fn restrict_to_password_setup(
+    session: &mut Session,
+    user: &User,
+) -> Result<bool, sqlx::Error> {
+    let required = session.config.require_password
+        && !user.bot
+        && session.storage.get_password(user.id).await.is_err();
+    if required {
+        session
+            .storage
+            .require_password_setup(session.auth_key_id)
+            .await?;
+    }
+    session.setup_password_required = required;
+    Ok(required)
+}
+
+/// Login tokens are shown as QR codes, clients refresh them once they expire
+const LOGIN_TOKEN_TTL: i32 = 30;
+
+/// Clients import exported authorizations right away
+const EXPORTED_AUTHORIZATION_TTL: i32 = 60;
+
+fn sent_code(phone_code_hash: String) -> AuthSentCode {
+    AuthSentCode {
+        r#type: v!(AuthSentCodeTypeVariant::AuthSentCodeTypeSms {
+            length: CODE_LENGTH as i32
+        }),
+        phone_code_hash,
+        next_type: Some(v!(AuthCodeTypeVariant::AuthCodeTypeSms {})),
+        timeout: Some(60),
+    }
+}
+
+///
+/// # Layer 158
+/// ## auth.sendCode#a677244f phone_number:string api_id:int api_hash:string settings:CodeSettings = auth.SentCode;
+/// Send the verification code for login
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | phone_number | string | Phone number in international format |
+/// | api_id | int | Application identifier |
+/// | api_hash | string | Application secret hash |
+/// | settings | CodeSettings | Settings for the code type to send |
+///
+/// ## Behavior
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * The code is delivered by the configured code sender instead of SMS
+/// * api_id, api_hash and settings are ignored
+///
 pub async fn rpc_auth_send_code(
     session: Arc<Mutex<Session>>,
     message: rpc::Message<AuthSendCode>,
 ) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
-    let mut session = session.lock().await;
+    let session = session.lock().await;
 
     if session.authorized {
         err!(message, 400, "PHONE_NUMBER_FLOOD");
     }
 
-    session.login_flow.phone_number = message.obj.phone_number;
-    session.login_flow.code = "12345".to_string(); // TODO: Generate random code
+    let Some(phone) = normalize_phone(&message.obj.phone_number) else {
+        err!(message, 400, "PHONE_NUMBER_INVALID");
+    };
 
-    ok!(
+    let phone_code_hash = generate_phone_code_hash();
+    let code = generate_code();
+    session
+        .storage
+        .insert_phone_code(
+            &phone,
+            &phone_code_hash,
+            &code,
+            time!() + session.config.phone_code_ttl,
+        )
+        .await?;
+
+    if let Err(e) = session.runtime_config.code_sender.send(&phone, &code).await {
+        println_yellow!("CODE SENDER", "{}", e);
+        session.storage.delete_phone_code(&phone_code_hash).await?;
+        err!(message, 500, "SEND_CODE_UNAVAILABLE");
+    }
+
+    ok_obj!(
         message,
-        AuthSentCode {
-            r#type: v!(AuthSentCodeTypeVariant::AuthSentCodeTypeSms { length: 5 }),
-            phone_code_hash: String::new(),
-            next_type: None,
-            timeout: None,
-        }
+        SchemaObject::AuthSentCode(sent_code(phone_code_hash))
     )
 }
 
+///
+/// # Layer 158
+/// ## auth.resendCode#3ef1a9bf phone_number:string phone_code_hash:string = auth.SentCode;
+/// Resend the login code via another medium
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | phone_number | string | The phone number |
+/// | phone_code_hash | string | The phone code hash obtained from auth.sendCode |
+///
+/// ## Behavior
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * A new code is sent using the same code sender, the phone_code_hash stays the same
+///
+pub async fn rpc_auth_resend_code(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<AuthResendCode>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+
+    if message.obj.phone_code_hash.is_empty() {
+        err!(message, 400, "PHONE_CODE_HASH_EMPTY");
+    }
+
+    let Some(phone) = normalize_phone(&message.obj.phone_number) else {
+        err!(message, 400, "PHONE_NUMBER_INVALID");
+    };
+
+    let Ok(phone_code) = session
+        .storage
+        .get_phone_code(&phone, &message.obj.phone_code_hash)
+        .await
+    else {
+        err!(message, 400, "PHONE_CODE_EXPIRED");
+    };
+
+    if phone_code.expired(time!()) {
+        session
+            .storage
+            .delete_phone_code(&message.obj.phone_code_hash)
+            .await?;
+        err!(message, 400, "PHONE_CODE_EXPIRED");
+    }
+
+    let code = generate_code();
+    session
+        .storage
+        .update_phone_code(
+            &message.obj.phone_code_hash,
+            &code,
+            time!() + session.config.phone_code_ttl,
+        )
+        .await?;
+
+    if let Err(e) = session.runtime_config.code_sender.send(&phone, &code).await {
+        println_yellow!("CODE SENDER", "{}", e);
+        err!(message, 500, "SEND_CODE_UNAVAILABLE");
+    }
+
+    ok_obj!(
+        message,
+        SchemaObject::AuthSentCode(sent_code(message.obj.phone_code_hash))
+    )
+}
+
+///
+/// # Layer 158
+/// ## auth.cancelCode#1f040578 phone_number:string phone_code_hash:string = Bool;
+/// Cancel the login verification code
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | phone_number | string | Phone number |
+/// | phone_code_hash | string | Phone code hash from auth.sendCode |
+///
+/// ## Behavior
+/// <strong>✅ This function behaves the same way as official Telegram servers</strong>
+///
+pub async fn rpc_auth_cancel_code(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<AuthCancelCode>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+
+    if message.obj.phone_code_hash.is_empty() {
+        err!(message, 400, "PHONE_CODE_HASH_EMPTY");
+    }
+
+    let Some(phone) = normalize_phone(&message.obj.phone_number) else {
+        err!(message, 400, "PHONE_NUMBER_INVALID");
+    };
+
+    if session
+        .storage
+        .get_phone_code(&phone, &message.obj.phone_code_hash)
+        .await
+        .is_err()
+    {
+        err!(message, 400, "PHONE_CODE_EXPIRED");
+    }
+
+    session
+        .storage
+        .delete_phone_code(&message.obj.phone_code_hash)
+        .await?;
+
+    ok!(message, BoolTrue {})
+}
+
+///
+/// # Layer 158
+/// ## auth.signIn#8d52a951 flags:# phone_number:string phone_code_hash:string phone_code:flags.0?string email_verification:flags.1?EmailVerification = auth.Authorization;
+/// Signs in a user with a validated phone number
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | flags | # | Flags, see TL conditional fields |
+/// | phone_number | string | Phone number in the international format |
+/// | phone_code_hash | string | SMS-message ID, obtained from auth.sendCode |
+/// | phone_code | flags.0?string | Valid numerical code from the SMS-message |
+/// | email_verification | flags.1?EmailVerification | Email verification code or token |
+///
+/// ## Behavior
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * email_verification is not supported
+/// * When `require_password` is enabled, users without a password can only set one up after logging in
+///
 pub async fn rpc_auth_sign_in(
     session: Arc<Mutex<Session>>,
     message: rpc::Message<AuthSignIn>,
@@ -38,39 +266,83 @@ pub async fn rpc_auth_sign_in(
         err!(message, 500, "SIGN_IN_FAILED")
     }
 
-    if let Some(code) = message.obj.phone_code {
-        if code != session.login_flow.code {
-            err!(message, 400, "PHONE_CODE_INVALID")
-        }
-    } else {
-        err!(message, 400, "PHONE_CODE_EMPTY")
+    if message.obj.phone_code_hash.is_empty() {
+        err!(message, 400, "PHONE_CODE_HASH_EMPTY");
     }
 
-    session.login_flow.phone_number_verified = true;
+    let Some(phone) = normalize_phone(&message.obj.phone_number) else {
+        err!(message, 400, "PHONE_NUMBER_INVALID");
+    };
+
+    let Some(code) = message.obj.phone_code.filter(|c| !c.is_empty()) else {
+        err!(message, 400, "PHONE_CODE_EMPTY")
+    };
 
-    if let Ok(mut user) = session
+    let Ok(phone_code) = session
         .storage
-        .get_user_by_phone(&session.login_flow.phone_number)
+        .get_phone_code(&phone, &message.obj.phone_code_hash)
         .await
-    {
-        user.is_self = true;
+    else {
+        err!(message, 400, "PHONE_CODE_EXPIRED");
+    };
+
+    if phone_code.expired(time!()) {
+        session
+            .storage
+            .delete_phone_code(&message.obj.phone_code_hash)
+            .await?;
+        err!(message, 400, "PHONE_CODE_EXPIRED");
+    }
+
+    if code != phone_code.code {
+        if !session
+            .storage
+            .fail_phone_code(&message.obj.phone_code_hash, PHONE_CODE_ATTEMPTS)
+            .await?
+        {
+            err!(message, 400, "PHONE_CODE_EXPIRED")
+        }
+        err!(message, 400, "PHONE_CODE_INVALID")
+    }
+
+    if let Ok(user) = session.storage.get_user_by_phone(&phone).await {
+        session
+            .storage
+            .delete_phone_code(&message.obj.phone_code_hash)
+            .await?;
+
+        let has_password = session.storage.get_password(user.id).await.is_ok();
         session
             .storage
-            .insert_session(session.auth_key_id, user.id)
+            .insert_session(
+                session.auth_key_id,
+                user.id,
+                has_password,
+                &session.client_info,
+            )
             .await?;
+        if has_password {
+            err!(message, 401, "SESSION_PASSWORD_NEEDED");
+        }
+
         session.authorized = true;
-        ok!(
+        rpc::messages::spawn_send_when_online(
+            session.config.data.clone(),
+            session.bus.clone(),
+            user.id,
+        );
+        let setup_password_required = restrict_to_password_setup(&mut session, &user).await?;
+        ok_obj!(
             message,
-            AuthAuthorization {
-                setup_password_required: false,
-                otherwise_relogin_days: None,
-                tmp_sessions: None,
-                future_auth_token: None,
-                user: UserVariant::User(Box::new(user)),
-            }
+            SchemaObject::AuthAuthorization(authorization(user, setup_password_required))
         )
     }
 
+    session
+        .storage
+        .verify_phone_code(&message.obj.phone_code_hash)
+        .await?;
+
     ok!(
         message,
         AuthAuthorizationSignUpRequired {
@@ -79,6 +351,23 @@ pub async fn rpc_auth_sign_in(
     )
 }
 
+///
+/// # Layer 158
+/// ## auth.signUp#80eee427 phone_number:string phone_code_hash:string first_name:string last_name:string = auth.Authorization;
+/// Registers a validated phone number in the system
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | phone_number | string | Phone number in the international format |
+/// | phone_code_hash | string | SMS-message ID |
+/// | first_name | string | New user first name |
+/// | last_name | string | New user last name |
+///
+/// ## Behavior
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * When `require_password` is enabled, new users can only set up a password after signing up
+///
 pub async fn rpc_auth_sign_up(
     session: Arc<Mutex<Session>>,
     message: rpc::Message<AuthSignUp>,
@@ -89,33 +378,483 @@ pub async fn rpc_auth_sign_up(
         err!(message, 500, "PHONE_NUMBER_FLOOD");
     }
 
-    if !session.login_flow.phone_number_verified {
+    if message.obj.phone_code_hash.is_empty() {
+        err!(message, 400, "PHONE_CODE_HASH_EMPTY");
+    }
+
+    let Some(phone) = normalize_phone(&message.obj.phone_number) else {
+        err!(message, 400, "PHONE_NUMBER_INVALID");
+    };
+
+    let Ok(phone_code) = session
+        .storage
+        .get_phone_code(&phone, &message.obj.phone_code_hash)
+        .await
+    else {
+        err!(message, 400, "PHONE_CODE_EXPIRED");
+    };
+
+    if phone_code.expired(time!()) {
+        session
+            .storage
+            .delete_phone_code(&message.obj.phone_code_hash)
+            .await?;
+        err!(message, 400, "PHONE_CODE_EXPIRED");
+    }
+
+    if !phone_code.verified {
         err!(message, 406, "PHONE_NUMBER_INVALID");
     }
 
+    if message.obj.first_name.trim().is_empty() {
+        err!(message, 400, "FIRST_NAME_INVALID");
+    }
+
+    if session.storage.get_user_by_phone(&phone).await.is_ok() {
+        err!(message, 400, "PHONE_NUMBER_OCCUPIED");
+    }
+
     let user = session
         .storage
-        .insert_user(
-            &message.obj.first_name,
-            &message.obj.last_name,
-            &session.login_flow.phone_number,
-        )
+        .insert_user(&message.obj.first_name, &message.obj.last_name, &phone)
         .await?;
 
     session
         .storage
-        .insert_session(session.auth_key_id, user.id)
+        .delete_phone_code(&message.obj.phone_code_hash)
         .await?;
+    session
+        .storage
+        .insert_session(session.auth_key_id, user.id, false, &session.client_info)
+        .await?;
+    session.authorized = true;
+    let setup_password_required = restrict_to_password_setup(&mut session, &user).await?;
+
+    ok_obj!(
+        message,
+        SchemaObject::AuthAuthorization(authorization(user, setup_password_required))
+    )
+}
+
+///
+/// # Layer 158
+/// ## auth.checkPassword#d18b4d16 password:InputCheckPasswordSRP = auth.Authorization;
+/// Try logging to an account protected by a 2FA password
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | password | InputCheckPasswordSRP | The account's password |
+///
+/// ## Behavior
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * Every account.getPassword call allows a single attempt
+/// * After 5 wrong passwords in a row every attempt waits 5 minutes
+///
+pub async fn rpc_auth_check_password(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<AuthCheckPassword>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let mut session = session.lock().await;
+
+    if session.authorized {
+        err!(message, 400, "PASSWORD_HASH_INVALID");
+    }
+
+    let Ok(true) = session
+        .storage
+        .get_password_pending(session.auth_key_id)
+        .await
+    else {
+        err!(message, 401, "AUTH_KEY_UNREGISTERED");
+    };
+
+    let user = session.get_self().await?;
+    let password = session.storage.get_password(user.id).await?;
+    if let Err(e) = session
+        .check_password(user.id, &password, &message.obj.password)
+        .await?
+    {
+        err!(message, e.error_code, e.error_message);
+    }
+
+    session.storage.confirm_session(session.auth_key_id).await?;
     session.authorized = true;
+    rpc::messages::spawn_send_when_online(
+        session.config.data.clone(),
+        session.bus.clone(),
+        user.id,
+    );
+
+    ok_obj!(
+        message,
+        SchemaObject::AuthAuthorization(authorization(user, false))
+    )
+}
+
+///
+/// # Layer 158
+/// ## auth.exportLoginToken#b7e085fe api_id:int api_hash:string except_ids:Vector<long> = auth.LoginToken;
+/// Generate a login token, for login via QR code
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | api_id | int | Application identifier |
+/// | api_hash | string | Application identifier hash |
+/// | except_ids | Vector<long> | List of already logged-in user IDs, to prevent logging in twice with the same user |
+///
+/// ## Behavior
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * auth.loginTokenMigrateTo is never returned
+/// * api_id, api_hash and except_ids are ignored
+///
+pub async fn rpc_auth_export_login_token(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<AuthExportLoginToken>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let mut session = session.lock().await;
+
+    if session.authorized {
+        let user = session.get_self().await?;
+        ok!(
+            message,
+            AuthLoginTokenSuccess {
+                authorization: AuthAuthorizationVariant::AuthAuthorization(Box::new(
+                    authorization(user, session.setup_password_required)
+                ))
+            }
+        )
+    }
+
+    if let Ok(token) = session
+        .storage
+        .get_accepted_login_token(session.auth_key_id)
+        .await
+    {
+        session
+            .storage
+            .delete_login_tokens(session.auth_key_id)
+            .await?;
+
+        // acceptLoginToken created the session, unless it was terminated since
+        if let Ok(user_session) = session.storage.get_user_session(session.auth_key_id).await {
+            session
+                .storage
+                .update_session_info(session.auth_key_id, &session.client_info)
+                .await?;
+            if user_session.password_pending {
+                err!(message, 401, "SESSION_PASSWORD_NEEDED");
+            }
+
+            let user = session.storage.get_user(token.user_id.unwrap()).await?;
+            session.authorized = true;
+            rpc::messages::spawn_send_when_online(
+                session.config.data.clone(),
+                session.bus.clone(),
+                user.id,
+            );
+            let setup_password_required = restrict_to_password_setup(&mut session, &user).await?;
+            ok!(
+                message,
+                AuthLoginTokenSuccess {
+                    authorization: AuthAuthorizationVariant::AuthAuthorization(Box::new(
+                        authorization(user, setup_password_required)
+                    ))
+                }
+            )
+        }
+    }
+
+    let token = random_bytes(32);
+    let expires = time!() + LOGIN_TOKEN_TTL;
+    session
+        .storage
+        .insert_login_token(&token, session.auth_key_id, expires)
+        .await?;
+
+    ok!(message, AuthLoginToken { expires, token })
+}
+
+///
+/// # Layer 158
+/// ## auth.acceptLoginToken#e894ad4d token:bytes = Authorization;
+/// Accept QR code login token, logging in the app that generated it
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | token | bytes | Login token embedded in QR code |
+///
+/// ## Behavior
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * The returned authorization does not contain any device info
+///
+#[auth]
+pub async fn rpc_auth_accept_login_token(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<AuthAcceptLoginToken>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+
+    let Ok(token) = session.storage.get_login_token(&message.obj.token).await else {
+        err!(message, 400, "AUTH_TOKEN_INVALID");
+    };
+
+    if token.expires_at < time!() {
+        err!(message, 400, "AUTH_TOKEN_EXPIRED");
+    }
+
+    if token.user_id.is_some() {
+        err!(message, 400, "AUTH_TOKEN_ALREADY_ACCEPTED");
+    }
+
+    let self_user = session.get_self().await?;
+    session
+        .storage
+        .accept_login_token(&message.obj.token, self_user.id)
+        .await?;
+    // The session is created right away so that the returned hash can terminate it
+    let has_password = session.storage.get_password(self_user.id).await.is_ok();
+    session
+        .storage
+        .insert_session(
+            token.auth_key_id,
+            self_user.id,
+            has_password,
+            &ClientInfo::default(),
+        )
+        .await?;
+    let user_session = session.storage.get_user_session(token.auth_key_id).await?;
+
+    // The waiting client calls auth.exportLoginToken again once it gets this
+    session
+        .bus
+        .push(
+            token.auth_key_id,
+            SchemaObject::UpdateShort(UpdateShort {
+                update: v!(UpdateVariant::UpdateLoginToken {}),
+                date: time!(),
+            }),
+        )
+        .await;
+
+    ok_obj!(
+        message,
+        SchemaObject::Authorization(rpc::account::authorization(user_session, false))
+    )
+}
+
+///
+/// # Layer 158
+/// ## auth.logOut#3e72ba19 = auth.LoggedOut;
+/// Logs out the user
+///
+/// ## Behavior
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * future_auth_token is never returned
+/// * All connections using the auth key are closed after the response is sent
+///
+#[auth(bots, setup_password)]
+pub async fn rpc_auth_log_out(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<AuthLogOut>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let mut session = session.lock().await;
+
+    session.storage.delete_session(session.auth_key_id).await?;
+    session.authorized = false;
+    session.bus.terminate(session.auth_key_id).await;
 
     ok!(
         message,
-        AuthAuthorization {
-            setup_password_required: false,
-            otherwise_relogin_days: None,
-            tmp_sessions: None,
-            future_auth_token: None,
-            user: UserVariant::User(Box::new(user)),
+        AuthLoggedOut {
+            future_auth_token: None
+        }
+    )
+}
+
+///
+/// # Layer 158
+/// ## auth.resetAuthorizations#9fab0d1a = Bool;
+/// Terminates all user's authorized sessions except for the current one
+///
+/// ## Behavior
+/// <strong>✅ This function behaves the same way as official Telegram servers</strong>
+///
+#[auth]
+pub async fn rpc_auth_reset_authorizations(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<AuthResetAuthorizations>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+    let self_user = session.get_self().await?;
+
+    for user_session in session.storage.get_user_sessions(self_user.id).await? {
+        if user_session.id == session.auth_key_id {
+            continue;
         }
+        session.storage.delete_session(user_session.id).await?;
+        session.bus.terminate(user_session.id).await;
+    }
+
+    ok!(message, BoolTrue {})
+}
+
+///
+/// # Layer 158
+/// ## auth.importBotAuthorization#67a3ff2c flags:int api_id:int api_hash:string bot_auth_token:string = auth.Authorization;
+/// Login as a bot
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | flags | int | Reserved for future use |
+/// | api_id | int | Application identifier |
+/// | api_hash | string | Application identifier hash |
+/// | bot_auth_token | string | Bot token, bots are created with `catte-server create-bot` |
+///
+/// ## Behavior
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * api_id and api_hash are ignored
+///
+pub async fn rpc_auth_import_bot_authorization(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<AuthImportBotAuthorization>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let mut session = session.lock().await;
+
+    if session.authorized {
+        err!(message, 400, "USER_ALREADY_AUTHORIZED");
+    }
+
+    let Ok(user) = session
+        .storage
+        .get_user_by_bot_token(&message.obj.bot_auth_token)
+        .await
+    else {
+        err!(message, 400, "ACCESS_TOKEN_INVALID");
+    };
+
+    session
+        .storage
+        .insert_session(session.auth_key_id, user.id, false, &session.client_info)
+        .await?;
+    session.authorized = true;
+    rpc::messages::spawn_send_when_online(
+        session.config.data.clone(),
+        session.bus.clone(),
+        user.id,
+    );
+    session.bot = true;
+
+    ok_obj!(
+        message,
+        SchemaObject::AuthAuthorization(authorization(user, false))
+    )
+}
+
+///
+/// # Layer 158
+/// ## auth.exportAuthorization#e5bfffcd dc_id:int = auth.ExportedAuthorization;
+/// Returns data for copying authorization to another data-center
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | dc_id | int | Number of a target data-center |
+///
+/// ## Behavior
+/// <strong>✅ This function behaves the same way as official Telegram servers</strong>
+///
+#[auth(bots)]
+pub async fn rpc_auth_export_authorization(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<AuthExportAuthorization>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+
+    // Same DCs as advertised in help.getConfig
+    if !(1..=5).contains(&message.obj.dc_id) || message.obj.dc_id == session.dc_id {
+        err!(message, 400, "DC_ID_INVALID");
+    }
+
+    let self_user = session.get_self().await?;
+    let id = rand::random::<i64>();
+    let bytes = random_bytes(32);
+    session
+        .storage
+        .insert_exported_authorization(
+            id,
+            &ExportedAuthorization {
+                bytes: bytes.clone(),
+                user_id: self_user.id,
+                dc_id: message.obj.dc_id,
+                expires_at: time!() + EXPORTED_AUTHORIZATION_TTL,
+            },
+        )
+        .await?;
+
+    ok!(message, AuthExportedAuthorization { id, bytes })
+}
+
+///
+/// # Layer 158
+/// ## auth.importAuthorization#a57a7dad id:long bytes:bytes = auth.Authorization;
+/// Logs in a user using a key transferred from a different data-center
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | id | long | User ID |
+/// | bytes | bytes | Authorization key |
+///
+/// ## Behavior
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * id is the id returned by auth.exportAuthorization instead of the user id
+///
+pub async fn rpc_auth_import_authorization(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<AuthImportAuthorization>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let mut session = session.lock().await;
+
+    if session.authorized {
+        err!(message, 400, "USER_ALREADY_AUTHORIZED");
+    }
+
+    let Ok(exported) = session
+        .storage
+        .take_exported_authorization(message.obj.id)
+        .await
+    else {
+        err!(message, 400, "AUTH_BYTES_INVALID");
+    };
+
+    if exported.bytes != message.obj.bytes || exported.expires_at < time!() {
+        err!(message, 400, "AUTH_BYTES_INVALID");
+    }
+
+    if exported.dc_id != session.dc_id {
+        err!(message, 400, "DC_ID_INVALID");
+    }
+
+    let user = session.storage.get_user(exported.user_id).await?;
+    session
+        .storage
+        .insert_session(session.auth_key_id, user.id, false, &session.client_info)
+        .await?;
+    session.authorized = true;
+    rpc::messages::spawn_send_when_online(
+        session.config.data.clone(),
+        session.bus.clone(),
+        user.id,
+    );
+    session.bot = user.bot;
+    let setup_password_required = restrict_to_password_setup(&mut session, &user).await?;
+
+    ok_obj!(
+        message,
+        SchemaObject::AuthAuthorization(authorization(user, setup_password_required))
     )
 }
@@ -156,13 +156,29 @@ pub async fn rpc_invoke_after_msg(
 /// | query | !X | The query itself |
 ///
 /// ## Behavior
-/// <strong>❌ This function does not behave the same way as official Telegram servers</strong>
-/// * Immediately executes the query without checking if msg_id was successfully completed
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * lang_pack is used as the platform name shown in account.getAuthorizations
+/// * proxy and params are ignored
 ///
 pub async fn rpc_init_connection(
     session: Arc<Mutex<Session>>,
     message: rpc::Message<InitConnection>,
 ) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    {
+        let mut session = session.lock().await;
+        session.client_info.api_id = message.obj.api_id;
+        session.client_info.device_model = message.obj.device_model;
+        session.client_info.platform = message.obj.lang_pack;
+        session.client_info.system_version = message.obj.system_version;
+        session.client_info.app_version = message.obj.app_version;
+        if session.authorized {
+            session
+                .storage
+                .update_session_info(session.auth_key_id, &session.client_info)
+                .await?;
+        }
+    }
+
     Box::pin(async {
         rpc::invoke(
             session,
@@ -4,6 +4,9 @@ use catte_tl_schema::*;
 use std::{error::Error, sync::Arc};
 use tokio::sync::Mutex;
 
+/// Seconds after sending during which a message can be edited
+pub const EDIT_TIME_LIMIT: i32 = 172800;
+
 pub async fn rpc_help_get_config(
     session: Arc<Mutex<Session>>,
     message: rpc::Message<HelpGetConfig>,
@@ -15,7 +18,7 @@ pub async fn rpc_help_get_config(
             date: time!(),
             expires: time!() + 1800,
             test_mode: false,
-            this_dc: 2,
+            this_dc: locked_session.dc_id,
             dc_options: vec![
                 DcOption {
                     id: 1,
@@ -90,7 +93,7 @@ pub async fn rpc_help_get_config(
             notify_default_delay_ms: 1500,
             push_chat_period_ms: 60000,
             push_chat_limit: 2,
-            edit_time_limit: 172800,
+            edit_time_limit: EDIT_TIME_LIMIT,
             revoke_time_limit: 2147483647,
             revoke_pm_time_limit: 2147483647,
             rating_e_decay: 2419200,
@@ -125,15 +128,16 @@ pub async fn rpc_help_get_config(
 }
 
 pub async fn rpc_help_get_nearest_dc(
-    _session: Arc<Mutex<Session>>,
+    session: Arc<Mutex<Session>>,
     message: rpc::Message<HelpGetNearestDc>,
 ) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let dc_id = session.lock().await.dc_id;
     ok!(
         message,
         NearestDc {
             country: "en".to_string(),
-            this_dc: 2,
-            nearest_dc: 2,
+            this_dc: dc_id,
+            nearest_dc: dc_id,
         }
     )
 }
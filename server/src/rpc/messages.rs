@@ -1,8 +1,17 @@
+use crate::bus::Bus;
+use crate::rpc::help::EDIT_TIME_LIMIT;
+use crate::rpc::updates::updates_state;
 use crate::session::Session;
-use crate::{err, ok, ok_vec, rpc, time};
+use crate::storage::{
+    MessageFilter, NewMessage, ScheduledMessage, SentMessage, Storage, SEND_WHEN_ONLINE,
+};
+use crate::{entities, err, ok, ok_obj, ok_vec, println_yellow, privacy, rpc, time, v};
 use catte_server::auth;
+use catte_tl_buffer::TlBuffer;
 use catte_tl_schema::*;
-use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet};
+use std::hash::{Hash, Hasher};
 use std::{error::Error, sync::Arc};
 use tokio::sync::Mutex;
 
@@ -40,7 +49,7 @@ pub async fn rpc_messages_get_featured_emoji_stickers(
     )
 }
 
-#[auth]
+#[auth(bots)]
 pub async fn rpc_messages_get_sticker_set(
     session: Arc<Mutex<Session>>,
     message: rpc::Message<MessagesGetStickerSet>,
@@ -74,94 +83,922 @@ pub async fn rpc_messages_get_sticker_set(
     )
 }
 
+/// Updates returned to the sender, updateMessageID maps the client's random_id to the new message
+fn sent_updates(sent: Vec<(i64, Message, i32)>, users: Vec<UserVariant>) -> Updates {
+    let mut updates = vec![];
+    for (random_id, message, pts) in sent {
+        updates.push(v!(UpdateVariant::UpdateMessageId {
+            id: message.id,
+            random_id,
+        }));
+        updates.push(update_new_message(message, pts));
+    }
+    Updates {
+        updates,
+        users,
+        chats: vec![],
+        date: time!(),
+        seq: 0,
+    }
+}
+
+/// Messages that were already sent with the random_ids, None if one of them
+/// is still being sent or was deleted since
+async fn already_sent(
+    storage: &Storage,
+    user_id: i64,
+    sent: Vec<(i64, SentMessage)>,
+) -> Result<Option<Vec<(i64, Message, i32)>>, sqlx::Error> {
+    let mut sent_messages = vec![];
+    for (random_id, sent) in sent {
+        match storage.get_message(user_id, sent.message_id).await {
+            Ok(sent_message) => sent_messages.push((random_id, sent_message, sent.pts)),
+            Err(sqlx::Error::RowNotFound) => return Ok(None),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(Some(sent_messages))
+}
+
+/// Answers a retried request with the updates of the messages that were already sent
+async fn resend<T>(
+    session: &Session,
+    self_user: &User,
+    message: &rpc::Message<T>,
+    sent: Vec<(i64, SentMessage)>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let Some(sent_messages) = already_sent(&session.storage, self_user.id, sent).await? else {
+        err!(message, 400, "RANDOM_ID_DUPLICATE")
+    };
+    let mut user_ids = BTreeSet::new();
+    for (_, sent_message, _) in &sent_messages {
+        add_message_users(&mut user_ids, sent_message);
+    }
+    let users = get_users(session, self_user, user_ids).await?;
+    ok_obj!(
+        message,
+        SchemaObject::Updates(sent_updates(sent_messages, users))
+    )
+}
+
+/// Reserves the random_ids of messages about to be sent, returns the ones that were already used.
+/// Messages that are still being sent have a message_id of 0
+async fn reserve_random_ids(
+    storage: &Storage,
+    user_id: i64,
+    random_ids: &[i64],
+) -> Result<Vec<(i64, SentMessage)>, sqlx::Error> {
+    let mut used = vec![];
+    for &random_id in random_ids {
+        if !storage.reserve_random_id(user_id, random_id).await? {
+            let sent = storage
+                .get_random_id(user_id, random_id)
+                .await?
+                .unwrap_or(SentMessage {
+                    message_id: 0,
+                    pts: 0,
+                });
+            used.push((random_id, sent));
+        }
+    }
+    Ok(used)
+}
+
+/// Header of a message forwarded for the first time,
+/// authors that don't allow linking to them in forwards are only named
+async fn fwd_header(
+    storage: &Storage,
+    self_user: &User,
+    author_id: i64,
+    date: i32,
+) -> Result<MessageFwdHeader, sqlx::Error> {
+    let forwards = v!(PrivacyKeyVariant::PrivacyKeyForwards {});
+    let hidden = author_id != self_user.id
+        && !privacy::allows(
+            &storage.get_privacy_rules(author_id, &forwards).await?,
+            self_user.id,
+        );
+    let (from_id, from_name) = if hidden {
+        let author = storage.get_user(author_id).await?;
+        let name = [author.first_name, author.last_name]
+            .into_iter()
+            .flatten()
+            .filter(|name| !name.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ");
+        (None, Some(name))
+    } else {
+        (
+            Some(PeerVariant::PeerUser(Box::new(PeerUser {
+                user_id: author_id,
+            }))),
+            None,
+        )
+    };
+    Ok(MessageFwdHeader {
+        imported: false,
+        from_id,
+        from_name,
+        date,
+        channel_post: None,
+        post_author: None,
+        saved_from_peer: None,
+        saved_from_msg_id: None,
+        psa_type: None,
+    })
+}
+
+/// Checks that the replied message belongs to the dialog,
+/// returns it along with the first message of its thread
+async fn reply_to(
+    storage: &Storage,
+    user_id: i64,
+    peer_id: i64,
+    reply_to_msg_id: Option<i32>,
+) -> Result<(Option<i32>, Option<i32>), sqlx::Error> {
+    let Some(reply_to_msg_id) = reply_to_msg_id else {
+        return Ok((None, None));
+    };
+    let replied = match storage.get_message(user_id, reply_to_msg_id).await {
+        Ok(replied) => replied,
+        // Replies to deleted messages are sent as regular messages
+        Err(sqlx::Error::RowNotFound) => return Ok((None, None)),
+        Err(e) => return Err(e),
+    };
+    match &replied.peer_id {
+        PeerVariant::PeerUser(peer) if peer.user_id == peer_id => {}
+        _ => return Ok((None, None)),
+    }
+    let top_id = match replied.reply_to {
+        Some(header) => header.reply_to_top_id.unwrap_or(header.reply_to_msg_id),
+        None => reply_to_msg_id,
+    };
+    Ok((Some(reply_to_msg_id), Some(top_id)))
+}
+
+/// Id of the other copy of a message in the peer's message box
+async fn peer_message_id(
+    storage: &Storage,
+    user_id: i64,
+    id: Option<i32>,
+) -> Result<Option<i32>, sqlx::Error> {
+    let Some(id) = id else {
+        return Ok(None);
+    };
+    Ok(storage
+        .get_message_link(user_id, id)
+        .await?
+        .and_then(|link| link.peer_message_id))
+}
+
+fn update_short_message(
+    message: &Message,
+    user_id: i64,
+    pts: i32,
+    silent: bool,
+) -> UpdateShortMessage {
+    UpdateShortMessage {
+        out: message.out,
+        mentioned: message.mentioned,
+        media_unread: message.media_unread,
+        silent,
+        id: message.id,
+        user_id,
+        message: message.message.clone(),
+        pts,
+        pts_count: 1,
+        date: message.date,
+        fwd_from: message.fwd_from.clone(),
+        via_bot_id: None,
+        reply_to: message.reply_to.clone(),
+        entities: message.entities.clone(),
+        ttl_period: None,
+    }
+}
+
+/// Stores an outgoing private message in the sender's and the recipient's message boxes,
+/// logs and pushes the new message to both users and fills in its random_id if there is one.
+/// The random_id has to be reserved, it's released if the message couldn't be stored.
+/// `except` is the sender's session that gets the updates in the response instead.
+/// Returns the sender's copy and pts
+async fn send_message(
+    storage: &Storage,
+    bus: &Bus,
+    self_user: &User,
+    new_message: NewMessage,
+    random_id: Option<i64>,
+    silent: bool,
+    except: Option<i64>,
+) -> Result<(Message, i32), sqlx::Error> {
+    let peer_user_id = new_message.peer_id;
+    let stored = async {
+        let sent_message = storage.insert_message(self_user.id, &new_message).await?;
+        storage
+            .update_dialog(self_user.id, peer_user_id, &sent_message)
+            .await?;
+        let pts = storage
+            .log_update(self_user.id, 1, |pts| {
+                update_new_message(sent_message.clone(), pts)
+            })
+            .await?;
+        Ok::<_, sqlx::Error>((sent_message, pts))
+    }
+    .await;
+    let (sent_message, pts) = match stored {
+        Ok(stored) => stored,
+        Err(e) => {
+            if let Some(random_id) = random_id {
+                storage.release_random_id(self_user.id, random_id).await?;
+            }
+            return Err(e);
+        }
+    };
+    if let Some(random_id) = random_id {
+        storage
+            .set_random_id(
+                self_user.id,
+                random_id,
+                &SentMessage {
+                    message_id: sent_message.id,
+                    pts,
+                },
+            )
+            .await?;
+    }
+
+    // Other devices of the sender
+    bus.publish(
+        self_user.id,
+        SchemaObject::UpdateShortMessage(update_short_message(
+            &sent_message,
+            peer_user_id,
+            pts,
+            silent,
+        )),
+        except,
+    )
+    .await?;
+
+    if peer_user_id != self_user.id {
+        // Mentions of the recipient stay unread until it reads the message contents
+        let mentioned = match &new_message.entities {
+            Some(entities) => {
+                let peer_user = storage.get_user(peer_user_id).await?;
+                entities::mentions(&new_message.message, entities, &peer_user)
+            }
+            None => false,
+        };
+        // The recipient gets its own copy in its message box, replies point to its own ids
+        let received = NewMessage {
+            peer_id: self_user.id,
+            out: false,
+            mentioned,
+            // There is no media yet, so mentions are the only unread contents
+            media_unread: mentioned,
+            reply_to_msg_id: peer_message_id(storage, self_user.id, new_message.reply_to_msg_id)
+                .await?,
+            reply_to_top_id: peer_message_id(storage, self_user.id, new_message.reply_to_top_id)
+                .await?,
+            ..new_message
+        };
+        let received_message = storage.insert_message(peer_user_id, &received).await?;
+        storage
+            .link_messages(
+                self_user.id,
+                sent_message.id,
+                peer_user_id,
+                received_message.id,
+            )
+            .await?;
+        storage
+            .update_dialog(peer_user_id, self_user.id, &received_message)
+            .await?;
+        let peer_pts = storage
+            .log_update(peer_user_id, 1, |pts| {
+                update_new_message(received_message.clone(), pts)
+            })
+            .await?;
+        bus.publish(
+            peer_user_id,
+            SchemaObject::UpdateShortMessage(update_short_message(
+                &received_message,
+                self_user.id,
+                peer_pts,
+                silent,
+            )),
+            None,
+        )
+        .await?;
+    }
+
+    Ok((sent_message, pts))
+}
+
+fn update_new_message(message: Message, pts: i32) -> UpdateVariant {
+    v!(UpdateVariant::UpdateNewMessage {
+        message: MessageVariant::Message(Box::new(message)),
+        pts,
+        pts_count: 1,
+    })
+}
+
+/// Dialogs returned by a single messages.getDialogs call
+const DIALOGS_LIMIT: i32 = 100;
+
+/// Messages returned by a single messages.getHistory call
+const HISTORY_LIMIT: i32 = 100;
+
+/// Maximum message length in UTF-16 code units
+const MESSAGE_LENGTH_LIMIT: usize = 4096;
+
+/// How far ahead a message can be scheduled, in seconds
+const SCHEDULE_PERIOD_LIMIT: i32 = 365 * 24 * 60 * 60;
+
+/// Scheduled messages a single dialog can hold
+const SCHEDULED_MESSAGE_LIMIT: i32 = 100;
+
+/// Resolves the user on the other side of a private chat,
+/// None if the peer is not a private chat or the user doesn't exist
+async fn resolve_peer(
+    session: &Session,
+    self_user: &User,
+    peer: &InputPeerVariant,
+) -> Result<Option<i64>, sqlx::Error> {
+    let user_id = match peer {
+        InputPeerVariant::InputPeerSelf(_) => return Ok(Some(self_user.id)),
+        InputPeerVariant::InputPeerUser(peer) => peer.user_id,
+        _ => return Ok(None),
+    };
+    match session.storage.get_user(user_id).await {
+        Ok(_) => Ok(Some(user_id)),
+        Err(sqlx::Error::RowNotFound) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Entities to store with a message: the client's ones after validation,
+/// or the URLs, mentions and hashtags found in the text if the client sent none
+async fn message_entities(
+    session: &Session,
+    self_user: &User,
+    text: &str,
+    entities: Option<Vec<MessageEntityVariant>>,
+) -> Result<Result<Option<Vec<MessageEntityVariant>>, &'static str>, sqlx::Error> {
+    let Some(entities) = entities else {
+        let entities = entities::parse(text);
+        return Ok(Ok(Some(entities).filter(|e| !e.is_empty())));
+    };
+    if let Err(e) = entities::validate(text, &entities) {
+        return Ok(Err(e));
+    }
+    let mut stored = vec![];
+    for entity in entities {
+        // Mentions of users without a username refer to them by InputUser
+        let MessageEntityVariant::InputMessageEntityMentionName(mention) = entity else {
+            stored.push(entity);
+            continue;
+        };
+        let user_id = match &mention.user_id {
+            InputUserVariant::InputUserSelf(_) => self_user.id,
+            InputUserVariant::InputUser(user) => user.user_id,
+            _ => continue,
+        };
+        match session.storage.get_user(user_id).await {
+            Ok(_) => stored.push(MessageEntityVariant::MessageEntityMentionName(Box::new(
+                MessageEntityMentionName {
+                    offset: mention.offset,
+                    length: mention.length,
+                    user_id,
+                },
+            ))),
+            Err(sqlx::Error::RowNotFound) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(Ok(Some(stored).filter(|e| !e.is_empty())))
+}
+
+/// Serialized entities, the schema types can't be compared directly
+fn entities_data(entities: Option<&[MessageEntityVariant]>) -> Vec<u8> {
+    let mut data = TlBuffer::new(vec![]);
+    for entity in entities.unwrap_or_default() {
+        entity.write(&mut data);
+    }
+    data.data().to_vec()
+}
+
+fn add_message_users(user_ids: &mut BTreeSet<i64>, message: &Message) {
+    let fwd_from_id = message
+        .fwd_from
+        .as_ref()
+        .and_then(|fwd_from| fwd_from.from_id.as_ref());
+    for peer in [
+        Some(&message.peer_id),
+        message.from_id.as_ref(),
+        fwd_from_id,
+    ]
+    .into_iter()
+    .flatten()
+    {
+        if let PeerVariant::PeerUser(peer) = peer {
+            user_ids.insert(peer.user_id);
+        }
+    }
+    for entity in message.entities.iter().flatten() {
+        if let MessageEntityVariant::MessageEntityMentionName(mention) = entity {
+            user_ids.insert(mention.user_id);
+        }
+    }
+}
+
+fn add_message_variant_users(user_ids: &mut BTreeSet<i64>, message: &MessageVariant) {
+    match message {
+        MessageVariant::Message(message) => add_message_users(user_ids, message),
+        MessageVariant::MessageService(message) => {
+            for peer in [Some(&message.peer_id), message.from_id.as_ref()]
+                .into_iter()
+                .flatten()
+            {
+                if let PeerVariant::PeerUser(peer) = peer {
+                    user_ids.insert(peer.user_id);
+                }
+            }
+        }
+        MessageVariant::MessageEmpty(_) => {}
+    }
+}
+
+/// Id and date of a regular or service message
+fn message_id_date(message: &MessageVariant) -> (i32, i32) {
+    match message {
+        MessageVariant::Message(message) => (message.id, message.date),
+        MessageVariant::MessageService(message) => (message.id, message.date),
+        MessageVariant::MessageEmpty(message) => (message.id, 0),
+    }
+}
+
+async fn get_users(
+    session: &Session,
+    self_user: &User,
+    user_ids: BTreeSet<i64>,
+) -> Result<Vec<UserVariant>, sqlx::Error> {
+    let mut users = vec![];
+    for user_id in user_ids {
+        if user_id == self_user.id {
+            users.push(UserVariant::User(Box::new(self_user.clone())));
+        } else {
+            let mut user = session.storage.get_user(user_id).await?;
+            user.access_hash = Some(0);
+            users.push(UserVariant::User(Box::new(user)));
+        }
+    }
+    Ok(users)
+}
+
+/// Hash of a cached list, see https://core.telegram.org/api/offsets#hash-generation
+fn list_hash(ids: impl IntoIterator<Item = i64>) -> i64 {
+    ids.into_iter().fold(0u64, |mut hash, id| {
+        hash ^= hash >> 21;
+        hash ^= hash << 35;
+        hash ^= hash >> 4;
+        hash.wrapping_add(id as u64)
+    }) as i64
+}
+
+/// Top messages and users needed to display the dialogs
+async fn dialogs_contents(
+    session: &Session,
+    self_user: &User,
+    dialogs: &[Dialog],
+) -> Result<(Vec<MessageVariant>, Vec<UserVariant>), sqlx::Error> {
+    let mut messages = vec![];
+    let mut user_ids = BTreeSet::new();
+    for dialog in dialogs {
+        if let PeerVariant::PeerUser(peer) = &dialog.peer {
+            user_ids.insert(peer.user_id);
+        }
+        // Cleared histories keep their dialog without a top message
+        if dialog.top_message == 0 {
+            continue;
+        }
+        let top_message = session
+            .storage
+            .get_message_variant(self_user.id, dialog.top_message)
+            .await?;
+        add_message_variant_users(&mut user_ids, &top_message);
+        messages.push(top_message);
+    }
+
+    let users = get_users(session, self_user, user_ids).await?;
+    Ok((messages, users))
+}
+
+///
+/// # Layer 158
+/// ## messages.getDialogs#a0f4cb4f flags:# exclude_pinned:flags.0?true folder_id:flags.1?int offset_date:int offset_id:int offset_peer:InputPeer limit:int hash:long = messages.Dialogs;
+/// Returns the current user dialog list.
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | flags | # | Flags, see TL conditional fields |
+/// | exclude_pinned | flags.0?true | Exclude pinned dialogs |
+/// | folder_id | flags.1?int | Peer folder ID, for more info click here |
+/// | offset_date | int | Offsets for pagination, for more info click here |
+/// | offset_id | int | Offsets for pagination, for more info click here |
+/// | offset_peer | InputPeer | Offset peer for pagination |
+/// | limit | int | Number of list elements to be returned |
+/// | hash | long | Hash for pagination, for more info click here |
+///
+/// ## Behavior
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * offset_peer is ignored, top message ids are unique within the user's dialogs
+/// * Pinned dialogs are returned on top of the first page and don't count towards limit
+///
 #[auth]
 pub async fn rpc_messages_get_dialogs(
     session: Arc<Mutex<Session>>,
     message: rpc::Message<MessagesGetDialogs>,
 ) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+    let self_user = session.get_self().await?;
+    let folder_id = message.obj.folder_id.unwrap_or(0);
+    let first_page = message.obj.offset_date == 0 && message.obj.offset_id == 0;
+
+    let mut dialogs = if first_page && !message.obj.exclude_pinned {
+        session
+            .storage
+            .get_pinned_dialogs(self_user.id, folder_id)
+            .await?
+    } else {
+        vec![]
+    };
+    let offset_date = match message.obj.offset_date {
+        0 => i32::MAX,
+        offset_date => offset_date,
+    };
+    let offset_id = if first_page {
+        i32::MAX
+    } else {
+        message.obj.offset_id
+    };
+    dialogs.extend(
+        session
+            .storage
+            .get_dialogs(
+                self_user.id,
+                folder_id,
+                offset_date,
+                offset_id,
+                message.obj.limit.clamp(1, DIALOGS_LIMIT),
+            )
+            .await?,
+    );
+
+    let count = session
+        .storage
+        .count_dialogs(self_user.id, folder_id)
+        .await?;
+    let hash = list_hash(dialogs.iter().flat_map(|dialog| {
+        let peer_id = match &dialog.peer {
+            PeerVariant::PeerUser(peer) => peer.user_id,
+            _ => 0,
+        };
+        [
+            peer_id,
+            dialog.top_message as i64,
+            dialog.read_inbox_max_id as i64,
+            dialog.unread_count as i64,
+            dialog.pinned as i64,
+        ]
+    }));
+    if message.obj.hash != 0 && message.obj.hash == hash {
+        ok!(message, MessagesDialogsNotModified { count })
+    }
+
+    let (messages, users) = dialogs_contents(&session, &self_user, &dialogs).await?;
+    let complete = first_page && dialogs.len() as i32 == count;
+    let dialogs = dialogs
+        .into_iter()
+        .map(|dialog| DialogVariant::Dialog(Box::new(dialog)))
+        .collect();
+    if complete {
+        ok!(
+            message,
+            MessagesDialogs {
+                dialogs,
+                messages,
+                chats: vec![],
+                users,
+            }
+        )
+    }
     ok!(
         message,
-        MessagesDialogs {
-            dialogs: vec![],
-            messages: vec![],
+        MessagesDialogsSlice {
+            count,
+            dialogs,
+            messages,
             chats: vec![],
-            users: vec![],
+            users,
         }
     )
 }
 
+///
+/// # Layer 158
+/// ## messages.getPeerDialogs#e470bcfd peers:Vector<InputDialogPeer> = messages.PeerDialogs;
+/// Get dialog info of specified peers
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | peers | Vector<InputDialogPeer> | Peers |
+///
+/// ## Behavior
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * Peers without a dialog and folder peers are omitted from the result
+///
 #[auth]
-pub async fn rpc_messages_get_history(
+pub async fn rpc_messages_get_peer_dialogs(
     session: Arc<Mutex<Session>>,
-    message: rpc::Message<MessagesGetHistory>,
+    message: rpc::Message<MessagesGetPeerDialogs>,
 ) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
     let session = session.lock().await;
     let self_user = session.get_self().await?;
 
-    let (mb_key_primary, mb_key_secondary) = match message.obj.peer {
-        InputPeerVariant::InputPeerSelf(_) => (self_user.id, Some(self_user.id)),
-        InputPeerVariant::InputPeerUser(peer) => {
-            let mut k = vec![peer.user_id, self_user.id];
-            k.sort();
-            (k[0], Some(k[1]))
+    let mut dialogs = vec![];
+    for peer in message.obj.peers.iter() {
+        let InputDialogPeerVariant::InputDialogPeer(peer) = peer else {
+            continue;
+        };
+        let Some(peer_user_id) = resolve_peer(&session, &self_user, &peer.peer).await? else {
+            err!(message, 400, "PEER_ID_INVALID")
+        };
+        if let Some(dialog) = session
+            .storage
+            .get_dialog(self_user.id, peer_user_id)
+            .await?
+        {
+            dialogs.push(dialog);
         }
-        _ => todo!(),
-    };
+    }
 
-    let messages = session
-        .storage
-        .get_messages(
-            mb_key_primary,
-            mb_key_secondary,
-            message.obj.limit,
-            message.obj.offset_id,
-        )
-        .await?
-        .into_iter()
-        .map(|mut m| match m.peer_id {
-            PeerVariant::PeerUser(ref peer) => {
-                if peer.user_id == self_user.id {
-                    m.out = true;
-                }
-                MessageVariant::Message(Box::new(m))
-            }
-            _ => todo!(),
-        })
-        .collect::<Vec<MessageVariant>>();
-
-    let mut users: HashMap<i64, UserVariant> = HashMap::new();
-    for message in messages.iter() {
-        match message {
-            MessageVariant::Message(m) => match &m.peer_id {
-                PeerVariant::PeerUser(u) => {
-                    if !users.contains_key(&u.user_id) {
-                        let mut user = session.storage.get_user(u.user_id).await?;
-                        user.access_hash = Some(0);
-                        users.insert(u.user_id, UserVariant::User(Box::new(user)));
-                    }
-                }
-                _ => todo!(),
-            },
-            _ => todo!(),
+    let (messages, users) = dialogs_contents(&session, &self_user, &dialogs).await?;
+    let state = session.storage.get_user_state(self_user.id).await?;
+    ok!(
+        message,
+        MessagesPeerDialogs {
+            dialogs: dialogs
+                .into_iter()
+                .map(|dialog| DialogVariant::Dialog(Box::new(dialog)))
+                .collect(),
+            messages,
+            chats: vec![],
+            users,
+            state: updates_state(&state, state.pts, time!()),
         }
-    }
+    )
+}
+
+///
+/// # Layer 158
+/// ## messages.getPinnedDialogs#d6b94df2 folder_id:int = messages.PeerDialogs;
+/// Get pinned dialogs
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | folder_id | int | Peer folder ID, for more info click here |
+///
+/// ## Behavior
+/// <strong>✅ This function behaves the same way as official Telegram servers</strong>
+///
+#[auth]
+pub async fn rpc_messages_get_pinned_dialogs(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<MessagesGetPinnedDialogs>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+    let self_user = session.get_self().await?;
 
-    let count = messages.len() as i32;
+    let dialogs = session
+        .storage
+        .get_pinned_dialogs(self_user.id, message.obj.folder_id)
+        .await?;
+    let (messages, users) = dialogs_contents(&session, &self_user, &dialogs).await?;
+    let state = session.storage.get_user_state(self_user.id).await?;
     ok!(
         message,
-        MessagesMessagesSlice {
+        MessagesPeerDialogs {
+            dialogs: dialogs
+                .into_iter()
+                .map(|dialog| DialogVariant::Dialog(Box::new(dialog)))
+                .collect(),
             messages,
             chats: vec![],
-            users: users.into_values().collect(),
-            inexact: false,
-            count,
-            next_rate: None,
-            offset_id_offset: None,
+            users,
+            state: updates_state(&state, state.pts, time!()),
         }
     )
 }
 
+/// A page of a dialog's messages, newest first
+struct MessagesPage {
+    messages: Vec<MessageVariant>,
+    count: i32,
+    offset_id_offset: Option<i32>,
+    next_rate: Option<i32>,
+}
+
+/// Lists the dialog's messages following the official paging rules,
+/// see https://core.telegram.org/api/offsets
+#[allow(clippy::too_many_arguments)]
+async fn get_messages_page(
+    session: &Session,
+    user_id: i64,
+    peer_id: i64,
+    mut filter: MessageFilter,
+    offset_id: i32,
+    offset_date: i32,
+    add_offset: i32,
+    limit: i32,
+) -> Result<MessagesPage, sqlx::Error> {
+    if filter.max_id <= 0 {
+        filter.max_id = i32::MAX;
+    }
+    let all = MessageFilter {
+        min_id: 0,
+        max_id: i32::MAX,
+        ..filter.clone()
+    };
+    let count = session
+        .storage
+        .count_messages(user_id, peer_id, &all, 0)
+        .await?;
+
+    // Index of the first message older than the offset in the newest first list
+    let position = if offset_id != 0 {
+        let newer = MessageFilter {
+            min_id: filter.min_id.max(offset_id - 1),
+            ..filter.clone()
+        };
+        session
+            .storage
+            .count_messages(user_id, peer_id, &newer, 0)
+            .await?
+    } else if offset_date != 0 {
+        session
+            .storage
+            .count_messages(user_id, peer_id, &filter, offset_date)
+            .await?
+    } else {
+        0
+    };
+    let start = position + add_offset;
+    let end = start + limit.clamp(0, HISTORY_LIMIT);
+    let messages = if end > start.max(0) {
+        session
+            .storage
+            .get_messages(user_id, peer_id, &filter, start.max(0), end - start.max(0))
+            .await?
+    } else {
+        vec![]
+    };
+
+    Ok(MessagesPage {
+        messages,
+        count,
+        offset_id_offset: if offset_id != 0 || offset_date != 0 {
+            Some(position)
+        } else {
+            None
+        },
+        next_rate: None,
+    })
+}
+
+/// messages.messages if the page holds every message, messages.messagesSlice otherwise
+async fn messages_page(
+    session: &Session,
+    self_user: &User,
+    page: MessagesPage,
+) -> Result<SchemaObject, sqlx::Error> {
+    let mut user_ids = BTreeSet::new();
+    for m in page.messages.iter() {
+        add_message_variant_users(&mut user_ids, m);
+    }
+    let users = get_users(session, self_user, user_ids).await?;
+    let messages = page.messages;
+
+    if messages.len() as i32 == page.count {
+        return Ok(SchemaObject::MessagesMessages(MessagesMessages {
+            messages,
+            chats: vec![],
+            users,
+        }));
+    }
+    Ok(SchemaObject::MessagesMessagesSlice(MessagesMessagesSlice {
+        messages,
+        chats: vec![],
+        users,
+        inexact: false,
+        count: page.count,
+        next_rate: page.next_rate,
+        offset_id_offset: page.offset_id_offset,
+    }))
+}
+
+///
+/// # Layer 158
+/// ## messages.getHistory#4423e6c5 peer:InputPeer offset_id:int offset_date:int add_offset:int limit:int max_id:int min_id:int hash:long = messages.Messages;
+/// Returns the conversation history with one interlocutor / within a chat
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | peer | InputPeer | Target peer |
+/// | offset_id | int | Only return messages starting from the specified message ID |
+/// | offset_date | int | Only return messages sent before the specified date |
+/// | add_offset | int | Number of list elements to be skipped, negative values are also accepted. |
+/// | limit | int | Number of results to return |
+/// | max_id | int | If a positive value was transferred, the method will return only messages with IDs less than max_id |
+/// | min_id | int | If a positive value was transferred, the method will return only messages with IDs more than min_id |
+/// | hash | long | Result hash |
+///
+/// ## Behavior
+/// <strong>✅ This function behaves the same way as official Telegram servers</strong>
+///
 #[auth]
+pub async fn rpc_messages_get_history(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<MessagesGetHistory>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+    let self_user = session.get_self().await?;
+
+    let Some(peer_user_id) = resolve_peer(&session, &self_user, &message.obj.peer).await? else {
+        err!(message, 400, "PEER_ID_INVALID")
+    };
+
+    let page = get_messages_page(
+        &session,
+        self_user.id,
+        peer_user_id,
+        MessageFilter {
+            min_id: message.obj.min_id.max(0),
+            max_id: message.obj.max_id,
+            ..Default::default()
+        },
+        message.obj.offset_id,
+        message.obj.offset_date,
+        message.obj.add_offset,
+        message.obj.limit,
+    )
+    .await?;
+
+    let hash = list_hash(page.messages.iter().map(|m| message_id_date(m).0 as i64));
+    if message.obj.hash != 0 && message.obj.hash == hash {
+        ok!(message, MessagesMessagesNotModified { count: page.count })
+    }
+    ok_obj!(message, messages_page(&session, &self_user, page).await?)
+}
+
+///
+/// # Layer 158
+/// ## messages.sendMessage#1cc20387 flags:# no_webpage:flags.1?true silent:flags.5?true background:flags.6?true clear_draft:flags.7?true noforwards:flags.14?true update_stickersets_order:flags.15?true peer:InputPeer reply_to_msg_id:flags.0?int top_msg_id:flags.9?int message:string random_id:long reply_markup:flags.2?ReplyMarkup entities:flags.3?Vector<MessageEntity> schedule_date:flags.10?int send_as:flags.13?InputPeer = Updates;
+/// Sends a message to a chat
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | flags | # | Flags, see TL conditional fields |
+/// | no_webpage | flags.1?true | Set this flag to disable generation of the webpage preview |
+/// | silent | flags.5?true | Send this message silently (no notifications for the receivers) |
+/// | background | flags.6?true | Send this message as background message |
+/// | clear_draft | flags.7?true | Clear the draft field |
+/// | noforwards | flags.14?true | Only for bots, disallows forwarding and saving of the messages, even if the destination chat doesn't have content protection enabled |
+/// | update_stickersets_order | flags.15?true | Whether to move used stickersets to top, see here for more info on this flag » |
+/// | peer | InputPeer | The destination where the message will be sent |
+/// | reply_to_msg_id | flags.0?int | The message ID to which this message will reply to |
+/// | top_msg_id | flags.9?int | This field must contain the topic ID only when replying to messages in forum topics different from the "General" topic |
+/// | message | string | The message |
+/// | random_id | long | Unique client message ID required to prevent message resending |
+/// | reply_markup | flags.2?ReplyMarkup | Reply markup for sending bot buttons |
+/// | entities | flags.3?Vector<MessageEntity> | Message entities for sending styled text |
+/// | schedule_date | flags.10?int | Scheduled message date for scheduled messages |
+/// | send_as | flags.13?InputPeer | Send this message as the specified peer |
+///
+/// ## Behavior
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * Only private chats and saved messages are supported
+/// * Everything except peer, reply_to_msg_id, message, random_id, entities, silent, clear_draft and schedule_date is ignored
+/// * Link previews are never attached to messages, see messages.getWebPagePreview
+/// * Retrying a scheduled message schedules it again, random_id is only remembered for messages sent right away
+/// * Layer 158 has no quotes, replies always refer to the whole message
+/// * Returns updates instead of updateShortSentMessage
+///
+#[auth(bots)]
 pub async fn rpc_messages_send_message(
     session: Arc<Mutex<Session>>,
     message: rpc::Message<MessagesSendMessage>,
@@ -169,71 +1006,2359 @@ pub async fn rpc_messages_send_message(
     let session = session.lock().await;
     let self_user = session.get_self().await?;
 
-    let (mb_key_primary, mb_key_secondary, peer_id, from_id) = match message.obj.peer {
-        InputPeerVariant::InputPeerSelf(_) => {
-            (self_user.id, Some(self_user.id), self_user.id, None)
-        }
-        InputPeerVariant::InputPeerUser(peer) => {
-            let mut k = vec![peer.user_id, self_user.id];
-            k.sort();
-            (k[0], Some(k[1]), self_user.id, None)
-        }
-        _ => todo!(),
+    let Some(peer_user_id) = resolve_peer(&session, &self_user, &message.obj.peer).await? else {
+        err!(message, 400, "PEER_ID_INVALID")
+    };
+    if message.obj.message.is_empty() {
+        err!(message, 400, "MESSAGE_EMPTY");
+    }
+    if message.obj.message.encode_utf16().count() > MESSAGE_LENGTH_LIMIT {
+        err!(message, 400, "MESSAGE_TOO_LONG");
+    }
+    let entities = match message_entities(
+        &session,
+        &self_user,
+        &message.obj.message,
+        message.obj.entities.clone(),
+    )
+    .await?
+    {
+        Ok(entities) => entities,
+        Err(e) => err!(message, 400, e),
     };
 
-    let date = time!();
-    let mut sent_message = session
-        .storage
-        .insert_message(
-            mb_key_primary,
-            mb_key_secondary,
-            peer_id,
-            from_id,
-            message.obj.message,
+    let (reply_to_msg_id, reply_to_top_id) = reply_to(
+        &session.storage,
+        self_user.id,
+        peer_user_id,
+        message.obj.reply_to_msg_id,
+    )
+    .await?;
+    if message.obj.clear_draft {
+        session
+            .storage
+            .set_draft(self_user.id, peer_user_id, None)
+            .await?;
+        publish_draft(&session, self_user.id, peer_user_id, None, time!()).await?;
+    }
+
+    // Dates in the past are sent right away, and so are messages to yourself sent when online
+    let schedule_date = message.obj.schedule_date.filter(|&date| {
+        date > time!() && !(date == SEND_WHEN_ONLINE && peer_user_id == self_user.id)
+    });
+    if let Some(schedule_date) = schedule_date {
+        if schedule_date != SEND_WHEN_ONLINE && schedule_date > time!() + SCHEDULE_PERIOD_LIMIT {
+            err!(message, 400, "SCHEDULE_DATE_TOO_LATE");
+        }
+        if session
+            .storage
+            .count_scheduled_messages(self_user.id, peer_user_id)
+            .await?
+            >= SCHEDULED_MESSAGE_LIMIT
+        {
+            err!(message, 400, "SCHEDULE_TOO_MUCH");
+        }
+        return schedule_message(
+            &session,
+            &self_user,
+            &message,
+            ScheduledMessage {
+                id: 0,
+                peer_id: peer_user_id,
+                message: message.obj.message.clone(),
+                entities,
+                reply_to_msg_id,
+                date: schedule_date,
+                silent: message.obj.silent,
+            },
+            message.obj.random_id,
         )
-        .await?;
+        .await;
+    }
+
+    let used = reserve_random_ids(&session.storage, self_user.id, &[message.obj.random_id]).await?;
+    if !used.is_empty() {
+        return resend(&session, &self_user, &message, used).await;
+    }
+    let (sent_message, pts) = send_message(
+        &session.storage,
+        &session.bus,
+        &self_user,
+        NewMessage {
+            peer_id: peer_user_id,
+            from_id: self_user.id,
+            out: true,
+            message: message.obj.message.clone(),
+            date: time!(),
+            reply_to_msg_id,
+            reply_to_top_id,
+            fwd_from: None,
+            entities,
+            from_scheduled: false,
+            mentioned: false,
+            media_unread: false,
+        },
+        Some(message.obj.random_id),
+        message.obj.silent,
+        Some(session.auth_key_id),
+    )
+    .await?;
 
-    sent_message.out = true;
+    let mut user_ids = BTreeSet::new();
+    add_message_users(&mut user_ids, &sent_message);
+    let users = get_users(&session, &self_user, user_ids).await?;
+    ok_obj!(
+        message,
+        SchemaObject::Updates(sent_updates(
+            vec![(message.obj.random_id, sent_message, pts)],
+            users
+        ))
+    )
+}
 
-    let pts = match session
-        .storage
-        .increment_mb_pts(mb_key_primary, mb_key_secondary, self_user.id, 1)
-        .await
+///
+/// # Layer 158
+/// ## messages.forwardMessages#c661bbc4 flags:# silent:flags.5?true background:flags.6?true with_my_score:flags.8?true drop_author:flags.11?true drop_media_captions:flags.12?true noforwards:flags.14?true from_peer:InputPeer id:Vector<int> random_id:Vector<long> to_peer:InputPeer top_msg_id:flags.9?int schedule_date:flags.10?int send_as:flags.13?InputPeer = Updates;
+/// Forwards messages by their IDs.
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | flags | # | Flags, see TL conditional fields |
+/// | silent | flags.5?true | Whether to send messages silently (no notification will be triggered on the destination clients) |
+/// | background | flags.6?true | Whether to send the message in background |
+/// | with_my_score | flags.8?true | When forwarding games, whether to include your score in the game |
+/// | drop_author | flags.11?true | Whether to forward messages without quoting the original author |
+/// | drop_media_captions | flags.12?true | Whether to strip captions from media |
+/// | noforwards | flags.14?true | Only for bots, disallows further re-forwarding and saving of the messages, even if the destination chat doesn't have content protection enabled |
+/// | from_peer | InputPeer | Source of messages |
+/// | id | Vector<int> | IDs of messages |
+/// | random_id | Vector<long> | Random ID to prevent resending of messages |
+/// | to_peer | InputPeer | Destination peer |
+/// | top_msg_id | flags.9?int | Destination forum topic |
+/// | schedule_date | flags.10?int | Scheduled message date for scheduled messages |
+/// | send_as | flags.13?InputPeer | Forward the messages as the specified peer |
+///
+/// ## Behavior
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * Authors whose forwards privacy doesn't allow the forwarding user are only named in the header
+/// * Retries only send the messages whose random_id wasn't used yet
+/// * top_msg_id, schedule_date and send_as are ignored
+///
+#[auth(bots)]
+pub async fn rpc_messages_forward_messages(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<MessagesForwardMessages>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+    let self_user = session.get_self().await?;
+
+    let Some(from_user_id) = resolve_peer(&session, &self_user, &message.obj.from_peer).await?
+    else {
+        err!(message, 400, "PEER_ID_INVALID")
+    };
+    let Some(to_user_id) = resolve_peer(&session, &self_user, &message.obj.to_peer).await? else {
+        err!(message, 400, "PEER_ID_INVALID")
+    };
+    if message.obj.id.is_empty() {
+        err!(message, 400, "MESSAGE_IDS_EMPTY");
+    }
+    if message.obj.id.len() != message.obj.random_id.len()
+        || message.obj.random_id.iter().collect::<BTreeSet<_>>().len()
+            != message.obj.random_id.len()
     {
-        Ok(r) => r,
-        Err(e) => {
-            session
-                .storage
-                .insert_mb_pts(mb_key_primary, mb_key_secondary, self_user.id)
-                .await?;
-            0
+        err!(message, 400, "RANDOM_ID_INVALID");
+    }
+
+    let mut originals = vec![];
+    for &id in message.obj.id.iter() {
+        let original = match session.storage.get_message(self_user.id, id).await {
+            Ok(original) => original,
+            Err(sqlx::Error::RowNotFound) => err!(message, 400, "MESSAGE_ID_INVALID"),
+            Err(e) => return Err(e.into()),
+        };
+        match &original.peer_id {
+            PeerVariant::PeerUser(peer) if peer.user_id == from_user_id => {}
+            _ => err!(message, 400, "MESSAGE_ID_INVALID"),
+        }
+        originals.push(original);
+    }
+
+    // Retries only send the messages that didn't make it the first time
+    let used = reserve_random_ids(&session.storage, self_user.id, &message.obj.random_id).await?;
+    let used_ids = used
+        .iter()
+        .map(|(random_id, _)| *random_id)
+        .collect::<Vec<_>>();
+    let Some(mut already_sent) = already_sent(&session.storage, self_user.id, used).await? else {
+        // random_ids are unique, so the rest were reserved by this request
+        for random_id in message.obj.random_id.iter() {
+            if !used_ids.contains(random_id) {
+                session
+                    .storage
+                    .release_random_id(self_user.id, *random_id)
+                    .await?;
+            }
         }
+        err!(message, 400, "RANDOM_ID_DUPLICATE")
     };
 
-    ok!(
-        message,
-        Updates {
-            updates: vec![UpdateVariant::UpdateNewMessage(Box::new(
-                UpdateNewMessage {
-                    message: MessageVariant::Message(Box::new(sent_message)),
-                    pts,
-                    pts_count: 1,
-                }
-            ))],
-            users: vec![UserVariant::User(Box::new(self_user))],
-            chats: vec![],
-            date,
-            seq: 0,
+    let date = time!();
+    let mut sent_messages = vec![];
+    let mut user_ids = BTreeSet::new();
+    for (i, (original, &random_id)) in originals
+        .into_iter()
+        .zip(message.obj.random_id.iter())
+        .enumerate()
+    {
+        if let Some(position) = already_sent
+            .iter()
+            .position(|(used, _, _)| *used == random_id)
+        {
+            let sent = already_sent.remove(position);
+            add_message_users(&mut user_ids, &sent.1);
+            sent_messages.push(sent);
+            continue;
+        }
+        let sent = async {
+            let fwd_from = if message.obj.drop_author {
+                None
+            } else {
+                // Forwarded messages keep pointing to the original author
+                let mut fwd_from = match original.fwd_from {
+                    Some(fwd_from) => fwd_from,
+                    None => {
+                        let author_id = if original.out {
+                            self_user.id
+                        } else {
+                            from_user_id
+                        };
+                        fwd_header(&session.storage, &self_user, author_id, original.date).await?
+                    }
+                };
+                // Hidden authors can't be found through saved messages either
+                (fwd_from.saved_from_peer, fwd_from.saved_from_msg_id) =
+                    if to_user_id == self_user.id && fwd_from.from_id.is_some() {
+                        (
+                            Some(PeerVariant::PeerUser(Box::new(PeerUser {
+                                user_id: from_user_id,
+                            }))),
+                            Some(original.id),
+                        )
+                    } else {
+                        (None, None)
+                    };
+                Some(fwd_from)
+            };
+            send_message(
+                &session.storage,
+                &session.bus,
+                &self_user,
+                NewMessage {
+                    peer_id: to_user_id,
+                    from_id: self_user.id,
+                    out: true,
+                    message: original.message,
+                    date,
+                    reply_to_msg_id: None,
+                    reply_to_top_id: None,
+                    fwd_from,
+                    entities: original.entities,
+                    from_scheduled: false,
+                    mentioned: false,
+                    media_unread: false,
+                },
+                Some(random_id),
+                message.obj.silent,
+                Some(session.auth_key_id),
+            )
+            .await
         }
+        .await;
+        let (sent_message, pts) = match sent {
+            Ok(sent) => sent,
+            Err(e) => {
+                // Messages from the failed one on were never sent, sent ones keep their random_id
+                for &random_id in &message.obj.random_id[i..] {
+                    session
+                        .storage
+                        .release_random_id(self_user.id, random_id)
+                        .await?;
+                }
+                return Err(e.into());
+            }
+        };
+        add_message_users(&mut user_ids, &sent_message);
+        sent_messages.push((random_id, sent_message, pts));
+    }
+
+    let users = get_users(&session, &self_user, user_ids).await?;
+    ok_obj!(
+        message,
+        SchemaObject::Updates(sent_updates(sent_messages, users))
     )
 }
 
-pub async fn rpc_messages_get_search_counters(
-    _session: Arc<Mutex<Session>>,
-    message: rpc::Message<MessagesGetSearchCounters>,
+///
+/// # Layer 158
+/// ## messages.getReplies#22ddd30c peer:InputPeer msg_id:int offset_id:int offset_date:int add_offset:int limit:int max_id:int min_id:int hash:long = messages.Messages;
+/// Get messages in a reply thread
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | peer | InputPeer | Peer |
+/// | msg_id | int | Message ID |
+/// | offset_id | int | Offsets for pagination, for more info click here |
+/// | offset_date | int | Offsets for pagination, for more info click here |
+/// | add_offset | int | Offsets for pagination, for more info click here |
+/// | limit | int | Maximum number of results to return, see pagination |
+/// | max_id | int | If a positive value was transferred, the method will return only messages with ID smaller than max_id |
+/// | min_id | int | If a positive value was transferred, the method will return only messages with ID bigger than min_id |
+/// | hash | long | Hash for pagination, for more info click here |
+///
+/// ## Behavior
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * Threads also work in private chats, every reply chain is a thread started by its first message
+///
+#[auth]
+pub async fn rpc_messages_get_replies(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<MessagesGetReplies>,
 ) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
-    ok_vec!(message, vec![])
-}
+    let session = session.lock().await;
+    let self_user = session.get_self().await?;
+
+    let Some(peer_user_id) = resolve_peer(&session, &self_user, &message.obj.peer).await? else {
+        err!(message, 400, "PEER_ID_INVALID")
+    };
+    match session
+        .storage
+        .get_message_link(self_user.id, message.obj.msg_id)
+        .await?
+    {
+        Some(link) if link.peer_id == peer_user_id => {}
+        _ => err!(message, 400, "MSG_ID_INVALID"),
+    }
+
+    let page = get_messages_page(
+        &session,
+        self_user.id,
+        peer_user_id,
+        MessageFilter {
+            min_id: message.obj.min_id.max(0),
+            max_id: message.obj.max_id,
+            thread_id: message.obj.msg_id,
+            ..Default::default()
+        },
+        message.obj.offset_id,
+        message.obj.offset_date,
+        message.obj.add_offset,
+        message.obj.limit,
+    )
+    .await?;
+
+    let hash = list_hash(page.messages.iter().map(|m| message_id_date(m).0 as i64));
+    if message.obj.hash != 0 && message.obj.hash == hash {
+        ok!(message, MessagesMessagesNotModified { count: page.count })
+    }
+    ok_obj!(message, messages_page(&session, &self_user, page).await?)
+}
+
+///
+/// # Layer 158
+/// ## messages.getDiscussionMessage#446972fd peer:InputPeer msg_id:int = messages.DiscussionMessage;
+/// Get discussion message from the associated discussion group of a channel to show it on top of the comment section, without actually joining the group
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | peer | InputPeer | Channel ID |
+/// | msg_id | int | Message ID |
+///
+/// ## Behavior
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * Works for reply threads in private chats, the first message of the thread is returned
+/// * Read state is shared with the dialog
+///
+#[auth]
+pub async fn rpc_messages_get_discussion_message(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<MessagesGetDiscussionMessage>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+    let self_user = session.get_self().await?;
+
+    let Some(peer_user_id) = resolve_peer(&session, &self_user, &message.obj.peer).await? else {
+        err!(message, 400, "PEER_ID_INVALID")
+    };
+    let root = match session
+        .storage
+        .get_message(self_user.id, message.obj.msg_id)
+        .await
+    {
+        Ok(root) => root,
+        Err(sqlx::Error::RowNotFound) => err!(message, 400, "MSG_ID_INVALID"),
+        Err(e) => return Err(e.into()),
+    };
+    match &root.peer_id {
+        PeerVariant::PeerUser(peer) if peer.user_id == peer_user_id => {}
+        _ => err!(message, 400, "MSG_ID_INVALID"),
+    }
+    let Some(dialog) = session
+        .storage
+        .get_dialog(self_user.id, peer_user_id)
+        .await?
+    else {
+        err!(message, 400, "MSG_ID_INVALID")
+    };
+
+    let thread = MessageFilter {
+        min_id: 0,
+        max_id: i32::MAX,
+        thread_id: root.id,
+        ..Default::default()
+    };
+    let max_id = session
+        .storage
+        .get_messages(self_user.id, peer_user_id, &thread, 0, 1)
+        .await?
+        .first()
+        .map(|m| message_id_date(m).0);
+    let unread_count = session
+        .storage
+        .count_messages(
+            self_user.id,
+            peer_user_id,
+            &MessageFilter {
+                min_id: dialog.read_inbox_max_id,
+                ..thread.clone()
+            },
+            0,
+        )
+        .await?;
+
+    let mut user_ids = BTreeSet::new();
+    add_message_users(&mut user_ids, &root);
+    let users = get_users(&session, &self_user, user_ids).await?;
+    ok!(
+        message,
+        MessagesDiscussionMessage {
+            messages: vec![MessageVariant::Message(Box::new(root))],
+            max_id,
+            read_inbox_max_id: Some(dialog.read_inbox_max_id),
+            read_outbox_max_id: Some(dialog.read_outbox_max_id),
+            unread_count,
+            chats: vec![],
+            users,
+        }
+    )
+}
+
+///
+/// # Layer 158
+/// ## messages.editMessage#48f71778 flags:# no_webpage:flags.1?true peer:InputPeer id:int message:flags.11?string media:flags.14?InputMedia reply_markup:flags.2?ReplyMarkup entities:flags.3?Vector<MessageEntity> schedule_date:flags.15?int = Updates;
+/// Edit message
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | flags | # | Flags, see TL conditional fields |
+/// | no_webpage | flags.1?true | Disable webpage preview |
+/// | peer | InputPeer | Where was the message sent |
+/// | id | int | ID of the message to edit |
+/// | message | flags.11?string | New message |
+/// | media | flags.14?InputMedia | New attached media |
+/// | reply_markup | flags.2?ReplyMarkup | Reply markup for inline keyboards |
+/// | entities | flags.3?Vector<MessageEntity> | Message entities for styled text |
+/// | schedule_date | flags.15?int | Scheduled message date for scheduled messages |
+///
+/// ## Behavior
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * Only the message text and entities can be edited, everything else is ignored
+/// * Entities are parsed from the new text if only the text is edited, and validated against the old text if only the entities are
+///
+#[auth(bots)]
+pub async fn rpc_messages_edit_message(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<MessagesEditMessage>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+    let self_user = session.get_self().await?;
+
+    let Some(peer_user_id) = resolve_peer(&session, &self_user, &message.obj.peer).await? else {
+        err!(message, 400, "PEER_ID_INVALID")
+    };
+    let link = match session
+        .storage
+        .get_message_link(self_user.id, message.obj.id)
+        .await?
+    {
+        Some(link) if link.peer_id == peer_user_id => link,
+        _ => err!(message, 400, "MESSAGE_ID_INVALID"),
+    };
+    // Service messages can't be edited
+    let old_message = match session
+        .storage
+        .get_message(self_user.id, message.obj.id)
+        .await
+    {
+        Ok(old_message) => old_message,
+        Err(sqlx::Error::RowNotFound) => err!(message, 400, "MESSAGE_ID_INVALID"),
+        Err(e) => return Err(e.into()),
+    };
+    if !old_message.out {
+        err!(message, 403, "MESSAGE_AUTHOR_REQUIRED");
+    }
+    // Formatting can be changed without resending the text
+    let text = match &message.obj.message {
+        Some(text) => text,
+        None if message.obj.entities.is_some() => &old_message.message,
+        None => err!(message, 400, "MESSAGE_NOT_MODIFIED"),
+    };
+    if text.is_empty() {
+        err!(message, 400, "MESSAGE_EMPTY");
+    }
+    if text.encode_utf16().count() > MESSAGE_LENGTH_LIMIT {
+        err!(message, 400, "MESSAGE_TOO_LONG");
+    }
+    let entities =
+        match message_entities(&session, &self_user, text, message.obj.entities.clone()).await? {
+            Ok(entities) => entities,
+            Err(e) => err!(message, 400, e),
+        };
+    if *text == old_message.message
+        && entities_data(entities.as_deref()) == entities_data(old_message.entities.as_deref())
+    {
+        err!(message, 400, "MESSAGE_NOT_MODIFIED");
+    }
+    let edit_date = time!();
+    // Saved messages can be edited forever
+    if peer_user_id != self_user.id && old_message.date + EDIT_TIME_LIMIT < edit_date {
+        err!(message, 400, "MESSAGE_EDIT_TIME_EXPIRED");
+    }
+
+    session
+        .storage
+        .edit_message(
+            self_user.id,
+            message.obj.id,
+            text,
+            entities.as_deref(),
+            edit_date,
+        )
+        .await?;
+    let edited_message = session
+        .storage
+        .get_message(self_user.id, message.obj.id)
+        .await?;
+    let pts = session
+        .storage
+        .log_update(self_user.id, 1, |pts| {
+            update_edit_message(edited_message.clone(), pts)
+        })
+        .await?;
+    session
+        .bus
+        .publish(
+            self_user.id,
+            SchemaObject::UpdateShort(UpdateShort {
+                update: update_edit_message(edited_message.clone(), pts),
+                date: edit_date,
+            }),
+            Some(session.auth_key_id),
+        )
+        .await?;
+
+    if let Some(peer_message_id) = link.peer_message_id {
+        let result = session
+            .storage
+            .edit_message(
+                peer_user_id,
+                peer_message_id,
+                text,
+                entities.as_deref(),
+                edit_date,
+            )
+            .await?;
+        // The recipient may have already deleted its copy
+        if result.rows_affected() > 0 {
+            let peer_message = session
+                .storage
+                .get_message(peer_user_id, peer_message_id)
+                .await?;
+            let peer_pts = session
+                .storage
+                .log_update(peer_user_id, 1, |pts| {
+                    update_edit_message(peer_message.clone(), pts)
+                })
+                .await?;
+            session
+                .bus
+                .publish(
+                    peer_user_id,
+                    SchemaObject::UpdateShort(UpdateShort {
+                        update: update_edit_message(peer_message, peer_pts),
+                        date: edit_date,
+                    }),
+                    None,
+                )
+                .await?;
+        }
+    }
+
+    let mut user_ids = BTreeSet::new();
+    add_message_users(&mut user_ids, &edited_message);
+    let users = get_users(&session, &self_user, user_ids).await?;
+    ok!(
+        message,
+        Updates {
+            updates: vec![update_edit_message(edited_message, pts)],
+            users,
+            chats: vec![],
+            date: edit_date,
+            seq: 0,
+        }
+    )
+}
+
+fn update_edit_message(message: Message, pts: i32) -> UpdateVariant {
+    v!(UpdateVariant::UpdateEditMessage {
+        message: MessageVariant::Message(Box::new(message)),
+        pts,
+        pts_count: 1,
+    })
+}
+
+/// Logs an update for the user and pushes it to its sessions, returns the new pts
+async fn publish_update(
+    session: &Session,
+    user_id: i64,
+    pts_count: i32,
+    update: impl Fn(i32) -> UpdateVariant,
+    except: Option<i64>,
+) -> Result<i32, sqlx::Error> {
+    let pts = session
+        .storage
+        .log_update(user_id, pts_count, &update)
+        .await?;
+    session
+        .bus
+        .publish(
+            user_id,
+            SchemaObject::UpdateShort(UpdateShort {
+                update: update(pts),
+                date: time!(),
+            }),
+            except,
+        )
+        .await?;
+    Ok(pts)
+}
+
+/// Logs updateDeleteMessages for the user and pushes it to its sessions,
+/// returns the new pts and pts_count
+async fn publish_deleted(
+    session: &Session,
+    user_id: i64,
+    ids: Vec<i32>,
+    except: Option<i64>,
+) -> Result<(i32, i32), sqlx::Error> {
+    if ids.is_empty() {
+        let state = session.storage.get_user_state(user_id).await?;
+        return Ok((state.pts, 0));
+    }
+    let pts_count = ids.len() as i32;
+    let update = |pts| {
+        v!(UpdateVariant::UpdateDeleteMessages {
+            messages: ids.clone(),
+            pts,
+            pts_count,
+        })
+    };
+    let pts = publish_update(session, user_id, pts_count, update, except).await?;
+    Ok((pts, pts_count))
+}
+
+/// Deletes messages from the user's message box and, with revoke, their copies
+/// in the peers' message boxes. Returns the user's new pts and pts_count
+async fn delete_messages(
+    session: &Session,
+    user_id: i64,
+    ids: &[i32],
+    revoke: bool,
+) -> Result<(i32, i32), sqlx::Error> {
+    let mut deleted = vec![];
+    let mut peer_deleted: BTreeMap<i64, Vec<i32>> = BTreeMap::new();
+    let mut peers = BTreeSet::new();
+    for &id in ids {
+        let Some(link) = session.storage.get_message_link(user_id, id).await? else {
+            continue;
+        };
+        session.storage.delete_message(user_id, id).await?;
+        deleted.push(id);
+        peers.insert(link.peer_id);
+
+        if let (true, Some(peer_message_id)) = (revoke, link.peer_message_id) {
+            let result = session
+                .storage
+                .delete_message(link.peer_id, peer_message_id)
+                .await?;
+            if result.rows_affected() > 0 {
+                peer_deleted
+                    .entry(link.peer_id)
+                    .or_default()
+                    .push(peer_message_id);
+            }
+        }
+    }
+
+    for peer_id in peers {
+        session.storage.refresh_dialog(user_id, peer_id).await?;
+    }
+    let affected = publish_deleted(session, user_id, deleted, Some(session.auth_key_id)).await?;
+    for (peer_id, ids) in peer_deleted {
+        session.storage.refresh_dialog(peer_id, user_id).await?;
+        publish_deleted(session, peer_id, ids, None).await?;
+    }
+    Ok(affected)
+}
+
+///
+/// # Layer 158
+/// ## messages.deleteMessages#e58e95d2 flags:# revoke:flags.0?true id:Vector<int> = messages.AffectedMessages;
+/// Deletes messages by their identifiers.
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | flags | # | Flags, see TL conditional fields |
+/// | revoke | flags.0?true | Whether to delete messages for all participants of the chat |
+/// | id | Vector<int> | Message ID list |
+///
+/// ## Behavior
+/// <strong>✅ This function behaves the same way as official Telegram servers</strong>
+///
+#[auth(bots)]
+pub async fn rpc_messages_delete_messages(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<MessagesDeleteMessages>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+    let self_user = session.get_self().await?;
+
+    let (pts, pts_count) =
+        delete_messages(&session, self_user.id, &message.obj.id, message.obj.revoke).await?;
+    ok!(message, MessagesAffectedMessages { pts, pts_count })
+}
+
+///
+/// # Layer 158
+/// ## messages.deleteHistory#b08f922a flags:# just_clear:flags.0?true revoke:flags.1?true peer:InputPeer max_id:int min_date:flags.2?int max_date:flags.3?int = messages.AffectedHistory;
+/// Deletes communication history.
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | flags | # | Flags, see TL conditional fields |
+/// | just_clear | flags.0?true | Just clear history for the current user, without actually removing messages for every chat user |
+/// | revoke | flags.1?true | Whether to delete the message history for all chat participants |
+/// | peer | InputPeer | User or chat, communication history of which will be deleted |
+/// | max_id | int | Maximum ID of message to delete |
+/// | min_date | flags.2?int | Delete all messages newer than this UNIX timestamp |
+/// | max_date | flags.3?int | Delete all messages older than this UNIX timestamp |
+///
+/// ## Behavior
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * The whole history is deleted at once, offset is always 0
+///
+#[auth]
+pub async fn rpc_messages_delete_history(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<MessagesDeleteHistory>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+    let self_user = session.get_self().await?;
+
+    let Some(peer_user_id) = resolve_peer(&session, &self_user, &message.obj.peer).await? else {
+        err!(message, 400, "PEER_ID_INVALID")
+    };
+    let ids = session
+        .storage
+        .get_message_ids(
+            self_user.id,
+            peer_user_id,
+            message.obj.max_id,
+            message.obj.min_date.unwrap_or(0),
+            message.obj.max_date.unwrap_or(i32::MAX),
+        )
+        .await?;
+    let (pts, pts_count) =
+        delete_messages(&session, self_user.id, &ids, message.obj.revoke).await?;
+
+    if !message.obj.just_clear {
+        session
+            .storage
+            .delete_dialog(self_user.id, peer_user_id)
+            .await?;
+        if message.obj.revoke && peer_user_id != self_user.id {
+            session
+                .storage
+                .delete_dialog(peer_user_id, self_user.id)
+                .await?;
+        }
+    }
+
+    ok!(
+        message,
+        MessagesAffectedHistory {
+            pts,
+            pts_count,
+            offset: 0,
+        }
+    )
+}
+
+///
+/// # Layer 158
+/// ## messages.readHistory#e306d3a peer:InputPeer max_id:int = messages.AffectedMessages;
+/// Marks message history as read.
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | peer | InputPeer | Target user or group |
+/// | max_id | int | If a positive value is passed, only messages with identifiers less or equal than the given one will be read |
+///
+/// ## Behavior
+/// <strong>✅ This function behaves the same way as official Telegram servers</strong>
+///
+#[auth]
+pub async fn rpc_messages_read_history(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<MessagesReadHistory>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+    let self_user = session.get_self().await?;
+
+    let Some(peer_user_id) = resolve_peer(&session, &self_user, &message.obj.peer).await? else {
+        err!(message, 400, "PEER_ID_INVALID")
+    };
+    let max_id = match message.obj.max_id {
+        max_id if max_id > 0 => max_id,
+        _ => i32::MAX,
+    };
+    let Some((max_id, still_unread_count)) = session
+        .storage
+        .read_inbox(self_user.id, peer_user_id, max_id)
+        .await?
+    else {
+        let state = session.storage.get_user_state(self_user.id).await?;
+        ok!(
+            message,
+            MessagesAffectedMessages {
+                pts: state.pts,
+                pts_count: 0,
+            }
+        )
+    };
+    let pts = publish_update(
+        &session,
+        self_user.id,
+        1,
+        |pts| {
+            v!(UpdateVariant::UpdateReadHistoryInbox {
+                folder_id: None,
+                peer: PeerVariant::PeerUser(Box::new(PeerUser {
+                    user_id: peer_user_id,
+                })),
+                max_id,
+                still_unread_count,
+                pts,
+                pts_count: 1,
+            })
+        },
+        Some(session.auth_key_id),
+    )
+    .await?;
+
+    // The sender sees its messages as read, in its own message ids
+    if peer_user_id != self_user.id {
+        if let Some(peer_max_id) = session
+            .storage
+            .get_peer_max_message_id(self_user.id, peer_user_id, max_id)
+            .await?
+        {
+            if session
+                .storage
+                .read_outbox(peer_user_id, self_user.id, peer_max_id)
+                .await?
+            {
+                publish_update(
+                    &session,
+                    peer_user_id,
+                    1,
+                    |pts| {
+                        v!(UpdateVariant::UpdateReadHistoryOutbox {
+                            peer: PeerVariant::PeerUser(Box::new(PeerUser {
+                                user_id: self_user.id,
+                            })),
+                            max_id: peer_max_id,
+                            pts,
+                            pts_count: 1,
+                        })
+                    },
+                    None,
+                )
+                .await?;
+            }
+        }
+    }
+
+    ok!(message, MessagesAffectedMessages { pts, pts_count: 1 })
+}
+
+/// Logs updateReadMessagesContents for the user and pushes it to its sessions
+async fn publish_read_contents(
+    session: &Session,
+    user_id: i64,
+    ids: Vec<i32>,
+    except: Option<i64>,
+) -> Result<i32, sqlx::Error> {
+    let update = |pts| {
+        v!(UpdateVariant::UpdateReadMessagesContents {
+            messages: ids.clone(),
+            pts,
+            pts_count: 1,
+        })
+    };
+    publish_update(session, user_id, 1, update, except).await
+}
+
+///
+/// # Layer 158
+/// ## messages.readMessageContents#36a73f77 id:Vector<int> = messages.AffectedMessages;
+/// Notifies the sender about the recipient having listened a voice message or watched a video.
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | id | Vector<int> | Message ID list |
+///
+/// ## Behavior
+/// <strong>✅ This function behaves the same way as official Telegram servers</strong>
+///
+#[auth]
+pub async fn rpc_messages_read_message_contents(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<MessagesReadMessageContents>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+    let self_user = session.get_self().await?;
+
+    let mut read = vec![];
+    let mut peer_read: BTreeMap<i64, Vec<i32>> = BTreeMap::new();
+    let mut peers = BTreeSet::new();
+    for &id in message.obj.id.iter() {
+        let Some(link) = session
+            .storage
+            .read_message_contents(self_user.id, id)
+            .await?
+        else {
+            continue;
+        };
+        read.push(id);
+        peers.insert(link.peer_id);
+        if let Some(peer_message_id) = link.peer_message_id {
+            if session
+                .storage
+                .read_message_contents(link.peer_id, peer_message_id)
+                .await?
+                .is_some()
+            {
+                peer_read
+                    .entry(link.peer_id)
+                    .or_default()
+                    .push(peer_message_id);
+            }
+        }
+    }
+    if read.is_empty() {
+        let state = session.storage.get_user_state(self_user.id).await?;
+        ok!(
+            message,
+            MessagesAffectedMessages {
+                pts: state.pts,
+                pts_count: 0,
+            }
+        )
+    }
+
+    for peer_id in peers {
+        session
+            .storage
+            .refresh_dialog(self_user.id, peer_id)
+            .await?;
+    }
+    let pts =
+        publish_read_contents(&session, self_user.id, read, Some(session.auth_key_id)).await?;
+    for (peer_id, ids) in peer_read {
+        session
+            .storage
+            .refresh_dialog(peer_id, self_user.id)
+            .await?;
+        publish_read_contents(&session, peer_id, ids, None).await?;
+    }
+
+    ok!(message, MessagesAffectedMessages { pts, pts_count: 1 })
+}
+
+///
+/// # Layer 158
+/// ## messages.getUnreadMentions#f107e790 flags:# peer:InputPeer top_msg_id:flags.0?int offset_id:int add_offset:int limit:int max_id:int min_id:int = messages.Messages;
+/// Get unread messages where we were mentioned
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | flags | # | Flags, see TL conditional fields |
+/// | peer | InputPeer | Peer where to look for mentions |
+/// | top_msg_id | flags.0?int | If set, considers only messages within the specified forum topic |
+/// | offset_id | int | Offsets for pagination, for more info click here |
+/// | add_offset | int | Offsets for pagination, for more info click here |
+/// | limit | int | Maximum number of results to return, see pagination |
+/// | max_id | int | Maximum message ID to return, see pagination |
+/// | min_id | int | Minimum message ID to return, see pagination |
+///
+/// ## Behavior
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * top_msg_id is ignored
+///
+#[auth]
+pub async fn rpc_messages_get_unread_mentions(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<MessagesGetUnreadMentions>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+    let self_user = session.get_self().await?;
+
+    let Some(peer_user_id) = resolve_peer(&session, &self_user, &message.obj.peer).await? else {
+        err!(message, 400, "PEER_ID_INVALID")
+    };
+    let page = get_messages_page(
+        &session,
+        self_user.id,
+        peer_user_id,
+        MessageFilter {
+            min_id: message.obj.min_id.max(0),
+            max_id: message.obj.max_id,
+            unread_mentions: true,
+            ..Default::default()
+        },
+        message.obj.offset_id,
+        0,
+        message.obj.add_offset,
+        message.obj.limit,
+    )
+    .await?;
+    ok_obj!(message, messages_page(&session, &self_user, page).await?)
+}
+
+///
+/// # Layer 158
+/// ## messages.updatePinnedMessage#d2aaf7ec flags:# silent:flags.0?true unpin:flags.1?true pm_oneside:flags.2?true peer:InputPeer id:int = Updates;
+/// Pin a message
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | flags | # | Flags, see TL conditional fields |
+/// | silent | flags.0?true | Pin the message silently, without triggering a notification |
+/// | unpin | flags.1?true | Whether the message should unpinned or pinned |
+/// | pm_oneside | flags.2?true | Whether the message should only be pinned on the local side of a one-to-one chat |
+/// | peer | InputPeer | The peer where to pin the message |
+/// | id | int | The message to pin or unpin |
+///
+/// ## Behavior
+/// <strong>✅ This function behaves the same way as official Telegram servers</strong>
+///
+#[auth(bots)]
+pub async fn rpc_messages_update_pinned_message(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<MessagesUpdatePinnedMessage>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+    let self_user = session.get_self().await?;
+
+    let Some(peer_user_id) = resolve_peer(&session, &self_user, &message.obj.peer).await? else {
+        err!(message, 400, "PEER_ID_INVALID")
+    };
+    let link = match session
+        .storage
+        .get_message_link(self_user.id, message.obj.id)
+        .await?
+    {
+        Some(link) if link.peer_id == peer_user_id => link,
+        _ => err!(message, 400, "MESSAGE_ID_INVALID"),
+    };
+    // Service messages can't be pinned
+    match session
+        .storage
+        .get_message(self_user.id, message.obj.id)
+        .await
+    {
+        Ok(_) => {}
+        Err(sqlx::Error::RowNotFound) => err!(message, 400, "MESSAGE_ID_INVALID"),
+        Err(e) => return Err(e.into()),
+    }
+
+    let pinned = !message.obj.unpin;
+    let date = time!();
+    let mut updates = vec![];
+    let mut user_ids = BTreeSet::from([peer_user_id]);
+    if session
+        .storage
+        .set_message_pinned(self_user.id, message.obj.id, pinned)
+        .await?
+    {
+        let update = |pts| update_pinned_messages(pinned, peer_user_id, vec![message.obj.id], pts);
+        let pts =
+            publish_update(&session, self_user.id, 1, update, Some(session.auth_key_id)).await?;
+        updates.push(update(pts));
+    }
+
+    // pm_oneside only changes the own side of a private chat
+    if peer_user_id != self_user.id && !message.obj.pm_oneside {
+        if let Some(peer_message_id) = link.peer_message_id {
+            if session
+                .storage
+                .set_message_pinned(peer_user_id, peer_message_id, pinned)
+                .await?
+            {
+                let update =
+                    |pts| update_pinned_messages(pinned, self_user.id, vec![peer_message_id], pts);
+                publish_update(&session, peer_user_id, 1, update, None).await?;
+            }
+        }
+        if pinned && !updates.is_empty() {
+            let action =
+                MessageActionVariant::MessageActionPinMessage(Box::new(MessageActionPinMessage {}));
+            let (service_message, pts) = send_service_message(
+                &session,
+                &self_user,
+                peer_user_id,
+                (message.obj.id, link.peer_message_id),
+                action,
+                message.obj.silent,
+            )
+            .await?;
+            add_message_variant_users(&mut user_ids, &service_message);
+            updates.push(v!(UpdateVariant::UpdateNewMessage {
+                message: service_message,
+                pts,
+                pts_count: 1,
+            }));
+        }
+    }
+
+    let users = get_users(&session, &self_user, user_ids).await?;
+    ok!(
+        message,
+        Updates {
+            updates,
+            users,
+            chats: vec![],
+            date,
+            seq: 0,
+        }
+    )
+}
+
+///
+/// # Layer 158
+/// ## messages.unpinAllMessages#ee22b9a8 flags:# peer:InputPeer top_msg_id:flags.0?int = messages.AffectedHistory;
+/// Unpin all pinned messages
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | flags | # | Flags, see TL conditional fields |
+/// | peer | InputPeer | Chat where to unpin |
+/// | top_msg_id | flags.0?int | Forum topic where to unpin |
+///
+/// ## Behavior
+/// <strong>✅ This function behaves the same way as official Telegram servers</strong>
+///
+#[auth]
+pub async fn rpc_messages_unpin_all_messages(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<MessagesUnpinAllMessages>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+    let self_user = session.get_self().await?;
+
+    let Some(peer_user_id) = resolve_peer(&session, &self_user, &message.obj.peer).await? else {
+        err!(message, 400, "PEER_ID_INVALID")
+    };
+    let ids = session
+        .storage
+        .unpin_messages(
+            self_user.id,
+            peer_user_id,
+            message.obj.top_msg_id.unwrap_or(0),
+        )
+        .await?;
+    if ids.is_empty() {
+        let state = session.storage.get_user_state(self_user.id).await?;
+        ok!(
+            message,
+            MessagesAffectedHistory {
+                pts: state.pts,
+                pts_count: 0,
+                offset: 0,
+            }
+        )
+    }
+
+    // The peer's copies get unpinned as well
+    let mut peer_ids = vec![];
+    if peer_user_id != self_user.id {
+        for &id in ids.iter() {
+            let Some(peer_message_id) =
+                peer_message_id(&session.storage, self_user.id, Some(id)).await?
+            else {
+                continue;
+            };
+            if session
+                .storage
+                .set_message_pinned(peer_user_id, peer_message_id, false)
+                .await?
+            {
+                peer_ids.push(peer_message_id);
+            }
+        }
+    }
+    if !peer_ids.is_empty() {
+        let update = |pts| update_pinned_messages(false, self_user.id, peer_ids.clone(), pts);
+        publish_update(&session, peer_user_id, peer_ids.len() as i32, update, None).await?;
+    }
+
+    let pts_count = ids.len() as i32;
+    let update = |pts| update_pinned_messages(false, peer_user_id, ids.clone(), pts);
+    let pts = publish_update(
+        &session,
+        self_user.id,
+        pts_count,
+        update,
+        Some(session.auth_key_id),
+    )
+    .await?;
+    ok!(
+        message,
+        MessagesAffectedHistory {
+            pts,
+            pts_count,
+            offset: 0,
+        }
+    )
+}
+
+fn update_pinned_messages(
+    pinned: bool,
+    peer_id: i64,
+    messages: Vec<i32>,
+    pts: i32,
+) -> UpdateVariant {
+    let pts_count = messages.len() as i32;
+    v!(UpdateVariant::UpdatePinnedMessages {
+        pinned,
+        peer: PeerVariant::PeerUser(Box::new(PeerUser { user_id: peer_id })),
+        messages,
+        pts,
+        pts_count,
+    })
+}
+
+/// Stores a service message in the sender's and the recipient's message boxes and pushes it
+/// to both users, `reply_to` is the id of the message it refers to in both boxes.
+/// Returns the sender's copy and pts
+async fn send_service_message(
+    session: &Session,
+    self_user: &User,
+    peer_user_id: i64,
+    reply_to: (i32, Option<i32>),
+    action: MessageActionVariant,
+    silent: bool,
+) -> Result<(MessageVariant, i32), sqlx::Error> {
+    let new_message = NewMessage {
+        peer_id: peer_user_id,
+        from_id: self_user.id,
+        out: true,
+        message: String::new(),
+        date: time!(),
+        reply_to_msg_id: Some(reply_to.0),
+        reply_to_top_id: None,
+        fwd_from: None,
+        entities: None,
+        from_scheduled: false,
+        mentioned: false,
+        media_unread: false,
+    };
+    let mut sent_message = session
+        .storage
+        .insert_service_message(self_user.id, &new_message, &action)
+        .await?;
+    if let MessageVariant::MessageService(m) = &mut sent_message {
+        m.silent = silent;
+    }
+    session
+        .storage
+        .refresh_dialog(self_user.id, peer_user_id)
+        .await?;
+    let update = |pts| {
+        v!(UpdateVariant::UpdateNewMessage {
+            message: sent_message.clone(),
+            pts,
+            pts_count: 1,
+        })
+    };
+    let pts = publish_update(session, self_user.id, 1, update, Some(session.auth_key_id)).await?;
+
+    let received = NewMessage {
+        peer_id: self_user.id,
+        out: false,
+        reply_to_msg_id: reply_to.1,
+        ..new_message
+    };
+    let mut received_message = session
+        .storage
+        .insert_service_message(peer_user_id, &received, &action)
+        .await?;
+    if let MessageVariant::MessageService(m) = &mut received_message {
+        m.silent = silent;
+    }
+    session
+        .storage
+        .link_messages(
+            self_user.id,
+            message_id_date(&sent_message).0,
+            peer_user_id,
+            message_id_date(&received_message).0,
+        )
+        .await?;
+    session
+        .storage
+        .refresh_dialog(peer_user_id, self_user.id)
+        .await?;
+    let update = |pts| {
+        v!(UpdateVariant::UpdateNewMessage {
+            message: received_message.clone(),
+            pts,
+            pts_count: 1,
+        })
+    };
+    publish_update(session, peer_user_id, 1, update, None).await?;
+
+    Ok((sent_message, pts))
+}
+
+/// Storage filter for an inputMessagesFilter*, None if no message can match it.
+/// Messages can't have media yet, so media filters never match
+fn search_filter(filter: &MessagesFilterVariant) -> Option<MessageFilter> {
+    let all = MessageFilter {
+        max_id: i32::MAX,
+        ..Default::default()
+    };
+    match filter {
+        MessagesFilterVariant::InputMessagesFilterEmpty(_) => Some(all),
+        MessagesFilterVariant::InputMessagesFilterMyMentions(_) => Some(MessageFilter {
+            mentions: true,
+            ..all
+        }),
+        MessagesFilterVariant::InputMessagesFilterUrl(_) => {
+            Some(MessageFilter { urls: true, ..all })
+        }
+        MessagesFilterVariant::InputMessagesFilterPinned(_) => Some(MessageFilter {
+            pinned: true,
+            ..all
+        }),
+        _ => None,
+    }
+}
+
+///
+/// # Layer 158
+/// ## messages.search#a0fda762 flags:# peer:InputPeer q:string from_id:flags.0?InputPeer top_msg_id:flags.1?int filter:MessagesFilter min_date:int max_date:int offset_id:int add_offset:int limit:int max_id:int min_id:int hash:long = messages.Messages;
+/// Search for messages.
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | flags | # | Flags, see TL conditional fields |
+/// | peer | InputPeer | User or chat, histories with which are searched, or (inputPeerEmpty) constructor to search in all private chats and normal groups (not channels) » |
+/// | q | string | Text search request |
+/// | from_id | flags.0?InputPeer | Only return messages sent by the specified user ID |
+/// | top_msg_id | flags.1?int | Thread ID |
+/// | filter | MessagesFilter | Filter to return only specified message types |
+/// | min_date | int | If a positive value was transferred, only messages with a sending date bigger than the transferred one will be returned |
+/// | max_date | int | If a positive value was transferred, only messages with a sending date smaller than the transferred one will be returned |
+/// | offset_id | int | Only return messages starting from the specified message ID |
+/// | add_offset | int | Additional offset |
+/// | limit | int | Number of results to return |
+/// | max_id | int | Maximum message ID to return |
+/// | min_id | int | Minimum message ID to return |
+/// | hash | long | Hash |
+///
+/// ## Behavior
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * Messages match if they contain every word of q, the last word may be incomplete
+/// * Media filters never match since messages can't have media yet
+/// * hash is ignored
+///
+#[auth]
+pub async fn rpc_messages_search(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<MessagesSearch>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+    let self_user = session.get_self().await?;
+
+    // inputPeerEmpty searches every dialog
+    let peer_id = match &message.obj.peer {
+        InputPeerVariant::InputPeerEmpty(_) => 0,
+        peer => match resolve_peer(&session, &self_user, peer).await? {
+            Some(peer_id) => peer_id,
+            None => err!(message, 400, "PEER_ID_INVALID"),
+        },
+    };
+    let from_id = match &message.obj.from_id {
+        Some(from_id) => match resolve_peer(&session, &self_user, from_id).await? {
+            Some(from_id) => from_id,
+            None => err!(message, 400, "PEER_ID_INVALID"),
+        },
+        None => 0,
+    };
+    let Some(filter) = search_filter(&message.obj.filter) else {
+        ok!(
+            message,
+            MessagesMessages {
+                messages: vec![],
+                chats: vec![],
+                users: vec![],
+            }
+        )
+    };
+
+    let page = get_messages_page(
+        &session,
+        self_user.id,
+        peer_id,
+        MessageFilter {
+            min_id: message.obj.min_id.max(0),
+            max_id: message.obj.max_id,
+            thread_id: message.obj.top_msg_id.unwrap_or(0),
+            query: message.obj.q.clone(),
+            from_id,
+            min_date: message.obj.min_date.max(0),
+            max_date: message.obj.max_date.max(0),
+            ..filter
+        },
+        message.obj.offset_id,
+        0,
+        message.obj.add_offset,
+        message.obj.limit,
+    )
+    .await?;
+    ok_obj!(message, messages_page(&session, &self_user, page).await?)
+}
+
+///
+/// # Layer 158
+/// ## messages.searchGlobal#4bc6589a flags:# folder_id:flags.0?int q:string filter:MessagesFilter min_date:int max_date:int offset_rate:int offset_peer:InputPeer offset_id:int limit:int = messages.Messages;
+/// Search for messages and peers globally
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | flags | # | Flags, see TL conditional fields |
+/// | folder_id | flags.0?int | Peer folder ID, for more info click here |
+/// | q | string | Query |
+/// | filter | MessagesFilter | Global search filter |
+/// | min_date | int | If a positive value was specified, the method will return only messages with date bigger than min_date |
+/// | max_date | int | If a positive value was transferred, the method will return only messages with date smaller than max_date |
+/// | offset_rate | int | Initially 0, then set to the next_rate parameter of messages.messagesSlice |
+/// | offset_peer | InputPeer | Offsets for pagination, for more info click here |
+/// | offset_id | int | Offsets for pagination, for more info click here |
+/// | limit | int | Offsets for pagination, for more info click here |
+///
+/// ## Behavior
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * Message ids are shared by all dialogs of a user, so offset_id alone is enough to page, offset_peer is only validated
+/// * offset_rate is only used when offset_id is 0, as an exclusive bound on the message date
+/// * folder_id is ignored
+/// * Media filters never match since messages can't have media yet
+///
+#[auth]
+pub async fn rpc_messages_search_global(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<MessagesSearchGlobal>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+    let self_user = session.get_self().await?;
+
+    if message.obj.q.trim().is_empty()
+        && matches!(
+            message.obj.filter,
+            MessagesFilterVariant::InputMessagesFilterEmpty(_)
+        )
+    {
+        err!(message, 400, "SEARCH_QUERY_EMPTY");
+    }
+    if !matches!(message.obj.offset_peer, InputPeerVariant::InputPeerEmpty(_))
+        && resolve_peer(&session, &self_user, &message.obj.offset_peer)
+            .await?
+            .is_none()
+    {
+        err!(message, 400, "PEER_ID_INVALID");
+    }
+    let Some(filter) = search_filter(&message.obj.filter) else {
+        ok!(
+            message,
+            MessagesMessages {
+                messages: vec![],
+                chats: vec![],
+                users: vec![],
+            }
+        )
+    };
+
+    let mut max_date = message.obj.max_date.max(0);
+    if message.obj.offset_id == 0 && message.obj.offset_rate > 0 {
+        let before_rate = message.obj.offset_rate - 1;
+        max_date = if max_date == 0 {
+            before_rate
+        } else {
+            max_date.min(before_rate)
+        };
+    }
+    let mut page = get_messages_page(
+        &session,
+        self_user.id,
+        0,
+        MessageFilter {
+            query: message.obj.q.clone(),
+            min_date: message.obj.min_date.max(0),
+            max_date,
+            ..filter
+        },
+        message.obj.offset_id,
+        0,
+        0,
+        message.obj.limit,
+    )
+    .await?;
+    // Global search pages by rate instead of offset_id_offset
+    let position = page.offset_id_offset.take().unwrap_or(0);
+    if position + (page.messages.len() as i32) < page.count {
+        page.next_rate = page.messages.last().map(|m| message_id_date(m).1);
+    }
+    ok_obj!(message, messages_page(&session, &self_user, page).await?)
+}
+
+///
+/// # Layer 158
+/// ## messages.getSearchCounters#ae7cc1 flags:# peer:InputPeer top_msg_id:flags.0?int filters:Vector<MessagesFilter> = Vector<messages.SearchCounter>;
+/// Get the number of results that would be found by a messages.search call with the same parameters
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | flags | # | Flags, see TL conditional fields |
+/// | peer | InputPeer | Peer where to search |
+/// | top_msg_id | flags.0?int | If set, consider only messages within the specified forum topic |
+/// | filters | Vector<MessagesFilter> | Search filters |
+///
+/// ## Behavior
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * Media filters always count 0 messages since messages can't have media yet
+///
+#[auth]
+pub async fn rpc_messages_get_search_counters(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<MessagesGetSearchCounters>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+    let self_user = session.get_self().await?;
+
+    let Some(peer_user_id) = resolve_peer(&session, &self_user, &message.obj.peer).await? else {
+        err!(message, 400, "PEER_ID_INVALID")
+    };
+    let mut counters = vec![];
+    for filter in message.obj.filters.iter() {
+        let count = match search_filter(filter) {
+            Some(search) => {
+                session
+                    .storage
+                    .count_messages(
+                        self_user.id,
+                        peer_user_id,
+                        &MessageFilter {
+                            thread_id: message.obj.top_msg_id.unwrap_or(0),
+                            ..search
+                        },
+                        0,
+                    )
+                    .await?
+            }
+            None => 0,
+        };
+        counters.push(SchemaObject::MessagesSearchCounter(MessagesSearchCounter {
+            inexact: false,
+            filter: filter.clone(),
+            count,
+        }));
+    }
+    ok_vec!(message, counters)
+}
+
+///
+/// # Layer 158
+/// ## messages.getWebPagePreview#8b68b0cc flags:# message:string entities:flags.3?Vector<MessageEntity> = MessageMedia;
+/// Get preview of webpage
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | flags | # | Flags, see TL conditional fields |
+/// | message | string | Message from which to extract the preview |
+/// | entities | flags.3?Vector<MessageEntity> | Message entities for styled text |
+///
+/// ## Behavior
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * Previews are built by the configured preview_fetcher, which builds none by default
+/// * Only the first link of the message is previewed
+/// * Links to local or private network addresses are never previewed
+/// * Previews only have a site name, title and description, there are no photos or embeds
+///
+#[auth]
+pub async fn rpc_messages_get_web_page_preview(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<MessagesGetWebPagePreview>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let text = &message.obj.message;
+    let entities = match &message.obj.entities {
+        Some(entities) => {
+            if let Err(e) = entities::validate(text, entities) {
+                err!(message, 400, e);
+            }
+            entities.clone()
+        }
+        None => entities::parse(text),
+    };
+    let Some(url) = entities::urls(text, &entities).into_iter().next() else {
+        ok!(message, MessageMediaEmpty {})
+    };
+
+    // Don't keep the session locked while the page is downloading
+    let runtime_config = session.lock().await.runtime_config.clone();
+    let preview = match runtime_config.preview_fetcher.fetch(&url).await {
+        Ok(Some(preview)) => preview,
+        Ok(None) => ok!(message, MessageMediaEmpty {}),
+        Err(e) => {
+            println_yellow!("PREVIEW FETCHER", "{}: {}", url, e);
+            ok!(message, MessageMediaEmpty {})
+        }
+    };
+
+    let mut hasher = DefaultHasher::new();
+    preview.url.hash(&mut hasher);
+    let display_url = preview
+        .url
+        .split_once("://")
+        .map_or(preview.url.as_str(), |(_, rest)| rest)
+        .trim_end_matches('/')
+        .to_string();
+    ok!(
+        message,
+        MessageMediaWebPage {
+            webpage: WebPageVariant::WebPage(Box::new(WebPage {
+                id: hasher.finish() as i64,
+                url: preview.url,
+                display_url,
+                hash: 0,
+                r#type: Some("article".to_string()),
+                site_name: preview.site_name,
+                title: preview.title,
+                description: preview.description,
+                photo: None,
+                embed_url: None,
+                embed_type: None,
+                embed_width: None,
+                embed_height: None,
+                duration: None,
+                author: None,
+                document: None,
+                cached_page: None,
+                attributes: None,
+            })),
+        }
+    )
+}
+
+///
+/// # Layer 158
+/// ## messages.saveDraft#b4331e3f flags:# no_webpage:flags.1?true reply_to_msg_id:flags.0?int top_msg_id:flags.2?int peer:InputPeer message:string entities:flags.3?Vector<MessageEntity> = Bool;
+/// Save a message draft associated to a chat.
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | flags | # | Flags, see TL conditional fields |
+/// | no_webpage | flags.1?true | Disable generation of the webpage preview |
+/// | reply_to_msg_id | flags.0?int | Message ID the message should reply to |
+/// | top_msg_id | flags.2?int | Forum topic where the message will be sent |
+/// | peer | InputPeer | Destination of the message that should be sent |
+/// | message | string | The draft |
+/// | entities | flags.3?Vector<MessageEntity> | Message entities for styled text |
+///
+/// ## Behavior
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * There is one draft per dialog, top_msg_id is ignored
+///
+#[auth]
+pub async fn rpc_messages_save_draft(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<MessagesSaveDraft>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+    let self_user = session.get_self().await?;
+
+    let Some(peer_user_id) = resolve_peer(&session, &self_user, &message.obj.peer).await? else {
+        err!(message, 400, "PEER_ID_INVALID")
+    };
+    if message.obj.message.encode_utf16().count() > MESSAGE_LENGTH_LIMIT {
+        err!(message, 400, "MESSAGE_TOO_LONG");
+    }
+    // Drafts keep exactly what the client sent, nothing is detected in the text
+    let entities = match message.obj.entities.clone() {
+        Some(entities) => {
+            match message_entities(&session, &self_user, &message.obj.message, Some(entities))
+                .await?
+            {
+                Ok(entities) => entities,
+                Err(e) => err!(message, 400, e),
+            }
+        }
+        None => None,
+    };
+
+    let date = time!();
+    let draft = if message.obj.message.is_empty() && message.obj.reply_to_msg_id.is_none() {
+        None
+    } else {
+        Some(DraftMessage {
+            no_webpage: message.obj.no_webpage,
+            reply_to_msg_id: message.obj.reply_to_msg_id,
+            message: message.obj.message.clone(),
+            entities,
+            date,
+        })
+    };
+    session
+        .storage
+        .set_draft(self_user.id, peer_user_id, draft.as_ref())
+        .await?;
+    publish_draft(&session, self_user.id, peer_user_id, draft, date).await?;
+    ok!(message, BoolTrue {})
+}
+
+///
+/// # Layer 158
+/// ## messages.getAllDrafts#6a3f8d65 = Updates;
+/// Return all message drafts.
+/// Returns all the latest updateDraftMessage updates related to all chats with drafts.
+///
+/// ## Behavior
+/// <strong>✅ This function behaves the same way as official Telegram servers</strong>
+///
+#[auth]
+pub async fn rpc_messages_get_all_drafts(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<MessagesGetAllDrafts>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+    let self_user = session.get_self().await?;
+
+    let mut updates = vec![];
+    let mut user_ids = BTreeSet::new();
+    for dialog in session.storage.get_draft_dialogs(self_user.id).await? {
+        let Some(draft) = dialog.draft else {
+            continue;
+        };
+        if let PeerVariant::PeerUser(peer) = &dialog.peer {
+            user_ids.insert(peer.user_id);
+        }
+        updates.push(v!(UpdateVariant::UpdateDraftMessage {
+            peer: dialog.peer,
+            top_msg_id: None,
+            draft,
+        }));
+    }
+    let users = get_users(&session, &self_user, user_ids).await?;
+    ok!(
+        message,
+        Updates {
+            updates,
+            users,
+            chats: vec![],
+            date: time!(),
+            seq: 0,
+        }
+    )
+}
+
+///
+/// # Layer 158
+/// ## messages.getScheduledHistory#f516760b peer:InputPeer hash:long = messages.Messages;
+/// Get scheduled messages
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | peer | InputPeer | Peer |
+/// | hash | long | Hash for pagination, for more info click here |
+///
+/// ## Behavior
+/// <strong>✅ This function behaves the same way as official Telegram servers</strong>
+///
+#[auth]
+pub async fn rpc_messages_get_scheduled_history(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<MessagesGetScheduledHistory>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+    let self_user = session.get_self().await?;
+
+    let Some(peer_user_id) = resolve_peer(&session, &self_user, &message.obj.peer).await? else {
+        err!(message, 400, "PEER_ID_INVALID")
+    };
+    let scheduled = session
+        .storage
+        .get_scheduled_messages(self_user.id, peer_user_id)
+        .await?;
+    let hash = list_hash(scheduled.iter().map(|m| m.id as i64));
+    if message.obj.hash != 0 && message.obj.hash == hash {
+        ok!(
+            message,
+            MessagesMessagesNotModified {
+                count: scheduled.len() as i32
+            }
+        )
+    }
+    ok_obj!(
+        message,
+        scheduled_messages(&session, &self_user, scheduled).await?
+    )
+}
+
+///
+/// # Layer 158
+/// ## messages.getScheduledMessages#bdbb0464 peer:InputPeer id:Vector<int> = messages.Messages;
+/// Get scheduled messages
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | peer | InputPeer | Peer |
+/// | id | Vector<int> | IDs of scheduled messages |
+///
+/// ## Behavior
+/// <strong>✅ This function behaves the same way as official Telegram servers</strong>
+///
+#[auth]
+pub async fn rpc_messages_get_scheduled_messages(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<MessagesGetScheduledMessages>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+    let self_user = session.get_self().await?;
+
+    let Some(peer_user_id) = resolve_peer(&session, &self_user, &message.obj.peer).await? else {
+        err!(message, 400, "PEER_ID_INVALID")
+    };
+    let mut scheduled = vec![];
+    for &id in message.obj.id.iter() {
+        match session
+            .storage
+            .get_scheduled_message(self_user.id, id)
+            .await?
+        {
+            Some(m) if m.peer_id == peer_user_id => scheduled.push(m),
+            _ => {}
+        }
+    }
+    ok_obj!(
+        message,
+        scheduled_messages(&session, &self_user, scheduled).await?
+    )
+}
+
+///
+/// # Layer 158
+/// ## messages.sendScheduledMessages#bd38850a peer:InputPeer id:Vector<int> = Updates;
+/// Send scheduled messages right away
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | peer | InputPeer | Peer |
+/// | id | Vector<int> | Scheduled message IDs |
+///
+/// ## Behavior
+/// <strong>✅ This function behaves the same way as official Telegram servers</strong>
+///
+#[auth]
+pub async fn rpc_messages_send_scheduled_messages(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<MessagesSendScheduledMessages>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+    let self_user = session.get_self().await?;
+
+    let Some(peer_user_id) = resolve_peer(&session, &self_user, &message.obj.peer).await? else {
+        err!(message, 400, "PEER_ID_INVALID")
+    };
+    for &id in message.obj.id.iter() {
+        match session
+            .storage
+            .get_scheduled_message(self_user.id, id)
+            .await?
+        {
+            Some(m) if m.peer_id == peer_user_id => {}
+            _ => err!(message, 400, "MESSAGE_ID_INVALID"),
+        }
+    }
+
+    let mut sent_ids = vec![];
+    let mut updates = vec![];
+    let mut user_ids = BTreeSet::new();
+    for &id in message.obj.id.iter() {
+        // The scheduler may have sent it in the meantime
+        let Some((sent_message, pts)) = send_scheduled_message(
+            &session.storage,
+            &session.bus,
+            self_user.id,
+            id,
+            Some(session.auth_key_id),
+        )
+        .await?
+        else {
+            continue;
+        };
+        sent_ids.push(id);
+        add_message_users(&mut user_ids, &sent_message);
+        updates.push(update_new_message(sent_message, pts));
+    }
+    updates.insert(0, update_delete_scheduled_messages(peer_user_id, sent_ids));
+
+    let users = get_users(&session, &self_user, user_ids).await?;
+    ok!(
+        message,
+        Updates {
+            updates,
+            users,
+            chats: vec![],
+            date: time!(),
+            seq: 0,
+        }
+    )
+}
+
+///
+/// # Layer 158
+/// ## messages.deleteScheduledMessages#59ae2b16 peer:InputPeer id:Vector<int> = Updates;
+/// Delete scheduled messages
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | peer | InputPeer | Peer |
+/// | id | Vector<int> | Scheduled message IDs |
+///
+/// ## Behavior
+/// <strong>✅ This function behaves the same way as official Telegram servers</strong>
+///
+#[auth]
+pub async fn rpc_messages_delete_scheduled_messages(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<MessagesDeleteScheduledMessages>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+    let self_user = session.get_self().await?;
+
+    let Some(peer_user_id) = resolve_peer(&session, &self_user, &message.obj.peer).await? else {
+        err!(message, 400, "PEER_ID_INVALID")
+    };
+    let mut deleted = vec![];
+    for &id in message.obj.id.iter() {
+        match session
+            .storage
+            .get_scheduled_message(self_user.id, id)
+            .await?
+        {
+            Some(m) if m.peer_id == peer_user_id => {}
+            _ => continue,
+        }
+        if session
+            .storage
+            .take_scheduled_message(self_user.id, id)
+            .await?
+            .is_some()
+        {
+            deleted.push(id);
+        }
+    }
+
+    let date = time!();
+    if !deleted.is_empty() {
+        session
+            .bus
+            .publish(
+                self_user.id,
+                SchemaObject::UpdateShort(UpdateShort {
+                    update: update_delete_scheduled_messages(peer_user_id, deleted.clone()),
+                    date,
+                }),
+                Some(session.auth_key_id),
+            )
+            .await?;
+    }
+    ok!(
+        message,
+        Updates {
+            updates: vec![update_delete_scheduled_messages(peer_user_id, deleted)],
+            users: vec![],
+            chats: vec![],
+            date,
+            seq: 0,
+        }
+    )
+}
+
+/// Stores a message in the sender's scheduled message box and pushes it to their other sessions
+async fn schedule_message<T>(
+    session: &Session,
+    self_user: &User,
+    message: &rpc::Message<T>,
+    scheduled: ScheduledMessage,
+    random_id: i64,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let scheduled = session
+        .storage
+        .insert_scheduled_message(self_user.id, &scheduled)
+        .await?;
+    let peer_user_id = scheduled.peer_id;
+    let scheduled_id = scheduled.id;
+    let update = v!(UpdateVariant::UpdateNewScheduledMessage {
+        message: MessageVariant::Message(Box::new(scheduled_message(self_user.id, scheduled))),
+    });
+    let date = time!();
+    session
+        .bus
+        .publish(
+            self_user.id,
+            SchemaObject::UpdateShort(UpdateShort {
+                update: update.clone(),
+                date,
+            }),
+            Some(session.auth_key_id),
+        )
+        .await?;
+
+    let users = get_users(
+        session,
+        self_user,
+        BTreeSet::from([self_user.id, peer_user_id]),
+    )
+    .await?;
+    ok!(
+        message,
+        Updates {
+            updates: vec![
+                v!(UpdateVariant::UpdateMessageId {
+                    id: scheduled_id,
+                    random_id,
+                }),
+                update,
+            ],
+            users,
+            chats: vec![],
+            date,
+            seq: 0,
+        }
+    )
+}
+
+/// Sends a message from the user's scheduled message box, returns None if it was already
+/// sent, deleted or is being sent. `except` is the session that asked for it, if any.
+/// The message only leaves the box once it was sent, and is put back if sending failed
+pub async fn send_scheduled_message(
+    storage: &Storage,
+    bus: &Bus,
+    user_id: i64,
+    id: i32,
+    except: Option<i64>,
+) -> Result<Option<(Message, i32)>, sqlx::Error> {
+    let Some(scheduled) = storage.claim_scheduled_message(user_id, id).await? else {
+        return Ok(None);
+    };
+    let peer_id = scheduled.peer_id;
+    let sent = async {
+        let self_user = storage.get_user(user_id).await?;
+        // The replied message may have been deleted while this one was waiting
+        let (reply_to_msg_id, reply_to_top_id) =
+            reply_to(storage, user_id, peer_id, scheduled.reply_to_msg_id).await?;
+        send_message(
+            storage,
+            bus,
+            &self_user,
+            NewMessage {
+                peer_id,
+                from_id: user_id,
+                out: true,
+                message: scheduled.message,
+                date: time!(),
+                reply_to_msg_id,
+                reply_to_top_id,
+                fwd_from: None,
+                entities: scheduled.entities,
+                from_scheduled: true,
+                mentioned: false,
+                media_unread: false,
+            },
+            None,
+            scheduled.silent,
+            except,
+        )
+        .await
+    }
+    .await;
+    let sent = match sent {
+        Ok(sent) => sent,
+        Err(e) => {
+            storage.release_scheduled_message(user_id, id).await?;
+            return Err(e);
+        }
+    };
+    storage.finish_scheduled_message(user_id, id).await?;
+
+    bus.publish(
+        user_id,
+        SchemaObject::UpdateShort(UpdateShort {
+            update: update_delete_scheduled_messages(peer_id, vec![id]),
+            date: time!(),
+        }),
+        except,
+    )
+    .await?;
+    Ok(Some(sent))
+}
+
+/// Sends the messages that were scheduled until the user comes online in the background,
+/// so that failing to send them never fails the request that brought the user online
+pub fn spawn_send_when_online(data: String, bus: Arc<Bus>, user_id: i64) {
+    tokio::spawn(async move {
+        let storage = Storage::new(data).await;
+        send_when_online(&storage, &bus, user_id).await;
+    });
+}
+
+/// Sends the messages that were scheduled until the user comes online,
+/// the ones that fail are logged and stay scheduled for the next time
+pub async fn send_when_online(storage: &Storage, bus: &Bus, user_id: i64) {
+    let scheduled = match storage.get_when_online_scheduled_messages(user_id).await {
+        Ok(scheduled) => scheduled,
+        Err(e) => {
+            println_yellow!(
+                "SCHEDULER",
+                "failed to get messages scheduled until {} is online: {}",
+                user_id,
+                e
+            );
+            return;
+        }
+    };
+    for (sender_id, id) in scheduled {
+        if let Err(e) = send_scheduled_message(storage, bus, sender_id, id, None).await {
+            println_yellow!(
+                "SCHEDULER",
+                "failed to send scheduled message {}: {}",
+                id,
+                e
+            );
+        }
+    }
+}
+
+/// How a scheduled message is shown in its sender's scheduled message box
+fn scheduled_message(user_id: i64, scheduled: ScheduledMessage) -> Message {
+    Message {
+        out: true,
+        silent: scheduled.silent,
+        id: scheduled.id,
+        from_id: Some(PeerVariant::PeerUser(Box::new(PeerUser { user_id }))),
+        peer_id: PeerVariant::PeerUser(Box::new(PeerUser {
+            user_id: scheduled.peer_id,
+        })),
+        reply_to: scheduled
+            .reply_to_msg_id
+            .map(|reply_to_msg_id| MessageReplyHeader {
+                reply_to_scheduled: false,
+                forum_topic: false,
+                reply_to_msg_id,
+                reply_to_peer_id: None,
+                reply_to_top_id: None,
+            }),
+        date: scheduled.date,
+        message: scheduled.message,
+        entities: scheduled.entities,
+        ..Default::default()
+    }
+}
+
+async fn scheduled_messages(
+    session: &Session,
+    self_user: &User,
+    scheduled: Vec<ScheduledMessage>,
+) -> Result<SchemaObject, sqlx::Error> {
+    let mut user_ids = BTreeSet::from([self_user.id]);
+    let messages = scheduled
+        .into_iter()
+        .map(|m| {
+            user_ids.insert(m.peer_id);
+            MessageVariant::Message(Box::new(scheduled_message(self_user.id, m)))
+        })
+        .collect();
+    let users = get_users(session, self_user, user_ids).await?;
+    Ok(SchemaObject::MessagesMessages(MessagesMessages {
+        messages,
+        chats: vec![],
+        users,
+    }))
+}
+
+fn update_delete_scheduled_messages(peer_id: i64, messages: Vec<i32>) -> UpdateVariant {
+    v!(UpdateVariant::UpdateDeleteScheduledMessages {
+        peer: PeerVariant::PeerUser(Box::new(PeerUser { user_id: peer_id })),
+        messages,
+    })
+}
+
+///
+/// # Layer 158
+/// ## messages.setTyping#58943ee2 flags:# peer:InputPeer top_msg_id:flags.0?int action:SendMessageAction = Bool;
+/// Sends a current user typing event (see SendMessageAction for all event types) to a conversation partner or group
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | flags | # | Flags, see TL conditional fields |
+/// | peer | InputPeer | Target user or group |
+/// | top_msg_id | flags.0?int | Thread ID |
+/// | action | SendMessageAction | Type of action |
+///
+/// ## Behavior
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * Only private chats are supported, so updateChatUserTyping is never sent
+/// * top_msg_id is ignored
+///
+#[auth(bots)]
+pub async fn rpc_messages_set_typing(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<MessagesSetTyping>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+    let self_user = session.get_self().await?;
+
+    let Some(peer_user_id) = resolve_peer(&session, &self_user, &message.obj.peer).await? else {
+        err!(message, 400, "PEER_ID_INVALID")
+    };
+    // Nobody is watching you type in saved messages
+    if peer_user_id != self_user.id {
+        // Emoji interactions are one-off, everything else lasts until it's cancelled or expires
+        let expires = !matches!(
+            message.obj.action,
+            SendMessageActionVariant::SendMessageCancelAction(_)
+                | SendMessageActionVariant::SendMessageEmojiInteraction(_)
+                | SendMessageActionVariant::SendMessageEmojiInteractionSeen(_)
+        );
+        session
+            .bus
+            .publish_chat_action(
+                self_user.id,
+                peer_user_id,
+                message.obj.action.clone(),
+                expires,
+            )
+            .await?;
+    }
+    ok!(message, BoolTrue {})
+}
+
+///
+/// # Layer 158
+/// ## messages.clearAllDrafts#7e58ee9c = Bool;
+/// Clear all drafts.
+///
+/// ## Behavior
+/// <strong>✅ This function behaves the same way as official Telegram servers</strong>
+///
+#[auth]
+pub async fn rpc_messages_clear_all_drafts(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<MessagesClearAllDrafts>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+    let self_user = session.get_self().await?;
+
+    let date = time!();
+    for peer_id in session.storage.clear_drafts(self_user.id).await? {
+        publish_draft(&session, self_user.id, peer_id, None, date).await?;
+    }
+    ok!(message, BoolTrue {})
+}
+
+/// Pushes updateDraftMessage to the user's other sessions, None for a cleared draft
+async fn publish_draft(
+    session: &Session,
+    user_id: i64,
+    peer_id: i64,
+    draft: Option<DraftMessage>,
+    date: i32,
+) -> Result<(), sqlx::Error> {
+    let draft = match draft {
+        Some(draft) => DraftMessageVariant::DraftMessage(Box::new(draft)),
+        None => {
+            DraftMessageVariant::DraftMessageEmpty(Box::new(DraftMessageEmpty { date: Some(date) }))
+        }
+    };
+    session
+        .bus
+        .publish(
+            user_id,
+            SchemaObject::UpdateShort(UpdateShort {
+                update: v!(UpdateVariant::UpdateDraftMessage {
+                    peer: PeerVariant::PeerUser(Box::new(PeerUser { user_id: peer_id })),
+                    top_msg_id: None,
+                    draft,
+                }),
+                date,
+            }),
+            Some(session.auth_key_id),
+        )
+        .await?;
+    Ok(())
+}
 
 pub async fn rpc_messages_get_messages_reactions(
     _session: Arc<Mutex<Session>>,
@@ -250,3 +3375,254 @@ pub async fn rpc_messages_get_messages_reactions(
         }
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::tests::session;
+    use crate::storage::tests::data_dir;
+
+    fn input_peer(user_id: i64) -> InputPeerVariant {
+        v!(InputPeerVariant::InputPeerUser {
+            user_id,
+            access_hash: 0,
+        })
+    }
+
+    fn my_mentions() -> MessagesFilterVariant {
+        v!(MessagesFilterVariant::InputMessagesFilterMyMentions {})
+    }
+
+    fn request<T>(obj: T) -> rpc::Message<T> {
+        rpc::Message {
+            msg_id: 0,
+            seq_no: 0,
+            obj,
+        }
+    }
+
+    fn result(object: SchemaObject) -> SchemaObject {
+        match object {
+            SchemaObject::RpcResult(result) => *result.result,
+            _ => panic!("expected an rpc_result"),
+        }
+    }
+
+    fn message_ids(object: SchemaObject) -> Vec<i32> {
+        let messages = match object {
+            SchemaObject::MessagesMessages(page) => page.messages,
+            SchemaObject::MessagesMessagesSlice(page) => page.messages,
+            _ => panic!("expected messages"),
+        };
+        messages.iter().map(|m| message_id_date(m).0).collect()
+    }
+
+    async fn unread_mentions(session: Arc<Mutex<Session>>, peer_id: i64) -> Vec<i32> {
+        let page = rpc_messages_get_unread_mentions(
+            session,
+            request(MessagesGetUnreadMentions {
+                peer: input_peer(peer_id),
+                top_msg_id: None,
+                offset_id: 0,
+                add_offset: 0,
+                limit: 10,
+                max_id: 0,
+                min_id: 0,
+            }),
+        )
+        .await
+        .unwrap();
+        message_ids(result(page))
+    }
+
+    async fn setup() -> (Arc<Mutex<Session>>, Arc<Mutex<Session>>, User, User) {
+        let data = data_dir();
+        let storage = Storage::new(data.clone()).await;
+        let alice = storage
+            .insert_user("Alice", "", "15550000001")
+            .await
+            .unwrap();
+        let bob = storage.insert_user("Bob", "", "15550000002").await.unwrap();
+        storage.update_username(bob.id, "bob_smith").await.unwrap();
+        (
+            session(&data, alice.id).await,
+            session(&data, bob.id).await,
+            alice,
+            bob,
+        )
+    }
+
+    async fn send(session: Arc<Mutex<Session>>, peer_id: i64, text: &str, random_id: i64) {
+        rpc_messages_send_message(
+            session,
+            request(MessagesSendMessage {
+                no_webpage: false,
+                silent: false,
+                background: false,
+                clear_draft: false,
+                noforwards: false,
+                update_stickersets_order: false,
+                peer: input_peer(peer_id),
+                reply_to_msg_id: None,
+                top_msg_id: None,
+                message: text.to_string(),
+                random_id,
+                reply_markup: None,
+                entities: None,
+                schedule_date: None,
+                send_as: None,
+            }),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn when_online_messages_that_fail_stay_scheduled() {
+        let (alice_session, _, alice, bob) = setup().await;
+        // Nobody can send as a user that doesn't exist, so this one keeps failing
+        let ghost_id = bob.id + 1000;
+        for user_id in [ghost_id, alice.id] {
+            alice_session
+                .lock()
+                .await
+                .storage
+                .insert_scheduled_message(
+                    user_id,
+                    &ScheduledMessage {
+                        id: 0,
+                        peer_id: bob.id,
+                        message: "see you".to_string(),
+                        entities: None,
+                        reply_to_msg_id: None,
+                        date: SEND_WHEN_ONLINE,
+                        silent: false,
+                    },
+                )
+                .await
+                .unwrap();
+        }
+
+        let session = alice_session.lock().await;
+        send_when_online(&session.storage, &session.bus, bob.id).await;
+
+        let storage = &session.storage;
+        assert!(storage
+            .get_scheduled_messages(alice.id, bob.id)
+            .await
+            .unwrap()
+            .is_empty());
+        let dialog = storage.get_dialog(bob.id, alice.id).await.unwrap().unwrap();
+        assert_eq!(dialog.unread_count, 1);
+        assert_eq!(
+            storage
+                .get_scheduled_messages(ghost_id, bob.id)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn mentions_stay_unread_until_their_contents_are_read() {
+        let (alice_session, bob_session, alice, bob) = setup().await;
+        send(alice_session.clone(), bob.id, "hello", 1).await;
+        send(alice_session.clone(), bob.id, "hi @bob_smith", 2).await;
+
+        let unread = unread_mentions(bob_session.clone(), alice.id).await;
+        assert_eq!(unread.len(), 1);
+        assert!(unread_mentions(alice_session, bob.id).await.is_empty());
+        let dialog = bob_session
+            .lock()
+            .await
+            .storage
+            .get_dialog(bob.id, alice.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(dialog.unread_mentions_count, 1);
+
+        let read = |id| {
+            rpc_messages_read_message_contents(
+                bob_session.clone(),
+                request(MessagesReadMessageContents { id: vec![id] }),
+            )
+        };
+        let SchemaObject::MessagesAffectedMessages(affected) =
+            result(read(unread[0]).await.unwrap())
+        else {
+            panic!("expected messages.affectedMessages")
+        };
+        assert_eq!(affected.pts_count, 1);
+        assert!(unread_mentions(bob_session.clone(), alice.id)
+            .await
+            .is_empty());
+        let dialog = bob_session
+            .lock()
+            .await
+            .storage
+            .get_dialog(bob.id, alice.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(dialog.unread_mentions_count, 0);
+        let SchemaObject::MessagesAffectedMessages(affected) =
+            result(read(unread[0]).await.unwrap())
+        else {
+            panic!("expected messages.affectedMessages")
+        };
+        assert_eq!(affected.pts_count, 0);
+    }
+
+    #[tokio::test]
+    async fn my_mentions_are_searched_and_counted() {
+        let (alice_session, bob_session, alice, bob) = setup().await;
+        send(alice_session.clone(), bob.id, "hello", 1).await;
+        send(alice_session.clone(), bob.id, "hi @bob_smith", 2).await;
+        send(alice_session.clone(), bob.id, "hi @someone_else", 3).await;
+
+        let counters = rpc_messages_get_search_counters(
+            bob_session.clone(),
+            request(MessagesGetSearchCounters {
+                peer: input_peer(alice.id),
+                top_msg_id: None,
+                filters: vec![my_mentions()],
+            }),
+        )
+        .await
+        .unwrap();
+        let SchemaObject::Vector(counters) = result(counters) else {
+            panic!("expected a vector")
+        };
+        let [SchemaObject::MessagesSearchCounter(counter)] = counters.as_slice() else {
+            panic!("expected one counter")
+        };
+        assert_eq!(counter.count, 1);
+
+        let search = |session| {
+            rpc_messages_search(
+                session,
+                request(MessagesSearch {
+                    peer: v!(InputPeerVariant::InputPeerEmpty {}),
+                    q: String::new(),
+                    from_id: None,
+                    top_msg_id: None,
+                    filter: my_mentions(),
+                    min_date: 0,
+                    max_date: 0,
+                    offset_id: 0,
+                    add_offset: 0,
+                    limit: 10,
+                    max_id: 0,
+                    min_id: 0,
+                    hash: 0,
+                }),
+            )
+        };
+        let found = message_ids(result(search(bob_session).await.unwrap()));
+        assert_eq!(found.len(), 1);
+        // The sender's copy doesn't mention the sender
+        assert!(message_ids(result(search(alice_session).await.unwrap())).is_empty());
+    }
+}
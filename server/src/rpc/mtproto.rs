@@ -49,7 +49,12 @@ pub async fn rpc_req_pq_multi(
         pq: (session.auth_key_flow.p as u64 * session.auth_key_flow.q as u64)
             .to_be_bytes()
             .to_vec(),
-        server_public_key_fingerprints: vec![session.runtime_config.rsa_fingerprint],
+        server_public_key_fingerprints: session
+            .runtime_config
+            .rsa_keys
+            .iter()
+            .map(|k| k.fingerprint)
+            .collect(),
     })
 }
 
@@ -65,9 +70,14 @@ pub async fn rpc_req_dh_params(
         return Err("nonce values altered".into());
     }
 
-    if message.obj.public_key_fingerprint != session.runtime_config.rsa_fingerprint {
+    let runtime_config = session.runtime_config.clone();
+    let Some(rsa_key) = runtime_config
+        .rsa_keys
+        .iter()
+        .find(|k| k.fingerprint == message.obj.public_key_fingerprint)
+    else {
         return Err("unknown fingerprint".into());
-    }
+    };
 
     let mut convert_buffer = [0u8; 4];
     convert_buffer.clone_from_slice(&message.obj.p);
@@ -79,39 +89,13 @@ pub async fn rpc_req_dh_params(
     }
 
     let decrypted = BigUint::from_bytes_be(&message.obj.encrypted_data)
-        .modpow(
-            &session.runtime_config.rsa_private_exponent,
-            &session.runtime_config.rsa_modulus,
-        )
+        .modpow(&rsa_key.private_exponent, &rsa_key.modulus)
         .to_bytes_be();
 
     let mut extended_encryption = true;
-    match read_p_q_inner_data_variant(&mut decrypted[20..].into()) {
-        Ok(PQInnerDataVariant::PQInnerDataDc(inner_data)) => {
-            session.auth_key_flow.nonce = inner_data.nonce;
-            session.auth_key_flow.server_nonce = inner_data.server_nonce;
-            session.auth_key_flow.new_nonce = clone_sized_slice!(&inner_data.new_nonce, 32);
-            extended_encryption = false;
-        }
-        Ok(PQInnerDataVariant::PQInnerDataTempDc(inner_data)) => {
-            session.auth_key_flow.nonce = inner_data.nonce;
-            session.auth_key_flow.server_nonce = inner_data.server_nonce;
-            session.auth_key_flow.new_nonce = clone_sized_slice!(&inner_data.new_nonce, 32);
-            extended_encryption = false;
-        }
-        Ok(PQInnerDataVariant::PQInnerData(inner_data)) => {
-            session.auth_key_flow.nonce = inner_data.nonce;
-            session.auth_key_flow.server_nonce = inner_data.server_nonce;
-            session.auth_key_flow.new_nonce = clone_sized_slice!(&inner_data.new_nonce, 32);
-            extended_encryption = false;
-        }
-        Ok(PQInnerDataVariant::PQInnerDataTemp(inner_data)) => {
-            session.auth_key_flow.nonce = inner_data.nonce;
-            session.auth_key_flow.server_nonce = inner_data.server_nonce;
-            session.auth_key_flow.new_nonce = clone_sized_slice!(&inner_data.new_nonce, 32);
-            extended_encryption = false;
-        }
-        Err(..) => {}
+    if let Ok(inner_data) = read_p_q_inner_data_variant(&mut decrypted[20..].into()) {
+        session.auth_key_flow.set_inner_data(inner_data);
+        extended_encryption = false;
     }
 
     // I hate this with great passion, but this is required
@@ -136,28 +120,9 @@ pub async fn rpc_req_dh_params(
             .cloned()
             .rev()
             .collect::<Vec<_>>();
-        match read_p_q_inner_data_variant(&mut data.into())? {
-            PQInnerDataVariant::PQInnerDataDc(inner_data) => {
-                session.auth_key_flow.nonce = inner_data.nonce;
-                session.auth_key_flow.server_nonce = inner_data.server_nonce;
-                session.auth_key_flow.new_nonce = clone_sized_slice!(&inner_data.new_nonce, 32);
-            }
-            PQInnerDataVariant::PQInnerDataTempDc(inner_data) => {
-                session.auth_key_flow.nonce = inner_data.nonce;
-                session.auth_key_flow.server_nonce = inner_data.server_nonce;
-                session.auth_key_flow.new_nonce = clone_sized_slice!(&inner_data.new_nonce, 32);
-            }
-            PQInnerDataVariant::PQInnerData(inner_data) => {
-                session.auth_key_flow.nonce = inner_data.nonce;
-                session.auth_key_flow.server_nonce = inner_data.server_nonce;
-                session.auth_key_flow.new_nonce = clone_sized_slice!(&inner_data.new_nonce, 32);
-            }
-            PQInnerDataVariant::PQInnerDataTemp(inner_data) => {
-                session.auth_key_flow.nonce = inner_data.nonce;
-                session.auth_key_flow.server_nonce = inner_data.server_nonce;
-                session.auth_key_flow.new_nonce = clone_sized_slice!(&inner_data.new_nonce, 32);
-            }
-        }
+        session
+            .auth_key_flow
+            .set_inner_data(read_p_q_inner_data_variant(&mut data.into())?);
     }
 
     session.auth_key_flow.tmp_aes_key = {
@@ -267,7 +232,12 @@ pub async fn rpc_set_client_dh_params(
 
     session
         .storage
-        .insert_auth_key(auth_key_id, auth_key)
+        .insert_auth_key(
+            auth_key_id,
+            auth_key,
+            session.auth_key_flow.dc_id,
+            session.auth_key_flow.expires_in.map(|x| time!() + x),
+        )
         .await?;
 
     ok_raw!(DhGenOk {
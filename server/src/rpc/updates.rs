@@ -1,21 +1,211 @@
 use crate::session::Session;
-use crate::{ok, rpc, time};
+use crate::storage::UserState;
+use crate::{err, ok, ok_obj, rpc, time};
+use catte_server::auth;
 use catte_tl_schema::*;
+use std::collections::HashSet;
 use std::{error::Error, sync::Arc};
 use tokio::sync::Mutex;
 
+/// Updates returned by a single updates.getDifference call when pts_limit is not set
+const DIFFERENCE_LIMIT: i32 = 100;
+
+/// Clients that are further behind than this have to resync
+const DIFFERENCE_TOO_LONG: i32 = 10000;
+
+pub fn updates_state(state: &UserState, pts: i32, date: i32) -> UpdatesState {
+    UpdatesState {
+        pts,
+        qts: state.qts,
+        date,
+        seq: state.seq,
+        unread_count: 0,
+    }
+}
+
+fn add_peer(user_ids: &mut HashSet<i64>, peer: &PeerVariant) {
+    if let PeerVariant::PeerUser(peer) = peer {
+        user_ids.insert(peer.user_id);
+    }
+}
+
+///
+/// # Layer 158
+/// ## updates.getState#edd4882a = updates.State;
+/// Returns a current state of updates
+///
+/// ## Behavior
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * qts and unread_count are always 0
+/// * Seq updates before the returned seq are not returned by updates.getDifference anymore
+///
+#[auth(bots)]
 pub async fn rpc_updates_get_state(
-    _session: Arc<Mutex<Session>>,
+    session: Arc<Mutex<Session>>,
     message: rpc::Message<UpdatesGetState>,
 ) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+    let self_user = session.get_self().await?;
+    let state = session.storage.get_user_state(self_user.id).await?;
+    session
+        .storage
+        .set_session_seq(self_user.id, session.auth_key_id, state.seq)
+        .await?;
+
+    ok_obj!(
+        message,
+        SchemaObject::UpdatesState(updates_state(&state, state.pts, time!()))
+    )
+}
+
+///
+/// # Layer 158
+/// ## updates.getDifference#25939651 flags:# pts:int pts_limit:flags.1?int pts_total_limit:flags.0?int date:int qts:int qts_limit:flags.2?int = updates.Difference;
+/// Get new updates
+///
+/// ## Parameters
+/// | Name | Type | Description |
+/// | ---- | ---- | ----------- |
+/// | flags | # | Flags, see TL conditional fields |
+/// | pts | int | PTS, see updates |
+/// | pts_limit | flags.1?int | PTS limit |
+/// | pts_total_limit | flags.0?int | For fast updating: if provided and pts + pts_total_limit < remote pts, updates.differenceTooLong will be returned |
+/// | date | int | date, see updates |
+/// | qts | int | QTS, see updates |
+/// | qts_limit | flags.2?int | QTS limit |
+///
+/// ## Behavior
+/// <strong>⚠️ This function behaves the same way as official Telegram servers, but has some quirks</strong>
+/// * qts, qts_limit and date are ignored
+/// * Seq updates are returned after the seq this session got from its last updates.getState or updates.getDifference, so ones already pushed can be repeated
+/// * updates.differenceTooLong is also returned when the client is more than 10000 pts behind
+///
+#[auth(bots)]
+pub async fn rpc_updates_get_difference(
+    session: Arc<Mutex<Session>>,
+    message: rpc::Message<UpdatesGetDifference>,
+) -> Result<SchemaObject, Box<dyn Error + Send + Sync>> {
+    let session = session.lock().await;
+    let self_user = session.get_self().await?;
+    let state = session.storage.get_user_state(self_user.id).await?;
+
+    let session_seq = session.storage.get_session_seq(session.auth_key_id).await?;
+    let seq_updates = session
+        .storage
+        .get_seq_updates(self_user.id, session_seq, DIFFERENCE_LIMIT)
+        .await?;
+    // The state is only as new as the last seq update returned if some didn't fit
+    let seq = match seq_updates.last() {
+        Some((seq, _)) if seq_updates.len() as i32 == DIFFERENCE_LIMIT => *seq,
+        Some((seq, _)) => state.seq.max(*seq),
+        None => state.seq,
+    };
+
+    if message.obj.pts == state.pts && seq_updates.is_empty() {
+        session
+            .storage
+            .set_session_seq(self_user.id, session.auth_key_id, seq)
+            .await?;
+        ok!(
+            message,
+            UpdatesDifferenceEmpty {
+                date: time!(),
+                seq: state.seq,
+            }
+        )
+    }
+
+    let total_limit = message
+        .obj
+        .pts_total_limit
+        .unwrap_or(DIFFERENCE_TOO_LONG)
+        .min(DIFFERENCE_TOO_LONG);
+    if message.obj.pts > state.pts || state.pts - message.obj.pts > total_limit {
+        ok!(message, UpdatesDifferenceTooLong { pts: state.pts })
+    }
+
+    let limit = message
+        .obj
+        .pts_limit
+        .unwrap_or(DIFFERENCE_LIMIT)
+        .clamp(1, DIFFERENCE_LIMIT);
+    let logged_updates = session
+        .storage
+        .get_updates(self_user.id, message.obj.pts, limit)
+        .await?;
+
+    let (last_pts, last_date) = logged_updates
+        .last()
+        .map(|u| (u.pts, u.date))
+        .unwrap_or((state.pts, state.date));
+
+    let mut new_messages = vec![];
+    let mut other_updates = vec![];
+    let mut user_ids = HashSet::from([self_user.id]);
+    for logged_update in logged_updates {
+        match logged_update.update {
+            UpdateVariant::UpdateNewMessage(update) => {
+                let (peer_id, from_id) = match &update.message {
+                    MessageVariant::Message(m) => (Some(&m.peer_id), m.from_id.as_ref()),
+                    MessageVariant::MessageService(m) => (Some(&m.peer_id), m.from_id.as_ref()),
+                    MessageVariant::MessageEmpty(_) => (None, None),
+                };
+                for peer in [peer_id, from_id].into_iter().flatten() {
+                    add_peer(&mut user_ids, peer);
+                }
+                new_messages.push(update.message);
+            }
+            update => other_updates.push(update),
+        }
+    }
+
+    let users = session
+        .storage
+        .get_users(&user_ids.into_iter().collect::<Vec<i64>>())
+        .await?
+        .into_iter()
+        .map(|mut u| {
+            u.is_self = u.id == self_user.id;
+            u.access_hash = Some(0);
+            UserVariant::User(Box::new(u))
+        })
+        .collect();
+
+    if last_pts < state.pts {
+        // Seq updates are only sent with the last slice
+        ok!(
+            message,
+            UpdatesDifferenceSlice {
+                new_messages,
+                new_encrypted_messages: vec![],
+                other_updates,
+                chats: vec![],
+                users,
+                intermediate_state: UpdatesState {
+                    seq: session_seq,
+                    ..updates_state(&state, last_pts, last_date)
+                },
+            }
+        )
+    }
+
+    other_updates.extend(seq_updates.into_iter().map(|(_, update)| update));
+    session
+        .storage
+        .set_session_seq(self_user.id, session.auth_key_id, seq)
+        .await?;
     ok!(
         message,
-        UpdatesState {
-            pts: 0,
-            qts: 0,
-            date: time!(),
-            seq: 0,
-            unread_count: 0,
+        UpdatesDifference {
+            new_messages,
+            new_encrypted_messages: vec![],
+            other_updates,
+            chats: vec![],
+            users,
+            state: UpdatesState {
+                seq,
+                ..updates_state(&state, state.pts, time!())
+            },
         }
     )
 }
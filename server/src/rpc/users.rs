@@ -20,7 +20,7 @@ use tokio::sync::Mutex;
 /// * No access_hash handling
 /// * Limited user types: self
 ///
-#[auth]
+#[auth(bots)]
 pub async fn rpc_users_get_full_user(
     session: Arc<Mutex<Session>>,
     message: rpc::Message<UsersGetFullUser>,
@@ -65,7 +65,7 @@ pub async fn rpc_users_get_full_user(
 /// * No access_hash handling
 /// * Limited user types: self, user
 ///
-#[auth]
+#[auth(bots)]
 pub async fn rpc_users_get_users(
     session: Arc<Mutex<Session>>,
     message: rpc::Message<UsersGetUsers>,
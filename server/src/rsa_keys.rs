@@ -0,0 +1,50 @@
+use catte_tl_buffer::TlBuffer;
+use grammers_crypto::sha1;
+use num_bigint::BigUint;
+use rand::rngs::OsRng;
+use rsa::pkcs1::{DecodeRsaPrivateKey, EncodeRsaPrivateKey, EncodeRsaPublicKey, LineEnding};
+use rsa::pkcs8::DecodePrivateKey;
+use rsa::traits::{PrivateKeyParts, PublicKeyParts};
+use rsa::RsaPrivateKey;
+use std::error::Error;
+
+use crate::clone_sized_slice;
+
+pub struct RsaKey {
+    pub fingerprint: i64,
+    pub modulus: BigUint,
+    pub private_exponent: BigUint,
+}
+
+impl RsaKey {
+    /// Parses a PKCS#1 (`BEGIN RSA PRIVATE KEY`) or PKCS#8 (`BEGIN PRIVATE KEY`) PEM
+    pub fn from_pem(pem: &str) -> Result<Self, Box<dyn Error>> {
+        let key =
+            RsaPrivateKey::from_pkcs1_pem(pem).or_else(|_| RsaPrivateKey::from_pkcs8_pem(pem))?;
+        Ok(Self {
+            fingerprint: fingerprint(&key),
+            modulus: BigUint::from_bytes_be(&key.n().to_bytes_be()),
+            private_exponent: BigUint::from_bytes_be(&key.d().to_bytes_be()),
+        })
+    }
+}
+
+/// Fingerprint as used by the clients, lower 64 bits of SHA1(n:bytes e:bytes)
+pub fn fingerprint(key: &RsaPrivateKey) -> i64 {
+    let mut n = TlBuffer::new(vec![]);
+    let mut e = TlBuffer::new(vec![]);
+    n.write_bytes(&key.n().to_bytes_be());
+    e.write_bytes(&key.e().to_bytes_be());
+    i64::from_le_bytes(clone_sized_slice!(&sha1!(n.data(), e.data())[12..], 8))
+}
+
+/// Generates a new 2048-bit keypair, saves the private key to `path`
+/// and returns the public key in PKCS#1 PEM along with its fingerprint
+pub fn generate(path: &str) -> Result<(String, i64), Box<dyn Error>> {
+    let key = RsaPrivateKey::new(&mut OsRng, 2048)?;
+    std::fs::write(path, key.to_pkcs1_pem(LineEnding::LF)?.as_bytes())?;
+    Ok((
+        key.to_public_key().to_pkcs1_pem(LineEnding::LF)?,
+        fingerprint(&key),
+    ))
+}
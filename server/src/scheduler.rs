@@ -0,0 +1,38 @@
+use crate::bus::Bus;
+use crate::storage::Storage;
+use crate::{println_yellow, rpc, time};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::interval;
+
+const SCHEDULER_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Periodically sends scheduled messages whose date has come
+pub async fn run(storage: Storage, bus: Arc<Bus>) {
+    if let Err(e) = storage.release_scheduled_messages().await {
+        println_yellow!("SCHEDULER", "failed to release scheduled messages: {}", e);
+    }
+    let mut interval = interval(SCHEDULER_INTERVAL);
+    loop {
+        interval.tick().await;
+        let due = match storage.get_due_scheduled_messages(time!()).await {
+            Ok(due) => due,
+            Err(e) => {
+                println_yellow!("SCHEDULER", "failed to get scheduled messages: {}", e);
+                continue;
+            }
+        };
+        for (user_id, id) in due {
+            if let Err(e) =
+                rpc::messages::send_scheduled_message(&storage, &bus, user_id, id, None).await
+            {
+                println_yellow!(
+                    "SCHEDULER",
+                    "failed to send scheduled message {}: {}",
+                    id,
+                    e
+                );
+            }
+        }
+    }
+}
@@ -1,5 +1,10 @@
+use crate::bus::Bus;
+use crate::srp::{self, SrpFlow};
 use crate::RuntimeConfig;
-use crate::{clone_sized_slice, storage::Storage, transport::Transport, Config as ServerConfig};
+use crate::storage::{ClientInfo, Password};
+use crate::{
+    clone_sized_slice, storage::Storage, time, transport::Transport, Config as ServerConfig,
+};
 use catte_tl_buffer::TlBuffer;
 use catte_tl_schema::*;
 use flate2::read::GzDecoder;
@@ -12,6 +17,15 @@ use std::{
     time::{SystemTime, UNIX_EPOCH},
 };
 
+/// DC id assumed for auth keys created with p_q_inner_data and p_q_inner_data_temp
+pub const DEFAULT_DC_ID: i32 = 2;
+
+/// How often the last use of an auth key is refreshed while a connection keeps using it
+const AUTH_KEY_TOUCH_INTERVAL: i32 = 60;
+
+/// Requests unpacked from containers as (msg_id, seq_no, object)
+type Requests = Vec<(i64, i32, SchemaObject)>;
+
 pub struct AuthKeyFlow {
     pub nonce: i128,
     pub server_nonce: i128,
@@ -23,6 +37,8 @@ pub struct AuthKeyFlow {
     pub a: BigUint,
     pub g: i32,
     pub g_a: BigUint,
+    pub dc_id: i32,
+    pub expires_in: Option<i32>,
 }
 
 impl AuthKeyFlow {
@@ -38,23 +54,40 @@ impl AuthKeyFlow {
             a: BigUint::default(),
             g: 3,
             g_a: BigUint::default(),
+            dc_id: DEFAULT_DC_ID,
+            expires_in: None,
         }
     }
-}
 
-pub struct LoginFlow {
-    pub phone_number: String,
-    pub phone_number_verified: bool,
-    pub code: String,
-}
-
-impl LoginFlow {
-    pub fn new() -> Self {
-        Self {
-            phone_number: String::new(),
-            phone_number_verified: false,
-            code: String::new(),
-        }
+    pub fn set_inner_data(&mut self, inner_data: PQInnerDataVariant) {
+        let (nonce, server_nonce, new_nonce, dc_id, expires_in) = match inner_data {
+            PQInnerDataVariant::PQInnerDataDc(d) => {
+                (d.nonce, d.server_nonce, d.new_nonce, d.dc, None)
+            }
+            PQInnerDataVariant::PQInnerDataTempDc(d) => (
+                d.nonce,
+                d.server_nonce,
+                d.new_nonce,
+                d.dc,
+                Some(d.expires_in),
+            ),
+            PQInnerDataVariant::PQInnerData(d) => {
+                (d.nonce, d.server_nonce, d.new_nonce, DEFAULT_DC_ID, None)
+            }
+            PQInnerDataVariant::PQInnerDataTemp(d) => (
+                d.nonce,
+                d.server_nonce,
+                d.new_nonce,
+                DEFAULT_DC_ID,
+                Some(d.expires_in),
+            ),
+        };
+        self.nonce = nonce;
+        self.server_nonce = server_nonce;
+        self.new_nonce = clone_sized_slice!(&new_nonce, 32);
+        // Media DCs are negative
+        self.dc_id = dc_id.abs();
+        self.expires_in = expires_in;
     }
 }
 
@@ -62,62 +95,93 @@ pub struct Session {
     pub closed: bool,
     pub storage: Storage,
     pub authorized: bool,
+    /// Logged in without a 2FA password while one is required
+    pub setup_password_required: bool,
+    pub bot: bool,
     pub encrypted: bool,
     pub auth_key_flow: AuthKeyFlow,
     pub auth_key_id: i64,
     pub auth_key: AuthKey,
-    pub login_flow: LoginFlow,
+    pub dc_id: i32,
+    pub srp_flow: Option<SrpFlow>,
+    pub client_info: ClientInfo,
     pub id: i64,
     pub seq_no: i32,
     pub config: Arc<ServerConfig>,
     pub runtime_config: Arc<RuntimeConfig>,
+    pub bus: Arc<Bus>,
     transport: Box<dyn Transport>,
     last_msg_id: i64,
+    auth_key_touched_at: i32,
 }
 
 impl Session {
     pub async fn new(
         config: Arc<ServerConfig>,
         runtime_config: Arc<RuntimeConfig>,
+        bus: Arc<Bus>,
         transport: Box<dyn Transport>,
+        ip: String,
     ) -> Self {
         Self {
             closed: false,
             storage: Storage::new(config.data.clone()).await,
             authorized: false,
+            setup_password_required: false,
+            bot: false,
             encrypted: false,
             auth_key_flow: AuthKeyFlow::new(),
             auth_key_id: 0,
             auth_key: AuthKey::from_bytes([0u8; 256]),
-            login_flow: LoginFlow::new(),
+            dc_id: DEFAULT_DC_ID,
+            srp_flow: None,
+            client_info: ClientInfo {
+                ip,
+                ..Default::default()
+            },
             id: 0,
             seq_no: 0,
             config,
             runtime_config,
+            bus,
             transport,
             last_msg_id: 0,
+            auth_key_touched_at: 0,
         }
     }
 
+    /// Reads the next packet from the transport, this is cancel safe
+    pub async fn read(&mut self) -> Result<(Vec<u8>, bool), std::io::Error> {
+        self.transport.read().await
+    }
+
     pub async fn receive(
         &mut self,
+        (raw, quick_ack): (Vec<u8>, bool),
     ) -> Result<Vec<(i64, i32, SchemaObject)>, Box<dyn Error + Send + Sync>> {
-        let (raw, quick_ack) = self.transport.read().await?;
         let auth_key_id = i64::from_le_bytes(clone_sized_slice!(&raw[..8], 8));
 
         if !self.encrypted && auth_key_id != 0 {
-            self.storage.get_auth_key(auth_key_id).await.unwrap();
-            if let Ok(auth_key) = self.storage.get_auth_key(auth_key_id).await {
+            if let Ok((auth_key, dc_id)) = self.storage.get_auth_key(auth_key_id).await {
                 self.auth_key = AuthKey::from_bytes(auth_key);
+                self.dc_id = dc_id;
                 self.auth_key_id = auth_key_id;
                 self.encrypted = true;
             } else {
+                // Tells the client to generate a new auth_key
+                self.transport.write(&(-404i32).to_le_bytes()).await?;
                 self.close().await?;
                 return Err(format!("cannot find auth_key for {}", auth_key_id).into());
             }
         }
 
         if self.encrypted {
+            // Keys in use on long-lived connections must not look stale to the reaper
+            if time!() - self.auth_key_touched_at >= AUTH_KEY_TOUCH_INTERVAL {
+                self.storage.touch_auth_key(self.auth_key_id).await?;
+                self.auth_key_touched_at = time!();
+            }
+
             let (raw_data, ack_token) = decrypt_data_server_v2(&raw, &self.auth_key)?;
 
             let mut data = TlBuffer::new(raw_data);
@@ -137,8 +201,19 @@ impl Session {
 
             if self.id == 0 && session_id != 0 {
                 self.id = session_id;
-                if let Ok(_) = self.storage.get_user_by_session_id(self.auth_key_id).await {
+                if let Ok(false) = self.storage.get_password_pending(self.auth_key_id).await {
                     self.authorized = true;
+                    let user = self.get_self().await?;
+                    self.bot = user.bot;
+                    self.setup_password_required = self
+                        .storage
+                        .get_setup_password_required(self.auth_key_id)
+                        .await?;
+                    crate::rpc::messages::spawn_send_when_online(
+                        self.config.data.clone(),
+                        self.bus.clone(),
+                        user.id,
+                    );
                 }
             }
 
@@ -150,16 +225,13 @@ impl Session {
                 msg_id: i64,
                 seq_no: i32,
                 data: Vec<u8>,
-            ) -> Result<Vec<(i64, i32, SchemaObject)>, Box<dyn Error + Send + Sync>> {
+            ) -> Result<Requests, Box<dyn Error + Send + Sync>> {
                 match catte_tl_schema::read(&mut data.into()) {
                     Ok(result) => match result {
                         SchemaObject::MsgContainer(messages) => Ok(messages
                             .into_iter()
                             .map(
-                                |m| -> Result<
-                                    Vec<(i64, i32, SchemaObject)>,
-                                    Box<dyn Error + Send + Sync>,
-                                > {
+                                |m| -> Result<Requests, Box<dyn Error + Send + Sync>> {
                                     Ok(match m.2 {
                                         SchemaObject::GzipPacked(obj) => {
                                             let mut decoder = GzDecoder::new(&obj.packed_data[..]);
@@ -217,12 +289,12 @@ impl Session {
                     message.write(&mut obj_buf);
                 }
                 data.write_int(obj_buf.len() as i32 + 8);
-                data.write_raw(&obj_buf.data());
+                data.write_raw(obj_buf.data());
             } else {
                 let mut obj_buf = TlBuffer::new(vec![]);
                 messages[0].write(&mut obj_buf);
                 data.write_int(obj_buf.len() as i32 + 8);
-                data.write_raw(&obj_buf.data());
+                data.write_raw(obj_buf.data());
             }
 
             let mut ring_buffer = DequeBuffer::with_capacity(data.data().len(), 0);
@@ -237,7 +309,7 @@ impl Session {
             messages[0].write(&mut obj_buf);
             data.write_int(obj_buf.len() as i32);
             data.write_raw(obj_buf.data());
-            self.transport.write(&data.data()).await?;
+            self.transport.write(data.data()).await?;
         }
         Ok(())
     }
@@ -268,15 +340,62 @@ impl Session {
         Ok(u)
     }
 
-    pub async fn get_self_peer(&self) -> Result<InputPeerUser, sqlx::Error> {
-        Ok(InputPeerUser {
-            user_id: self.get_self().await?.id,
-            access_hash: 0,
-        })
+    /// Whether the session can only be used to set up a 2FA password,
+    /// another session of the user may have set it up in the meantime
+    pub async fn password_setup_pending(&mut self) -> Result<bool, sqlx::Error> {
+        if self.setup_password_required {
+            self.setup_password_required = self
+                .storage
+                .get_setup_password_required(self.auth_key_id)
+                .await?;
+        }
+        Ok(self.setup_password_required)
+    }
+
+    /// Checks the answer to the SRP exchange started by account.getPassword. Every exchange
+    /// allows a single answer, and wrong answers in a row are throttled with FLOOD_WAIT
+    pub async fn check_password(
+        &mut self,
+        user_id: i64,
+        password: &Password,
+        input: &InputCheckPasswordSrpVariant,
+    ) -> Result<Result<(), RpcError>, sqlx::Error> {
+        let now = time!();
+        if let Some(wait) = srp::flood_wait(password, now) {
+            return Ok(Err(RpcError {
+                error_code: 420,
+                error_message: format!("FLOOD_WAIT_{wait}"),
+            }));
+        }
+        match srp::check(self.srp_flow.take().as_ref(), password, input) {
+            Ok(()) => {
+                if password.failed_attempts > 0 {
+                    self.storage.reset_password_attempts(user_id).await?;
+                }
+                Ok(Ok(()))
+            }
+            Err(e) => {
+                // Answers to an exchange that doesn't exist are not guesses
+                if e != "SRP_ID_INVALID" {
+                    self.storage.fail_password_check(user_id, now).await?;
+                }
+                Ok(Err(RpcError {
+                    error_code: 400,
+                    error_message: e.to_string(),
+                }))
+            }
+        }
     }
 
     pub async fn get_self_full(&self) -> Result<(User, UserFull), sqlx::Error> {
         let user = self.get_self().await?;
+        // Saved messages are the dialog with yourself
+        let pinned_msg_id = self.storage.get_pinned_message_id(user.id, user.id).await?;
+        let has_scheduled = self
+            .storage
+            .count_scheduled_messages(user.id, user.id)
+            .await?
+            > 0;
         Ok((
             user.clone(),
             UserFull {
@@ -284,7 +403,7 @@ impl Session {
                 phone_calls_available: false,
                 phone_calls_private: false,
                 can_pin_message: true,
-                has_scheduled: false,
+                has_scheduled,
                 video_calls_available: false,
                 voice_messages_forbidden: false,
                 translations_disabled: false,
@@ -316,7 +435,7 @@ impl Session {
                     other_sound: None,
                 },
                 bot_info: None,
-                pinned_msg_id: None,
+                pinned_msg_id,
                 common_chats_count: 0,
                 folder_id: None,
                 ttl_period: None,
@@ -337,3 +456,73 @@ impl Session {
         Ok(())
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::code_sender::{CodeSenderConfig, LogCodeSender};
+    use crate::preview::{NoPreviewFetcher, PreviewFetcherConfig};
+    use async_trait::async_trait;
+    use tokio::sync::Mutex;
+
+    /// Transport of a connection that never receives anything
+    struct NullTransport;
+
+    #[async_trait]
+    impl Transport for NullTransport {
+        async fn read(&mut self) -> Result<(Vec<u8>, bool), std::io::Error> {
+            std::future::pending().await
+        }
+
+        async fn write(&mut self, _data: &[u8]) -> Result<(), std::io::Error> {
+            Ok(())
+        }
+
+        async fn write_quick_ack(&mut self, _ack_token: u32) -> Result<(), std::io::Error> {
+            Ok(())
+        }
+
+        async fn close(&mut self) -> Result<(), std::io::Error> {
+            Ok(())
+        }
+    }
+
+    /// Authorized session of the user, using the database in `data`
+    pub async fn session(data: &str, user_id: i64) -> Arc<Mutex<Session>> {
+        let config = Arc::new(ServerConfig {
+            listen_port: 443,
+            actual_port: 443,
+            host: String::new(),
+            rsa_keys: vec![],
+            auth_key_ttl: 86400,
+            authorization_ttl_days: 180,
+            phone_code_ttl: 300,
+            require_password: false,
+            data: data.to_string(),
+            code_sender: CodeSenderConfig::Log,
+            preview_fetcher: PreviewFetcherConfig::None,
+        });
+        let runtime_config = Arc::new(RuntimeConfig {
+            rsa_keys: vec![],
+            code_sender: Box::new(LogCodeSender),
+            preview_fetcher: Box::new(NoPreviewFetcher),
+        });
+        let bus = Arc::new(Bus::new(Storage::new(data.to_string()).await));
+        let mut session = Session::new(
+            config,
+            runtime_config,
+            bus,
+            Box::new(NullTransport),
+            String::new(),
+        )
+        .await;
+        session.auth_key_id = rand::random();
+        session
+            .storage
+            .insert_session(session.auth_key_id, user_id, false, &ClientInfo::default())
+            .await
+            .unwrap();
+        session.authorized = true;
+        Arc::new(Mutex::new(session))
+    }
+}
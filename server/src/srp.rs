@@ -0,0 +1,264 @@
+use crate::random::random_bytes;
+use crate::rpc::CURRENT_PRIME;
+use crate::storage::Password;
+use catte_tl_schema::{
+    InputCheckPasswordSrpVariant, PasswordKdfAlgoSha256Sha256Pbkdf2Hmacsha512Iter100000Sha256ModPow,
+};
+use num_bigint::BigUint;
+use sha2::{Digest, Sha256};
+
+pub const G: i32 = 3;
+
+/// Wrong answers allowed in a row before checks are throttled
+pub const PASSWORD_ATTEMPTS: i32 = 5;
+
+/// Seconds to wait after every wrong answer past PASSWORD_ATTEMPTS
+pub const PASSWORD_FLOOD_WAIT: i32 = 300;
+
+/// Server side of an SRP exchange started by account.getPassword
+pub struct SrpFlow {
+    pub srp_id: i64,
+    pub b: BigUint,
+    pub g_b: BigUint,
+}
+
+fn pad(n: &BigUint) -> [u8; 256] {
+    let bytes = n.to_bytes_be();
+    let mut out = [0u8; 256];
+    out[256 - bytes.len()..].copy_from_slice(&bytes);
+    out
+}
+
+fn sha256(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+pub fn algo(
+    salt1: Vec<u8>,
+    salt2: Vec<u8>,
+) -> PasswordKdfAlgoSha256Sha256Pbkdf2Hmacsha512Iter100000Sha256ModPow {
+    PasswordKdfAlgoSha256Sha256Pbkdf2Hmacsha512Iter100000Sha256ModPow {
+        salt1,
+        salt2,
+        g: G,
+        p: CURRENT_PRIME.to_vec(),
+    }
+}
+
+/// k = H(p | g)
+fn k() -> BigUint {
+    BigUint::from_bytes_be(&sha256(&[&CURRENT_PRIME, &pad(&BigUint::from(G as u32))]))
+}
+
+/// Picks a random b and computes B = (k * v + g ^ b) mod p
+pub fn start(verifier: &[u8]) -> SrpFlow {
+    let p = BigUint::from_bytes_be(&CURRENT_PRIME);
+    let b = BigUint::from_bytes_be(&random_bytes(256));
+    let v = BigUint::from_bytes_be(verifier);
+    let g_b = (k() * v + BigUint::from(G as u32).modpow(&b, &p)) % &p;
+    SrpFlow {
+        srp_id: rand::random(),
+        b,
+        g_b,
+    }
+}
+
+/// Checks whether the verifier produced by the client is usable
+pub fn is_valid_verifier(verifier: &[u8]) -> bool {
+    let v = BigUint::from_bytes_be(verifier);
+    verifier.len() <= 256 && v > BigUint::from(1u32) && v < BigUint::from_bytes_be(&CURRENT_PRIME)
+}
+
+/// Seconds left until the password can be checked again, if it's throttled
+pub fn flood_wait(password: &Password, now: i32) -> Option<i32> {
+    if password.failed_attempts < PASSWORD_ATTEMPTS {
+        return None;
+    }
+    Some(password.failed_at + PASSWORD_FLOOD_WAIT - now).filter(|&wait| wait > 0)
+}
+
+/// Verifies M1 sent by the client, returns the RPC error on failure
+pub fn check(
+    flow: Option<&SrpFlow>,
+    password: &Password,
+    input: &InputCheckPasswordSrpVariant,
+) -> Result<(), &'static str> {
+    let InputCheckPasswordSrpVariant::InputCheckPasswordSrp(input) = input else {
+        return Err("PASSWORD_HASH_INVALID");
+    };
+
+    let Some(flow) = flow.filter(|f| f.srp_id == input.srp_id) else {
+        return Err("SRP_ID_INVALID");
+    };
+
+    let p = BigUint::from_bytes_be(&CURRENT_PRIME);
+    let g_a = BigUint::from_bytes_be(&input.a);
+    if input.a.len() > 256 || g_a <= BigUint::from(1u32) || g_a >= &p - 1u32 {
+        return Err("SRP_A_INVALID");
+    }
+
+    let v = BigUint::from_bytes_be(&password.verifier);
+    let u = BigUint::from_bytes_be(&sha256(&[&pad(&g_a), &pad(&flow.g_b)]));
+    let s = (&g_a * v.modpow(&u, &p)).modpow(&flow.b, &p);
+    let k_s = sha256(&[&pad(&s)]);
+
+    let h_p = sha256(&[&CURRENT_PRIME]);
+    let h_g = sha256(&[&pad(&BigUint::from(G as u32))]);
+    let h_p_xor_h_g = h_p
+        .iter()
+        .zip(h_g.iter())
+        .map(|(a, b)| a ^ b)
+        .collect::<Vec<u8>>();
+    let m1 = sha256(&[
+        &h_p_xor_h_g,
+        &sha256(&[&password.salt1]),
+        &sha256(&[&password.salt2]),
+        &pad(&g_a),
+        &pad(&flow.g_b),
+        &k_s,
+    ]);
+
+    if m1[..] != input.m1[..] {
+        return Err("PASSWORD_HASH_INVALID");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use catte_tl_schema::{InputCheckPasswordEmpty, InputCheckPasswordSrp};
+
+    fn password(x: &BigUint) -> Password {
+        let p = BigUint::from_bytes_be(&CURRENT_PRIME);
+        Password {
+            salt1: vec![1; 8],
+            salt2: vec![2; 16],
+            verifier: BigUint::from(G as u32).modpow(x, &p).to_bytes_be(),
+            hint: None,
+            email: None,
+            failed_attempts: 0,
+            failed_at: 0,
+        }
+    }
+
+    /// Client side of the exchange for a password hash `x`, see https://core.telegram.org/api/srp
+    fn answer(flow: &SrpFlow, password: &Password, x: &BigUint) -> InputCheckPasswordSrpVariant {
+        let p = BigUint::from_bytes_be(&CURRENT_PRIME);
+        let g = BigUint::from(G as u32);
+        let a = BigUint::from_bytes_be(&random_bytes(256));
+        let g_a = g.modpow(&a, &p);
+        let u = BigUint::from_bytes_be(&sha256(&[&pad(&g_a), &pad(&flow.g_b)]));
+        let k_v = (k() * g.modpow(x, &p)) % &p;
+        let base = (&flow.g_b + &p - k_v) % &p;
+        let s = base.modpow(&(a + u * x), &p);
+        let h_p = sha256(&[&CURRENT_PRIME]);
+        let h_g = sha256(&[&pad(&g)]);
+        let h_p_xor_h_g = h_p
+            .iter()
+            .zip(h_g.iter())
+            .map(|(a, b)| a ^ b)
+            .collect::<Vec<u8>>();
+        let m1 = sha256(&[
+            &h_p_xor_h_g,
+            &sha256(&[&password.salt1]),
+            &sha256(&[&password.salt2]),
+            &pad(&g_a),
+            &pad(&flow.g_b),
+            &sha256(&[&pad(&s)]),
+        ]);
+        InputCheckPasswordSrpVariant::InputCheckPasswordSrp(Box::new(InputCheckPasswordSrp {
+            srp_id: flow.srp_id,
+            a: pad(&g_a).to_vec(),
+            m1: m1.to_vec(),
+        }))
+    }
+
+    #[test]
+    fn check_accepts_the_right_password() {
+        let x = BigUint::from_bytes_be(&random_bytes(32));
+        let password = password(&x);
+        let flow = start(&password.verifier);
+        let input = answer(&flow, &password, &x);
+        assert_eq!(check(Some(&flow), &password, &input), Ok(()));
+    }
+
+    #[test]
+    fn check_rejects_a_wrong_password() {
+        let x = BigUint::from_bytes_be(&random_bytes(32));
+        let password = password(&x);
+        let flow = start(&password.verifier);
+        let input = answer(&flow, &password, &(x + 1u32));
+        assert_eq!(
+            check(Some(&flow), &password, &input),
+            Err("PASSWORD_HASH_INVALID")
+        );
+    }
+
+    #[test]
+    fn check_rejects_an_unknown_exchange() {
+        let x = BigUint::from_bytes_be(&random_bytes(32));
+        let password = password(&x);
+        let flow = start(&password.verifier);
+        let input = answer(&flow, &password, &x);
+        assert_eq!(check(None, &password, &input), Err("SRP_ID_INVALID"));
+
+        let other = start(&password.verifier);
+        assert_eq!(
+            check(Some(&other), &password, &input),
+            Err("SRP_ID_INVALID")
+        );
+    }
+
+    #[test]
+    fn check_rejects_invalid_a() {
+        let x = BigUint::from_bytes_be(&random_bytes(32));
+        let password = password(&x);
+        let flow = start(&password.verifier);
+        for a in [vec![1], CURRENT_PRIME.to_vec()] {
+            let input = InputCheckPasswordSrpVariant::InputCheckPasswordSrp(Box::new(
+                InputCheckPasswordSrp {
+                    srp_id: flow.srp_id,
+                    a,
+                    m1: vec![0; 32],
+                },
+            ));
+            assert_eq!(check(Some(&flow), &password, &input), Err("SRP_A_INVALID"));
+        }
+    }
+
+    #[test]
+    fn check_rejects_an_empty_password() {
+        let x = BigUint::from_bytes_be(&random_bytes(32));
+        let password = password(&x);
+        let flow = start(&password.verifier);
+        let input = InputCheckPasswordSrpVariant::InputCheckPasswordEmpty(Box::new(
+            InputCheckPasswordEmpty {},
+        ));
+        assert_eq!(
+            check(Some(&flow), &password, &input),
+            Err("PASSWORD_HASH_INVALID")
+        );
+    }
+
+    #[test]
+    fn flood_wait_starts_after_the_attempt_limit() {
+        let mut password = password(&BigUint::from(2u32));
+        password.failed_at = 1000;
+        password.failed_attempts = PASSWORD_ATTEMPTS - 1;
+        assert_eq!(flood_wait(&password, 1000), None);
+
+        password.failed_attempts = PASSWORD_ATTEMPTS;
+        assert_eq!(flood_wait(&password, 1000), Some(PASSWORD_FLOOD_WAIT));
+        assert_eq!(
+            flood_wait(&password, 1000 + PASSWORD_FLOOD_WAIT - 1),
+            Some(1)
+        );
+        assert_eq!(flood_wait(&password, 1000 + PASSWORD_FLOOD_WAIT), None);
+    }
+}
@@ -1,11 +1,154 @@
+use catte_tl_buffer::{TlBuffer, TlBufferError};
 use catte_tl_schema::*;
 use sqlx::migrate::MigrateDatabase;
-use sqlx::{Pool, Row, Sqlite, SqlitePool};
-use sqlx_sqlite::{SqliteQueryResult, SqliteRow};
+use sqlx::query::Query;
+use sqlx::{Pool, Row, Sqlite, SqliteExecutor, SqlitePool};
+use sqlx_sqlite::{SqliteArguments, SqliteQueryResult, SqliteRow};
 
 use crate::{clone_sized_slice, time};
 
-const SCHEMA_VERSION: u32 = 1;
+pub struct UserState {
+    pub pts: i32,
+    pub qts: i32,
+    pub seq: i32,
+    pub date: i32,
+}
+
+pub struct LoggedUpdate {
+    pub pts: i32,
+    pub date: i32,
+    pub update: UpdateVariant,
+}
+
+/// Which messages of a dialog are listed, ids are exclusive bounds
+#[derive(Clone, Default)]
+pub struct MessageFilter {
+    pub min_id: i32,
+    pub max_id: i32,
+    pub unread_mentions: bool,
+    /// Only replies within the thread started by this message, 0 for all messages
+    pub thread_id: i32,
+    /// Words the message must contain, empty for all messages
+    pub query: String,
+    /// Only messages sent by this user, 0 for all senders
+    pub from_id: i64,
+    /// Only messages sent within these dates (inclusive), 0 for no bound
+    pub min_date: i32,
+    pub max_date: i32,
+    /// Only messages mentioning the user, read or not
+    pub mentions: bool,
+    /// Only messages containing links
+    pub urls: bool,
+    pub pinned: bool,
+}
+
+const MESSAGE_FILTER: &str = "user_id = ? AND (? = 0 OR peer_id = ?) AND id > ? AND id < ? AND (? = 0 OR (mentioned = 1 AND media_unread = 1)) AND (? = 0 OR reply_to_top_id = ?) AND (? = 0 OR from_id = ?) AND (? = 0 OR date >= ?) AND (? = 0 OR date <= ?) AND (? = 0 OR mentioned = 1) AND (? = 0 OR has_url = 1) AND (? = 0 OR pinned = 1) AND (? = '' OR row_id IN (SELECT rowid FROM messages_fts WHERE messages_fts MATCH ?))";
+
+/// A message about to be stored in a message box
+#[derive(Clone)]
+pub struct NewMessage {
+    pub peer_id: i64,
+    pub from_id: i64,
+    pub out: bool,
+    pub message: String,
+    pub date: i32,
+    pub reply_to_msg_id: Option<i32>,
+    /// The first message of the thread the reply belongs to
+    pub reply_to_top_id: Option<i32>,
+    pub fwd_from: Option<MessageFwdHeader>,
+    pub entities: Option<Vec<MessageEntityVariant>>,
+    /// Sent by the scheduler
+    pub from_scheduled: bool,
+    /// Mentions the owner of the message box
+    pub mentioned: bool,
+    /// Has contents the owner of the message box didn't see yet, such as a mention
+    pub media_unread: bool,
+}
+
+/// Where the other copy of a private message is stored
+pub struct MessageLink {
+    pub peer_id: i64,
+    pub peer_message_id: Option<i32>,
+}
+
+/// Message waiting in the sender's scheduled message box
+pub struct ScheduledMessage {
+    pub id: i32,
+    pub peer_id: i64,
+    pub message: String,
+    pub entities: Option<Vec<MessageEntityVariant>>,
+    pub reply_to_msg_id: Option<i32>,
+    /// SEND_WHEN_ONLINE sends it once the peer comes online
+    pub date: i32,
+    pub silent: bool,
+}
+
+/// Message sent with a client generated random_id
+pub struct SentMessage {
+    pub message_id: i32,
+    pub pts: i32,
+}
+
+pub struct PhoneCode {
+    pub code: String,
+    pub expires_at: i32,
+    pub verified: bool,
+}
+
+impl PhoneCode {
+    pub fn expired(&self, now: i32) -> bool {
+        self.expires_at < now
+    }
+}
+
+pub struct ExportedAuthorization {
+    pub bytes: Vec<u8>,
+    pub user_id: i64,
+    pub dc_id: i32,
+    pub expires_at: i32,
+}
+
+pub struct LoginToken {
+    pub auth_key_id: i64,
+    pub user_id: Option<i64>,
+    pub expires_at: i32,
+}
+
+/// Client info sent in initConnection
+#[derive(Default)]
+pub struct ClientInfo {
+    pub api_id: i32,
+    pub device_model: String,
+    pub platform: String,
+    pub system_version: String,
+    pub app_version: String,
+    pub ip: String,
+}
+
+pub struct UserSession {
+    pub id: i64,
+    pub password_pending: bool,
+    pub setup_password_required: bool,
+    pub hash: i64,
+    pub info: ClientInfo,
+    pub created_at: i32,
+    pub active_at: i32,
+}
+
+pub struct Password {
+    pub salt1: Vec<u8>,
+    pub salt2: Vec<u8>,
+    pub verifier: Vec<u8>,
+    pub hint: Option<String>,
+    pub email: Option<String>,
+    pub failed_attempts: i32,
+    pub failed_at: i32,
+}
+
+const SCHEMA_VERSION: u32 = 27;
+
+/// Schedule date of messages sent once the peer comes online
+pub const SEND_WHEN_ONLINE: i32 = 0x7FFFFFFE;
 pub struct Storage {
     db: Pool<Sqlite>,
 }
@@ -39,265 +182,2063 @@ impl Storage {
         &self,
         auth_key_id: i64,
         auth_key: [u8; 256],
+        dc_id: i32,
+        expires_at: Option<i32>,
     ) -> Result<SqliteQueryResult, sqlx::Error> {
-        sqlx::query("INSERT INTO auth_keys (id, auth_key) VALUES (?, ?)")
+        let date = time!();
+        sqlx::query("INSERT INTO auth_keys (id, auth_key, dc_id, temp, expires_at, created_at, last_used_at) VALUES (?, ?, ?, ?, ?, ?, ?)")
             .bind(auth_key_id)
             .bind(&auth_key[..])
+            .bind(dc_id)
+            .bind(expires_at.is_some())
+            .bind(expires_at)
+            .bind(date)
+            .bind(date)
+            .execute(&self.db)
+            .await
+    }
+
+    /// Returns the auth key along with the DC id it was created for
+    pub async fn get_auth_key(&self, auth_key_id: i64) -> Result<([u8; 256], i32), sqlx::Error> {
+        let (auth_key, dc_id) = sqlx::query_as::<_, (Vec<u8>, i32)>(
+            "SELECT auth_key, dc_id FROM auth_keys WHERE id = ?",
+        )
+        .bind(auth_key_id)
+        .fetch_one(&self.db)
+        .await?;
+        Ok((clone_sized_slice!(&auth_key, 256), dc_id))
+    }
+
+    pub async fn touch_auth_key(&self, auth_key_id: i64) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query("UPDATE auth_keys SET last_used_at = ? WHERE id = ?")
+            .bind(time!())
+            .bind(auth_key_id)
+            .execute(&self.db)
+            .await
+    }
+
+    /// Removes expired temp auth keys and auth keys that were either
+    /// unused for `ttl` seconds (temp) or never authorized (permanent)
+    pub async fn delete_stale_auth_keys(&self, ttl: i32) -> Result<u64, sqlx::Error> {
+        let now = time!();
+        let result = sqlx::query("DELETE FROM auth_keys WHERE (temp = 1 AND (expires_at < ? OR last_used_at < ?)) OR (id NOT IN (SELECT id FROM sessions) AND last_used_at < ?)")
+            .bind(now)
+            .bind(now - ttl)
+            .bind(now - ttl)
+            .execute(&self.db)
+            .await?;
+        sqlx::query("DELETE FROM sessions WHERE id NOT IN (SELECT id FROM auth_keys)")
+            .execute(&self.db)
+            .await?;
+        sqlx::query("DELETE FROM login_tokens WHERE expires_at < ?")
+            .bind(now)
+            .execute(&self.db)
+            .await?;
+        sqlx::query("DELETE FROM exported_authorizations WHERE expires_at < ?")
+            .bind(now)
+            .execute(&self.db)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    pub async fn delete_inactive_sessions(&self, ttl: i32) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM sessions WHERE active_at < ?")
+            .bind(time!() - ttl)
+            .execute(&self.db)
+            .await?;
+        // No session that could still ask for these is left
+        sqlx::query("DELETE FROM seq_updates WHERE date < ?")
+            .bind(time!() - ttl)
+            .execute(&self.db)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    pub async fn insert_phone_code(
+        &self,
+        phone: &str,
+        phone_code_hash: &str,
+        code: &str,
+        expires_at: i32,
+    ) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query("DELETE FROM phone_codes WHERE phone = ?")
+            .bind(phone)
+            .execute(&self.db)
+            .await?;
+        sqlx::query("INSERT INTO phone_codes (phone_code_hash, phone, code, expires_at) VALUES (?, ?, ?, ?)")
+            .bind(phone_code_hash)
+            .bind(phone)
+            .bind(code)
+            .bind(expires_at)
+            .execute(&self.db)
+            .await
+    }
+
+    pub async fn get_phone_code(
+        &self,
+        phone: &str,
+        phone_code_hash: &str,
+    ) -> Result<PhoneCode, sqlx::Error> {
+        sqlx::query("SELECT * FROM phone_codes WHERE phone = ? AND phone_code_hash = ?")
+            .bind(phone)
+            .bind(phone_code_hash)
+            .map(Storage::map_phone_code)
+            .fetch_one(&self.db)
+            .await
+    }
+
+    pub async fn update_phone_code(
+        &self,
+        phone_code_hash: &str,
+        code: &str,
+        expires_at: i32,
+    ) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query("UPDATE phone_codes SET code = ?, expires_at = ? WHERE phone_code_hash = ?")
+            .bind(code)
+            .bind(expires_at)
+            .bind(phone_code_hash)
+            .execute(&self.db)
+            .await
+    }
+
+    pub async fn verify_phone_code(
+        &self,
+        phone_code_hash: &str,
+    ) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query("UPDATE phone_codes SET verified = 1 WHERE phone_code_hash = ?")
+            .bind(phone_code_hash)
             .execute(&self.db)
             .await
     }
 
-    pub async fn get_auth_key(&self, auth_key_id: i64) -> Result<[u8; 256], sqlx::Error> {
-        Ok(clone_sized_slice!(
-            &sqlx::query_scalar::<_, Vec<u8>>("SELECT auth_key FROM auth_keys WHERE rowid = ?")
-                .bind(auth_key_id)
-                .fetch_one(&self.db)
-                .await?,
-            256
-        ))
+    /// Counts a wrong code, the hash is deleted once `max_attempts` wrong codes were entered.
+    /// Returns whether the hash can still be used
+    pub async fn fail_phone_code(
+        &self,
+        phone_code_hash: &str,
+        max_attempts: i32,
+    ) -> Result<bool, sqlx::Error> {
+        let attempts: Option<i32> = sqlx::query_scalar(
+            "UPDATE phone_codes SET attempts = attempts + 1 WHERE phone_code_hash = ? RETURNING attempts",
+        )
+        .bind(phone_code_hash)
+        .fetch_optional(&self.db)
+        .await?;
+        match attempts {
+            Some(attempts) if attempts < max_attempts => Ok(true),
+            Some(_) => {
+                self.delete_phone_code(phone_code_hash).await?;
+                Ok(false)
+            }
+            None => Ok(false),
+        }
+    }
+
+    pub async fn delete_phone_code(
+        &self,
+        phone_code_hash: &str,
+    ) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query("DELETE FROM phone_codes WHERE phone_code_hash = ?")
+            .bind(phone_code_hash)
+            .execute(&self.db)
+            .await
     }
 
     pub async fn insert_session(
         &self,
         session_id: i64,
         user_id: i64,
+        password_pending: bool,
+        info: &ClientInfo,
     ) -> Result<SqliteQueryResult, sqlx::Error> {
-        sqlx::query("INSERT INTO sessions (id, user_id) VALUES (?, ?)")
+        sqlx::query("INSERT OR REPLACE INTO sessions (id, user_id, password_pending, seq, hash, api_id, device_model, platform, system_version, app_version, ip, created_at, active_at) VALUES (?, ?, ?, COALESCE((SELECT seq FROM user_state WHERE user_id = ?), 0), ?, ?, ?, ?, ?, ?, ?, ?, ?)")
             .bind(session_id)
             .bind(user_id)
+            .bind(password_pending)
+            .bind(user_id)
+            .bind(rand::random::<i64>())
+            .bind(info.api_id)
+            .bind(&info.device_model)
+            .bind(&info.platform)
+            .bind(&info.system_version)
+            .bind(&info.app_version)
+            .bind(&info.ip)
+            .bind(time!())
+            .bind(time!())
             .execute(&self.db)
             .await
     }
 
-    pub async fn insert_user(
+    pub async fn update_session_info(
         &self,
-        first_name: &str,
-        last_name: &str,
-        phone: &str,
-    ) -> Result<User, sqlx::Error> {
-        let result = sqlx::query(
-            "INSERT INTO users (id, first_name, last_name, phone) VALUES (NULL, ?, ?, ?)",
-        )
-        .bind(first_name)
-        .bind(last_name)
-        .bind(phone)
-        .execute(&self.db)
-        .await?;
-        self.get_user(result.last_insert_rowid()).await
+        session_id: i64,
+        info: &ClientInfo,
+    ) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query("UPDATE sessions SET api_id = ?, device_model = ?, platform = ?, system_version = ?, app_version = ?, ip = ?, active_at = ? WHERE id = ?")
+            .bind(info.api_id)
+            .bind(&info.device_model)
+            .bind(&info.platform)
+            .bind(&info.system_version)
+            .bind(&info.app_version)
+            .bind(&info.ip)
+            .bind(time!())
+            .bind(session_id)
+            .execute(&self.db)
+            .await
     }
 
-    pub async fn update_username(&self, user_id: i64, username: &str) -> Result<SqliteQueryResult, sqlx::Error> {
-        sqlx::query("UPDATE users SET username = ? WHERE id = ?")
-            .bind(username)
+    pub async fn get_user_sessions(&self, user_id: i64) -> Result<Vec<UserSession>, sqlx::Error> {
+        sqlx::query("SELECT * FROM sessions WHERE user_id = ? ORDER BY active_at DESC")
             .bind(user_id)
-            .execute(&self.db)
+            .map(Storage::map_user_session)
+            .fetch_all(&self.db)
             .await
     }
 
-    pub async fn get_user(&self, id: i64) -> Result<User, sqlx::Error> {
-        sqlx::query("SELECT * FROM users WHERE id = ?")
-            .bind(id)
-            .map(Storage::map_user)
+    pub async fn get_user_session(&self, session_id: i64) -> Result<UserSession, sqlx::Error> {
+        sqlx::query("SELECT * FROM sessions WHERE id = ?")
+            .bind(session_id)
+            .map(Storage::map_user_session)
             .fetch_one(&self.db)
             .await
     }
 
-    pub async fn get_user_by_phone(&self, phone: &str) -> Result<User, sqlx::Error> {
-        sqlx::query("SELECT * FROM users WHERE phone = ?")
-            .bind(phone)
-            .map(Storage::map_user)
+    pub async fn get_user_session_by_hash(
+        &self,
+        user_id: i64,
+        hash: i64,
+    ) -> Result<UserSession, sqlx::Error> {
+        sqlx::query("SELECT * FROM sessions WHERE user_id = ? AND hash = ?")
+            .bind(user_id)
+            .bind(hash)
+            .map(Storage::map_user_session)
             .fetch_one(&self.db)
             .await
     }
 
-    pub async fn get_user_by_username(&self, username: &str) -> Result<User, sqlx::Error> {
-        sqlx::query("SELECT * FROM users WHERE username = ?")
-            .bind(username)
-            .map(Storage::map_user)
+    pub async fn delete_session(&self, session_id: i64) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query("DELETE FROM sessions WHERE id = ?")
+            .bind(session_id)
+            .execute(&self.db)
+            .await
+    }
+
+    pub async fn get_password_pending(&self, session_id: i64) -> Result<bool, sqlx::Error> {
+        sqlx::query_scalar("SELECT password_pending FROM sessions WHERE id = ?")
+            .bind(session_id)
             .fetch_one(&self.db)
             .await
     }
 
-    pub async fn get_user_by_session_id(&self, session_id: i64) -> Result<User, sqlx::Error> {
-        let user_id = sqlx::query_scalar("SELECT user_id FROM sessions WHERE id = ?")
+    pub async fn get_setup_password_required(&self, session_id: i64) -> Result<bool, sqlx::Error> {
+        sqlx::query_scalar("SELECT setup_password_required FROM sessions WHERE id = ?")
             .bind(session_id)
             .fetch_one(&self.db)
-            .await?;
-        self.get_user(user_id).await
+            .await
     }
 
-    pub async fn get_users(&self, ids: &[i64]) -> Result<Vec<User>, sqlx::Error> {
-        let sql_query = format!(
-            "SELECT * FROM users WHERE id IN ({})",
-            format!("?{}", ", ?".repeat(ids.len() - 1))
-        );
-        let mut query = sqlx::query(&sql_query);
-        for id in ids {
-            query = query.bind(id);
-        }
-        query.map(Storage::map_user).fetch_all(&self.db).await
+    pub async fn require_password_setup(
+        &self,
+        session_id: i64,
+    ) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query("UPDATE sessions SET setup_password_required = 1 WHERE id = ?")
+            .bind(session_id)
+            .execute(&self.db)
+            .await
     }
 
-    pub async fn get_message_by_global_id(&self, id: i64) -> Result<Message, sqlx::Error> {
-        sqlx::query("SELECT * FROM messages WHERE id = ?")
-            .bind(id)
-            .map(Storage::map_message)
-            .fetch_one(&self.db)
+    /// Lifts the restriction from all sessions of the user once a password is set
+    pub async fn finish_password_setup(
+        &self,
+        user_id: i64,
+    ) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query("UPDATE sessions SET setup_password_required = 0 WHERE user_id = ?")
+            .bind(user_id)
+            .execute(&self.db)
             .await
     }
 
-    pub async fn get_messages(
+    pub async fn confirm_session(&self, session_id: i64) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query("UPDATE sessions SET password_pending = 0 WHERE id = ?")
+            .bind(session_id)
+            .execute(&self.db)
+            .await
+    }
+
+    pub async fn insert_exported_authorization(
         &self,
-        mb_key_primary: i64,
-        mb_key_secondary: Option<i64>,
-        limit: i32,
-        offset: i32,
-    ) -> Result<Vec<Message>, sqlx::Error> {
-        sqlx::query("SELECT * FROM messages WHERE id > ? AND mb_key_primary = ? AND mb_key_secondary = ? LIMIT ?")
-            .bind(offset)
-            .bind(mb_key_primary)
-            .bind(mb_key_secondary)
-            .bind(limit)
-            .map(Storage::map_message)
-            .fetch_all(&self.db)
+        id: i64,
+        exported: &ExportedAuthorization,
+    ) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query("INSERT INTO exported_authorizations (id, bytes, user_id, dc_id, expires_at) VALUES (?, ?, ?, ?, ?)")
+            .bind(id)
+            .bind(&exported.bytes)
+            .bind(exported.user_id)
+            .bind(exported.dc_id)
+            .bind(exported.expires_at)
+            .execute(&self.db)
             .await
     }
 
-    pub async fn insert_mb_metadata(
+    /// Exported authorizations can only be imported once
+    pub async fn take_exported_authorization(
+        &self,
+        id: i64,
+    ) -> Result<ExportedAuthorization, sqlx::Error> {
+        let exported = sqlx::query("SELECT * FROM exported_authorizations WHERE id = ?")
+            .bind(id)
+            .map(Storage::map_exported_authorization)
+            .fetch_one(&self.db)
+            .await?;
+        sqlx::query("DELETE FROM exported_authorizations WHERE id = ?")
+            .bind(id)
+            .execute(&self.db)
+            .await?;
+        Ok(exported)
+    }
+
+    pub async fn insert_login_token(
         &self,
-        mb_key_primary: i64,
-        mb_key_secondary: Option<i64>,
+        token: &[u8],
+        auth_key_id: i64,
+        expires_at: i32,
     ) -> Result<SqliteQueryResult, sqlx::Error> {
-        sqlx::query("INSERT INTO mb_metadata (mb_key_primary, mb_key_secondary, last_message_id) VALUES (?, ?, 0)")
-            .bind(mb_key_primary)
-            .bind(mb_key_secondary)
+        self.delete_login_tokens(auth_key_id).await?;
+        sqlx::query("INSERT INTO login_tokens (token, auth_key_id, expires_at) VALUES (?, ?, ?)")
+            .bind(token)
+            .bind(auth_key_id)
+            .bind(expires_at)
             .execute(&self.db)
             .await
     }
 
-    pub async fn insert_mb_pts(
+    pub async fn get_login_token(&self, token: &[u8]) -> Result<LoginToken, sqlx::Error> {
+        sqlx::query("SELECT * FROM login_tokens WHERE token = ?")
+            .bind(token)
+            .map(Storage::map_login_token)
+            .fetch_one(&self.db)
+            .await
+    }
+
+    pub async fn get_accepted_login_token(
+        &self,
+        auth_key_id: i64,
+    ) -> Result<LoginToken, sqlx::Error> {
+        sqlx::query("SELECT * FROM login_tokens WHERE auth_key_id = ? AND user_id IS NOT NULL")
+            .bind(auth_key_id)
+            .map(Storage::map_login_token)
+            .fetch_one(&self.db)
+            .await
+    }
+
+    pub async fn accept_login_token(
         &self,
-        mb_key_primary: i64,
-        mb_key_secondary: Option<i64>,
+        token: &[u8],
         user_id: i64,
     ) -> Result<SqliteQueryResult, sqlx::Error> {
-        sqlx::query("INSERT INTO mb_pts (mb_key_primary, mb_key_secondary, user_id, pts) VALUES (?, ?, ?, 0)")
-            .bind(mb_key_primary)
-            .bind(mb_key_secondary)
+        sqlx::query("UPDATE login_tokens SET user_id = ? WHERE token = ?")
             .bind(user_id)
+            .bind(token)
+            .execute(&self.db)
+            .await
+    }
+
+    pub async fn delete_login_tokens(
+        &self,
+        auth_key_id: i64,
+    ) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query("DELETE FROM login_tokens WHERE auth_key_id = ?")
+            .bind(auth_key_id)
             .execute(&self.db)
             .await
     }
 
-    pub async fn increment_mb_pts(
+    pub async fn get_password(&self, user_id: i64) -> Result<Password, sqlx::Error> {
+        sqlx::query("SELECT * FROM passwords WHERE user_id = ?")
+            .bind(user_id)
+            .map(Storage::map_password)
+            .fetch_one(&self.db)
+            .await
+    }
+
+    /// Rules of a privacy key, empty if the user never set them
+    pub async fn get_privacy_rules(
         &self,
-        mb_key_primary: i64,
-        mb_key_secondary: Option<i64>,
         user_id: i64,
-        value: i32,
-    ) -> Result<i32, sqlx::Error> {
-        sqlx::query("UPDATE mb_pts SET pts = pts + ? WHERE mb_key_primary = ? AND mb_key_secondary = ? AND user_id = ?")
-            .bind(value)
-            .bind(mb_key_primary)
-            .bind(mb_key_secondary)
+        key: &PrivacyKeyVariant,
+    ) -> Result<Vec<PrivacyRuleVariant>, sqlx::Error> {
+        let mut data = TlBuffer::new(vec![]);
+        key.write(&mut data);
+        let rules: Option<Vec<u8>> =
+            sqlx::query_scalar("SELECT rules FROM privacy_rules WHERE user_id = ? AND key = ?")
+                .bind(user_id)
+                .bind(data.data())
+                .fetch_optional(&self.db)
+                .await?;
+        let Some(rules) = rules else {
+            return Ok(vec![]);
+        };
+        let mut rules = TlBuffer::new(rules);
+        let count = rules.read_int().map_err(|e| sqlx::Error::Decode(Box::new(e)))?;
+        (0..count)
+            .map(|_| {
+                read_privacy_rule_variant(&mut rules).map_err(|e| sqlx::Error::Decode(Box::new(e)))
+            })
+            .collect()
+    }
+
+    pub async fn set_privacy_rules(
+        &self,
+        user_id: i64,
+        key: &PrivacyKeyVariant,
+        rules: &[PrivacyRuleVariant],
+    ) -> Result<SqliteQueryResult, sqlx::Error> {
+        let mut key_data = TlBuffer::new(vec![]);
+        key.write(&mut key_data);
+        let mut data = TlBuffer::new(vec![]);
+        data.write_int(rules.len() as i32);
+        for rule in rules {
+            rule.write(&mut data);
+        }
+        sqlx::query("INSERT OR REPLACE INTO privacy_rules (user_id, key, rules) VALUES (?, ?, ?)")
             .bind(user_id)
+            .bind(key_data.data())
+            .bind(data.data())
             .execute(&self.db)
-            .await?;
-        self.get_mb_pts(mb_key_primary, mb_key_secondary, user_id)
             .await
     }
 
-    pub async fn get_mb_pts(
+    pub async fn set_password(
         &self,
-        mb_key_primary: i64,
-        mb_key_secondary: Option<i64>,
         user_id: i64,
-    ) -> Result<i32, sqlx::Error> {
-        sqlx::query_scalar("SELECT pts FROM mb_pts WHERE mb_key_primary = ? AND mb_key_secondary = ? AND user_id = ?")
-            .bind(mb_key_primary)
-            .bind(mb_key_secondary)
+        password: &Password,
+    ) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query("INSERT OR REPLACE INTO passwords (user_id, salt1, salt2, verifier, hint, email) VALUES (?, ?, ?, ?, ?, ?)")
             .bind(user_id)
-            .fetch_one(&self.db)
+            .bind(&password.salt1)
+            .bind(&password.salt2)
+            .bind(&password.verifier)
+            .bind(&password.hint)
+            .bind(&password.email)
+            .execute(&self.db)
             .await
     }
 
-    pub async fn get_last_message_id(
+    pub async fn update_password_email(
         &self,
-        mb_key_primary: i64,
-        mb_key_secondary: Option<i64>,
-    ) -> Result<i64, sqlx::Error> {
-        sqlx::query_scalar("SELECT last_message_id FROM mb_metadata WHERE mb_key_primary = ? AND mb_key_secondary = ?")
-            .bind(mb_key_primary)
-            .bind(mb_key_secondary)
-            .fetch_one(&self.db)
+        user_id: i64,
+        email: &str,
+    ) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query("UPDATE passwords SET email = ? WHERE user_id = ?")
+            .bind(email)
+            .bind(user_id)
+            .execute(&self.db)
             .await
     }
 
-    pub async fn increment_last_message_id(
+    pub async fn fail_password_check(
         &self,
-        mb_key_primary: i64,
-        mb_key_secondary: Option<i64>,
+        user_id: i64,
+        date: i32,
     ) -> Result<SqliteQueryResult, sqlx::Error> {
-        sqlx::query("UPDATE mb_metadata SET last_message_id = last_message_id + 1 WHERE mb_key_primary = ? AND mb_key_secondary = ?")
-            .bind(mb_key_primary)
-            .bind(mb_key_secondary)
+        sqlx::query("UPDATE passwords SET failed_attempts = failed_attempts + 1, failed_at = ? WHERE user_id = ?")
+            .bind(date)
+            .bind(user_id)
             .execute(&self.db)
             .await
     }
 
-    pub async fn insert_message(
+    pub async fn reset_password_attempts(
         &self,
-        mb_key_primary: i64,
-        mb_key_secondary: Option<i64>,
-        peer_id: i64,
-        from_id: Option<i64>,
-        message: String,
-    ) -> Result<Message, sqlx::Error> {
-        let last_message_id = match self
-            .get_last_message_id(mb_key_primary, mb_key_secondary)
-            .await
-        {
-            Ok(r) => r,
-            Err(_) => {
-                self.insert_mb_metadata(mb_key_primary, mb_key_secondary)
-                    .await?;
-                0
-            }
-        };
-        let result = sqlx::query("INSERT INTO messages (id, mb_key_primary, mb_key_secondary, message_id, peer_id, from_id, message, date) VALUES (NULL, ?, ?, ?, ?, ?, ?, ?)")
-            .bind(mb_key_primary)
-            .bind(mb_key_secondary)
-            .bind(last_message_id + 1)
-            .bind(peer_id)
-            .bind(from_id)
-            .bind(message)
-            .bind(time!())
+        user_id: i64,
+    ) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query("UPDATE passwords SET failed_attempts = 0 WHERE user_id = ?")
+            .bind(user_id)
             .execute(&self.db)
-            .await?;
-        self.increment_last_message_id(mb_key_primary, mb_key_secondary)
-            .await?;
-        self.get_message_by_global_id(result.last_insert_rowid())
+            .await
+    }
+
+    pub async fn delete_password(&self, user_id: i64) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query("DELETE FROM passwords WHERE user_id = ?")
+            .bind(user_id)
+            .execute(&self.db)
+            .await
+    }
+
+    pub async fn insert_user(
+        &self,
+        first_name: &str,
+        last_name: &str,
+        phone: &str,
+    ) -> Result<User, sqlx::Error> {
+        let result = sqlx::query(
+            "INSERT INTO users (id, first_name, last_name, phone) VALUES (NULL, ?, ?, ?)",
+        )
+        .bind(first_name)
+        .bind(last_name)
+        .bind(phone)
+        .execute(&self.db)
+        .await?;
+        self.get_user(result.last_insert_rowid()).await
+    }
+
+    pub async fn insert_bot(
+        &self,
+        first_name: &str,
+        username: &str,
+        token_secret: &str,
+    ) -> Result<(User, String), sqlx::Error> {
+        let result = sqlx::query(
+            "INSERT INTO users (id, first_name, username, bot) VALUES (NULL, ?, ?, 1)",
+        )
+        .bind(first_name)
+        .bind(username)
+        .execute(&self.db)
+        .await?;
+        let id = result.last_insert_rowid();
+        // Same format as the Bot API tokens, the id prefix keeps them unique
+        let token = format!("{id}:{token_secret}");
+        sqlx::query("UPDATE users SET bot_token = ? WHERE id = ?")
+            .bind(&token)
+            .bind(id)
+            .execute(&self.db)
+            .await?;
+        Ok((self.get_user(id).await?, token))
+    }
+
+    pub async fn get_user_by_bot_token(&self, token: &str) -> Result<User, sqlx::Error> {
+        sqlx::query("SELECT * FROM users WHERE bot = 1 AND bot_token = ?")
+            .bind(token)
+            .map(Storage::map_user)
+            .fetch_one(&self.db)
+            .await
+    }
+
+    pub async fn update_username(&self, user_id: i64, username: &str) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query("UPDATE users SET username = ? WHERE id = ?")
+            .bind(username)
+            .bind(user_id)
+            .execute(&self.db)
+            .await
+    }
+
+    pub async fn get_user(&self, id: i64) -> Result<User, sqlx::Error> {
+        sqlx::query("SELECT * FROM users WHERE id = ?")
+            .bind(id)
+            .map(Storage::map_user)
+            .fetch_one(&self.db)
+            .await
+    }
+
+    pub async fn get_user_by_phone(&self, phone: &str) -> Result<User, sqlx::Error> {
+        sqlx::query("SELECT * FROM users WHERE phone = ?")
+            .bind(phone)
+            .map(Storage::map_user)
+            .fetch_one(&self.db)
+            .await
+    }
+
+    pub async fn get_user_by_username(&self, username: &str) -> Result<User, sqlx::Error> {
+        sqlx::query("SELECT * FROM users WHERE username = ?")
+            .bind(username)
+            .map(Storage::map_user)
+            .fetch_one(&self.db)
+            .await
+    }
+
+    pub async fn get_user_by_session_id(&self, session_id: i64) -> Result<User, sqlx::Error> {
+        let user_id = sqlx::query_scalar("SELECT user_id FROM sessions WHERE id = ?")
+            .bind(session_id)
+            .fetch_one(&self.db)
+            .await?;
+        self.get_user(user_id).await
+    }
+
+    pub async fn get_users(&self, ids: &[i64]) -> Result<Vec<User>, sqlx::Error> {
+        let sql_query = format!(
+            "SELECT * FROM users WHERE id IN (?{})",
+            ", ?".repeat(ids.len() - 1)
+        );
+        let mut query = sqlx::query(&sql_query);
+        for id in ids {
+            query = query.bind(id);
+        }
+        query.map(Storage::map_user).fetch_all(&self.db).await
+    }
+
+    /// A regular message, service messages are not found
+    pub async fn get_message(&self, user_id: i64, id: i32) -> Result<Message, sqlx::Error> {
+        sqlx::query("SELECT * FROM messages WHERE user_id = ? AND id = ? AND action IS NULL")
+            .bind(user_id)
+            .bind(id)
+            .try_map(Storage::map_message)
+            .fetch_one(&self.db)
+            .await
+    }
+
+    /// A regular or service message
+    pub async fn get_message_variant(
+        &self,
+        user_id: i64,
+        id: i32,
+    ) -> Result<MessageVariant, sqlx::Error> {
+        sqlx::query("SELECT * FROM messages WHERE user_id = ? AND id = ?")
+            .bind(user_id)
+            .bind(id)
+            .try_map(Storage::map_message_variant)
+            .fetch_one(&self.db)
+            .await
+    }
+
+    /// Messages of the dialog matching the filter, newest first,
+    /// skipping the first `offset` of them
+    pub async fn get_messages(
+        &self,
+        user_id: i64,
+        peer_id: i64,
+        filter: &MessageFilter,
+        offset: i32,
+        limit: i32,
+    ) -> Result<Vec<MessageVariant>, sqlx::Error> {
+        let sql = format!("SELECT * FROM messages WHERE {MESSAGE_FILTER} ORDER BY id DESC LIMIT ? OFFSET ?");
+        Storage::bind_message_filter(sqlx::query(&sql), user_id, peer_id, filter)
+            .bind(limit)
+            .bind(offset)
+            .try_map(Storage::map_message_variant)
+            .fetch_all(&self.db)
+            .await
+    }
+
+    /// Number of messages of the dialog matching the filter that were sent at `min_date` or later
+    pub async fn count_messages(
+        &self,
+        user_id: i64,
+        peer_id: i64,
+        filter: &MessageFilter,
+        min_date: i32,
+    ) -> Result<i32, sqlx::Error> {
+        let sql = format!("SELECT COUNT(*) FROM messages WHERE {MESSAGE_FILTER} AND date >= ?");
+        let query = Storage::bind_message_filter(sqlx::query(&sql), user_id, peer_id, filter);
+        query
+            .bind(min_date)
+            .map(|row: SqliteRow| row.get(0))
+            .fetch_one(&self.db)
+            .await
+    }
+
+    /// Binds the parameters of MESSAGE_FILTER, peer_id 0 matches every dialog
+    fn bind_message_filter<'q>(
+        query: Query<'q, Sqlite, SqliteArguments<'q>>,
+        user_id: i64,
+        peer_id: i64,
+        filter: &MessageFilter,
+    ) -> Query<'q, Sqlite, SqliteArguments<'q>> {
+        let text = Storage::fts_query(&filter.query);
+        query
+            .bind(user_id)
+            .bind(peer_id)
+            .bind(peer_id)
+            .bind(filter.min_id)
+            .bind(filter.max_id)
+            .bind(filter.unread_mentions)
+            .bind(filter.thread_id)
+            .bind(filter.thread_id)
+            .bind(filter.from_id)
+            .bind(filter.from_id)
+            .bind(filter.min_date)
+            .bind(filter.min_date)
+            .bind(filter.max_date)
+            .bind(filter.max_date)
+            .bind(filter.mentions)
+            .bind(filter.urls)
+            .bind(filter.pinned)
+            .bind(text.clone())
+            .bind(text)
+    }
+
+    /// FTS5 query matching messages that contain every word of the search query,
+    /// the last word is matched as a prefix since it may not be typed in full yet
+    fn fts_query(query: &str) -> String {
+        let words: Vec<String> = query
+            .split_whitespace()
+            .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+            .collect();
+        if words.is_empty() {
+            return String::new();
+        }
+        words.join(" ") + "*"
+    }
+
+    /// Stores a message in the user's message box, message ids are per user
+    /// and shared by all of their dialogs
+    pub async fn insert_message(
+        &self,
+        user_id: i64,
+        message: &NewMessage,
+    ) -> Result<Message, sqlx::Error> {
+        let id = self.insert_message_row(user_id, message, None).await?;
+        self.get_message(user_id, id).await
+    }
+
+    /// Stores a service message, its text is ignored
+    pub async fn insert_service_message(
+        &self,
+        user_id: i64,
+        message: &NewMessage,
+        action: &MessageActionVariant,
+    ) -> Result<MessageVariant, sqlx::Error> {
+        let id = self.insert_message_row(user_id, message, Some(action)).await?;
+        self.get_message_variant(user_id, id).await
+    }
+
+    async fn insert_message_row(
+        &self,
+        user_id: i64,
+        message: &NewMessage,
+        action: Option<&MessageActionVariant>,
+    ) -> Result<i32, sqlx::Error> {
+        Self::insert_user_state(&self.db, user_id).await?;
+        let id: i32 = sqlx::query_scalar(
+            "UPDATE user_state SET message_id = message_id + 1 WHERE user_id = ? RETURNING message_id",
+        )
+        .bind(user_id)
+        .fetch_one(&self.db)
+        .await?;
+        let fwd_from = message.fwd_from.as_ref().map(|fwd_from| {
+            let mut data = TlBuffer::new(vec![]);
+            fwd_from.write(&mut data);
+            data.data().to_vec()
+        });
+        let action = action.map(|action| {
+            let mut data = TlBuffer::new(vec![]);
+            action.write(&mut data);
+            data.data().to_vec()
+        });
+        sqlx::query("INSERT INTO messages (user_id, id, peer_id, from_id, out, message, date, reply_to_msg_id, reply_to_top_id, fwd_from, entities, has_url, action, from_scheduled, mentioned, media_unread) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)")
+            .bind(user_id)
+            .bind(id)
+            .bind(message.peer_id)
+            .bind(message.from_id)
+            .bind(message.out)
+            .bind(if action.is_some() { "" } else { &message.message })
+            .bind(message.date)
+            .bind(message.reply_to_msg_id)
+            .bind(message.reply_to_top_id)
+            .bind(fwd_from)
+            .bind(message.entities.as_deref().map(Storage::write_entities))
+            .bind(Storage::has_url(message.entities.as_deref()))
+            .bind(action)
+            .bind(message.from_scheduled)
+            .bind(message.mentioned)
+            .bind(message.media_unread)
+            .execute(&self.db)
+            .await?;
+        Ok(id)
+    }
+
+    /// Stores a message in the user's scheduled message box, its id is ignored
+    pub async fn insert_scheduled_message(
+        &self,
+        user_id: i64,
+        message: &ScheduledMessage,
+    ) -> Result<ScheduledMessage, sqlx::Error> {
+        Self::insert_user_state(&self.db, user_id).await?;
+        let id: i32 = sqlx::query_scalar(
+            "UPDATE user_state SET scheduled_message_id = scheduled_message_id + 1 WHERE user_id = ? RETURNING scheduled_message_id",
+        )
+        .bind(user_id)
+        .fetch_one(&self.db)
+        .await?;
+        sqlx::query("INSERT INTO scheduled_messages (user_id, id, peer_id, message, entities, reply_to_msg_id, date, silent) VALUES (?, ?, ?, ?, ?, ?, ?, ?) RETURNING *")
+            .bind(user_id)
+            .bind(id)
+            .bind(message.peer_id)
+            .bind(&message.message)
+            .bind(message.entities.as_deref().map(Storage::write_entities))
+            .bind(message.reply_to_msg_id)
+            .bind(message.date)
+            .bind(message.silent)
+            .try_map(Storage::map_scheduled_message)
+            .fetch_one(&self.db)
+            .await
+    }
+
+    /// Scheduled messages of the dialog, the ones sent last come first
+    pub async fn get_scheduled_messages(
+        &self,
+        user_id: i64,
+        peer_id: i64,
+    ) -> Result<Vec<ScheduledMessage>, sqlx::Error> {
+        sqlx::query("SELECT * FROM scheduled_messages WHERE user_id = ? AND peer_id = ? ORDER BY date DESC, id DESC")
+            .bind(user_id)
+            .bind(peer_id)
+            .try_map(Storage::map_scheduled_message)
+            .fetch_all(&self.db)
+            .await
+    }
+
+    pub async fn get_scheduled_message(
+        &self,
+        user_id: i64,
+        id: i32,
+    ) -> Result<Option<ScheduledMessage>, sqlx::Error> {
+        sqlx::query("SELECT * FROM scheduled_messages WHERE user_id = ? AND id = ?")
+            .bind(user_id)
+            .bind(id)
+            .try_map(Storage::map_scheduled_message)
+            .fetch_optional(&self.db)
+            .await
+    }
+
+    pub async fn count_scheduled_messages(
+        &self,
+        user_id: i64,
+        peer_id: i64,
+    ) -> Result<i32, sqlx::Error> {
+        sqlx::query_scalar(
+            "SELECT COUNT(*) FROM scheduled_messages WHERE user_id = ? AND peer_id = ?",
+        )
+        .bind(user_id)
+        .bind(peer_id)
+        .fetch_one(&self.db)
+        .await
+    }
+
+    /// Removes a message from the scheduled message box, returns None if it was already gone
+    /// or is being sent
+    pub async fn take_scheduled_message(
+        &self,
+        user_id: i64,
+        id: i32,
+    ) -> Result<Option<ScheduledMessage>, sqlx::Error> {
+        sqlx::query("DELETE FROM scheduled_messages WHERE user_id = ? AND id = ? AND sending = 0 RETURNING *")
+            .bind(user_id)
+            .bind(id)
+            .try_map(Storage::map_scheduled_message)
+            .fetch_optional(&self.db)
+            .await
+    }
+
+    /// Marks a scheduled message as being sent, returns None if it was already gone or claimed.
+    /// Whoever claims it is the only one allowed to send it, and must either finish or release it
+    pub async fn claim_scheduled_message(
+        &self,
+        user_id: i64,
+        id: i32,
+    ) -> Result<Option<ScheduledMessage>, sqlx::Error> {
+        sqlx::query("UPDATE scheduled_messages SET sending = 1 WHERE user_id = ? AND id = ? AND sending = 0 RETURNING *")
+            .bind(user_id)
+            .bind(id)
+            .try_map(Storage::map_scheduled_message)
+            .fetch_optional(&self.db)
+            .await
+    }
+
+    /// Removes a claimed scheduled message once it was sent
+    pub async fn finish_scheduled_message(&self, user_id: i64, id: i32) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM scheduled_messages WHERE user_id = ? AND id = ?")
+            .bind(user_id)
+            .bind(id)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    /// Puts a claimed scheduled message back in the box after sending it failed
+    pub async fn release_scheduled_message(
+        &self,
+        user_id: i64,
+        id: i32,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE scheduled_messages SET sending = 0 WHERE user_id = ? AND id = ?")
+            .bind(user_id)
+            .bind(id)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    /// Releases every claimed scheduled message, the server may have stopped while sending them
+    pub async fn release_scheduled_messages(&self) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE scheduled_messages SET sending = 0 WHERE sending = 1")
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    /// (user_id, id) of the scheduled messages due at `date`
+    pub async fn get_due_scheduled_messages(
+        &self,
+        date: i32,
+    ) -> Result<Vec<(i64, i32)>, sqlx::Error> {
+        sqlx::query("SELECT user_id, id FROM scheduled_messages WHERE date <= ? AND sending = 0 ORDER BY date, id")
+            .bind(date)
+            .map(|row: SqliteRow| (row.get("user_id"), row.get("id")))
+            .fetch_all(&self.db)
+            .await
+    }
+
+    /// (user_id, id) of the scheduled messages waiting for the peer to come online
+    pub async fn get_when_online_scheduled_messages(
+        &self,
+        peer_id: i64,
+    ) -> Result<Vec<(i64, i32)>, sqlx::Error> {
+        sqlx::query("SELECT user_id, id FROM scheduled_messages WHERE peer_id = ? AND date = ? AND sending = 0 ORDER BY id")
+            .bind(peer_id)
+            .bind(SEND_WHEN_ONLINE)
+            .map(|row: SqliteRow| (row.get("user_id"), row.get("id")))
+            .fetch_all(&self.db)
+            .await
+    }
+
+    /// Claims the random_id for a message about to be sent, returns false if it was already used
+    pub async fn reserve_random_id(&self, user_id: i64, random_id: i64) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("INSERT INTO random_ids (user_id, random_id, message_id, pts) VALUES (?, ?, 0, 0) ON CONFLICT DO NOTHING")
+            .bind(user_id)
+            .bind(random_id)
+            .execute(&self.db)
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    pub async fn set_random_id(
+        &self,
+        user_id: i64,
+        random_id: i64,
+        sent: &SentMessage,
+    ) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query("UPDATE random_ids SET message_id = ?, pts = ? WHERE user_id = ? AND random_id = ?")
+            .bind(sent.message_id)
+            .bind(sent.pts)
+            .bind(user_id)
+            .bind(random_id)
+            .execute(&self.db)
+            .await
+    }
+
+    /// Frees a reserved random_id whose message was never sent
+    pub async fn release_random_id(
+        &self,
+        user_id: i64,
+        random_id: i64,
+    ) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query("DELETE FROM random_ids WHERE user_id = ? AND random_id = ? AND message_id = 0")
+            .bind(user_id)
+            .bind(random_id)
+            .execute(&self.db)
+            .await
+    }
+
+    pub async fn get_random_id(
+        &self,
+        user_id: i64,
+        random_id: i64,
+    ) -> Result<Option<SentMessage>, sqlx::Error> {
+        sqlx::query("SELECT * FROM random_ids WHERE user_id = ? AND random_id = ?")
+            .bind(user_id)
+            .bind(random_id)
+            .map(Storage::map_sent_message)
+            .fetch_optional(&self.db)
+            .await
+    }
+
+    pub async fn get_message_link(
+        &self,
+        user_id: i64,
+        id: i32,
+    ) -> Result<Option<MessageLink>, sqlx::Error> {
+        sqlx::query("SELECT peer_id, peer_message_id FROM messages WHERE user_id = ? AND id = ?")
+            .bind(user_id)
+            .bind(id)
+            .map(Storage::map_message_link)
+            .fetch_optional(&self.db)
+            .await
+    }
+
+    /// Ids of the dialog's messages up to `max_id` (all if 0) sent between `min_date` and `max_date`
+    pub async fn get_message_ids(
+        &self,
+        user_id: i64,
+        peer_id: i64,
+        max_id: i32,
+        min_date: i32,
+        max_date: i32,
+    ) -> Result<Vec<i32>, sqlx::Error> {
+        sqlx::query_scalar("SELECT id FROM messages WHERE user_id = ? AND peer_id = ? AND (? = 0 OR id <= ?) AND date >= ? AND date <= ? ORDER BY id")
+            .bind(user_id)
+            .bind(peer_id)
+            .bind(max_id)
+            .bind(max_id)
+            .bind(min_date)
+            .bind(max_date)
+            .fetch_all(&self.db)
+            .await
+    }
+
+    /// Pins or unpins a regular message, returns false if it was already in that state
+    pub async fn set_message_pinned(
+        &self,
+        user_id: i64,
+        id: i32,
+        pinned: bool,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("UPDATE messages SET pinned = ? WHERE user_id = ? AND id = ? AND action IS NULL AND pinned != ?")
+            .bind(pinned)
+            .bind(user_id)
+            .bind(id)
+            .bind(pinned)
+            .execute(&self.db)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Unpins every message of the dialog, or of the thread if `thread_id` isn't 0,
+    /// returns the ids of the unpinned messages
+    pub async fn unpin_messages(
+        &self,
+        user_id: i64,
+        peer_id: i64,
+        thread_id: i32,
+    ) -> Result<Vec<i32>, sqlx::Error> {
+        sqlx::query_scalar("UPDATE messages SET pinned = 0 WHERE user_id = ? AND peer_id = ? AND pinned = 1 AND (? = 0 OR reply_to_top_id = ?) RETURNING id")
+            .bind(user_id)
+            .bind(peer_id)
+            .bind(thread_id)
+            .bind(thread_id)
+            .fetch_all(&self.db)
+            .await
+    }
+
+    /// The most recent pinned message of the dialog
+    pub async fn get_pinned_message_id(
+        &self,
+        user_id: i64,
+        peer_id: i64,
+    ) -> Result<Option<i32>, sqlx::Error> {
+        sqlx::query_scalar("SELECT MAX(id) FROM messages WHERE user_id = ? AND peer_id = ? AND pinned = 1")
+            .bind(user_id)
+            .bind(peer_id)
+            .fetch_one(&self.db)
+            .await
+    }
+
+    pub async fn edit_message(
+        &self,
+        user_id: i64,
+        id: i32,
+        message: &str,
+        entities: Option<&[MessageEntityVariant]>,
+        edit_date: i32,
+    ) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query("UPDATE messages SET message = ?, entities = ?, has_url = ?, edit_date = ? WHERE user_id = ? AND id = ?")
+            .bind(message)
+            .bind(entities.map(Storage::write_entities))
+            .bind(Storage::has_url(entities))
+            .bind(edit_date)
+            .bind(user_id)
+            .bind(id)
+            .execute(&self.db)
+            .await
+    }
+
+    /// Marks the contents of an incoming message as read,
+    /// returns None if there was nothing to read
+    pub async fn read_message_contents(
+        &self,
+        user_id: i64,
+        id: i32,
+    ) -> Result<Option<MessageLink>, sqlx::Error> {
+        sqlx::query("UPDATE messages SET media_unread = 0 WHERE user_id = ? AND id = ? AND media_unread = 1 RETURNING peer_id, peer_message_id")
+            .bind(user_id)
+            .bind(id)
+            .map(Storage::map_message_link)
+            .fetch_optional(&self.db)
+            .await
+    }
+
+    /// The highest id the peer uses for the incoming messages up to `max_id`
+    pub async fn get_peer_max_message_id(
+        &self,
+        user_id: i64,
+        peer_id: i64,
+        max_id: i32,
+    ) -> Result<Option<i32>, sqlx::Error> {
+        sqlx::query_scalar("SELECT MAX(peer_message_id) FROM messages WHERE user_id = ? AND peer_id = ? AND out = 0 AND id <= ?")
+            .bind(user_id)
+            .bind(peer_id)
+            .bind(max_id)
+            .fetch_one(&self.db)
+            .await
+    }
+
+    pub async fn delete_message(
+        &self,
+        user_id: i64,
+        id: i32,
+    ) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query("DELETE FROM messages WHERE user_id = ? AND id = ?")
+            .bind(user_id)
+            .bind(id)
+            .execute(&self.db)
+            .await
+    }
+
+    /// Links the two copies of a private message so edits and deletions
+    /// can be applied to both message boxes
+    pub async fn link_messages(
+        &self,
+        user_id: i64,
+        id: i32,
+        peer_id: i64,
+        peer_message_id: i32,
+    ) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query("UPDATE messages SET peer_message_id = ? WHERE user_id = ? AND id = ?")
+            .bind(peer_message_id)
+            .bind(user_id)
+            .bind(id)
+            .execute(&self.db)
+            .await?;
+        sqlx::query("UPDATE messages SET peer_message_id = ? WHERE user_id = ? AND id = ?")
+            .bind(id)
+            .bind(peer_id)
+            .bind(peer_message_id)
+            .execute(&self.db)
+            .await
+    }
+
+    /// Moves the message to the top of the dialog with `peer_id`, creating the dialog
+    /// if needed. Incoming messages are counted as unread
+    pub async fn update_dialog(
+        &self,
+        user_id: i64,
+        peer_id: i64,
+        message: &Message,
+    ) -> Result<SqliteQueryResult, sqlx::Error> {
+        let unread = if message.out { 0 } else { 1 };
+        let unread_mentions = if message.mentioned && message.media_unread { 1 } else { 0 };
+        sqlx::query("INSERT INTO dialogs (user_id, peer_id, top_message, top_message_date, unread_count, unread_mentions_count) VALUES (?, ?, ?, ?, ?, ?) ON CONFLICT (user_id, peer_id) DO UPDATE SET top_message = excluded.top_message, top_message_date = excluded.top_message_date, unread_count = unread_count + excluded.unread_count, unread_mentions_count = unread_mentions_count + excluded.unread_mentions_count")
+            .bind(user_id)
+            .bind(peer_id)
+            .bind(message.id)
+            .bind(message.date)
+            .bind(unread)
+            .bind(unread_mentions)
+            .execute(&self.db)
+            .await
+    }
+
+    /// Recalculates the top message and the unread count after messages were deleted,
+    /// the dialog is kept with top_message 0 when it becomes empty
+    pub async fn refresh_dialog(
+        &self,
+        user_id: i64,
+        peer_id: i64,
+    ) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query("UPDATE dialogs SET top_message = COALESCE((SELECT MAX(id) FROM messages WHERE user_id = dialogs.user_id AND peer_id = dialogs.peer_id), 0), top_message_date = COALESCE((SELECT date FROM messages WHERE user_id = dialogs.user_id AND peer_id = dialogs.peer_id ORDER BY id DESC LIMIT 1), top_message_date), unread_count = (SELECT COUNT(*) FROM messages WHERE user_id = dialogs.user_id AND peer_id = dialogs.peer_id AND out = 0 AND id > dialogs.read_inbox_max_id), unread_mentions_count = (SELECT COUNT(*) FROM messages WHERE user_id = dialogs.user_id AND peer_id = dialogs.peer_id AND mentioned = 1 AND media_unread = 1) WHERE user_id = ? AND peer_id = ?")
+            .bind(user_id)
+            .bind(peer_id)
+            .execute(&self.db)
+            .await
+    }
+
+    /// Marks incoming messages up to `max_id` as read, returns the new
+    /// read_inbox_max_id and unread count or None if nothing changed
+    pub async fn read_inbox(
+        &self,
+        user_id: i64,
+        peer_id: i64,
+        max_id: i32,
+    ) -> Result<Option<(i32, i32)>, sqlx::Error> {
+        sqlx::query_as("UPDATE dialogs SET read_inbox_max_id = MIN(?, top_message), unread_mark = 0, unread_count = (SELECT COUNT(*) FROM messages WHERE user_id = dialogs.user_id AND peer_id = dialogs.peer_id AND out = 0 AND id > MIN(?, dialogs.top_message)) WHERE user_id = ? AND peer_id = ? AND read_inbox_max_id < MIN(?, top_message) RETURNING read_inbox_max_id, unread_count")
+            .bind(max_id)
+            .bind(max_id)
+            .bind(user_id)
+            .bind(peer_id)
+            .bind(max_id)
+            .fetch_optional(&self.db)
+            .await
+    }
+
+    /// Marks outgoing messages up to `max_id` as read by the peer,
+    /// returns false if they already were
+    pub async fn read_outbox(
+        &self,
+        user_id: i64,
+        peer_id: i64,
+        max_id: i32,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("UPDATE dialogs SET read_outbox_max_id = ? WHERE user_id = ? AND peer_id = ? AND read_outbox_max_id < ?")
+            .bind(max_id)
+            .bind(user_id)
+            .bind(peer_id)
+            .bind(max_id)
+            .execute(&self.db)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn delete_dialog(
+        &self,
+        user_id: i64,
+        peer_id: i64,
+    ) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query("DELETE FROM dialogs WHERE user_id = ? AND peer_id = ?")
+            .bind(user_id)
+            .bind(peer_id)
+            .execute(&self.db)
+            .await
+    }
+
+    pub async fn get_dialog(
+        &self,
+        user_id: i64,
+        peer_id: i64,
+    ) -> Result<Option<Dialog>, sqlx::Error> {
+        sqlx::query("SELECT * FROM dialogs WHERE user_id = ? AND peer_id = ?")
+            .bind(user_id)
+            .bind(peer_id)
+            .try_map(Storage::map_dialog)
+            .fetch_optional(&self.db)
+            .await
+    }
+
+    /// Saves or clears (None) the draft of a dialog, creating the dialog if needed
+    pub async fn set_draft(
+        &self,
+        user_id: i64,
+        peer_id: i64,
+        draft: Option<&DraftMessage>,
+    ) -> Result<SqliteQueryResult, sqlx::Error> {
+        let (data, date) = match draft {
+            Some(draft) => {
+                let mut data = TlBuffer::new(vec![]);
+                DraftMessageVariant::DraftMessage(Box::new(draft.clone())).write(&mut data);
+                (Some(data.data().to_vec()), draft.date)
+            }
+            None => (None, time!()),
+        };
+        sqlx::query("INSERT INTO dialogs (user_id, peer_id, top_message, top_message_date, draft) VALUES (?, ?, 0, ?, ?) ON CONFLICT (user_id, peer_id) DO UPDATE SET draft = excluded.draft")
+            .bind(user_id)
+            .bind(peer_id)
+            .bind(date)
+            .bind(data)
+            .execute(&self.db)
+            .await
+    }
+
+    /// Dialogs that have a draft
+    pub async fn get_draft_dialogs(&self, user_id: i64) -> Result<Vec<Dialog>, sqlx::Error> {
+        sqlx::query("SELECT * FROM dialogs WHERE user_id = ? AND draft IS NOT NULL")
+            .bind(user_id)
+            .try_map(Storage::map_dialog)
+            .fetch_all(&self.db)
+            .await
+    }
+
+    /// Clears every draft, returns the peers of the dialogs that had one
+    pub async fn clear_drafts(&self, user_id: i64) -> Result<Vec<i64>, sqlx::Error> {
+        sqlx::query_scalar("UPDATE dialogs SET draft = NULL WHERE user_id = ? AND draft IS NOT NULL RETURNING peer_id")
+            .bind(user_id)
+            .fetch_all(&self.db)
+            .await
+    }
+
+    /// Unpinned dialogs of a folder ordered by their top message, newest first,
+    /// starting after the dialog whose top message is (`offset_date`, `offset_id`)
+    pub async fn get_dialogs(
+        &self,
+        user_id: i64,
+        folder_id: i32,
+        offset_date: i32,
+        offset_id: i32,
+        limit: i32,
+    ) -> Result<Vec<Dialog>, sqlx::Error> {
+        sqlx::query("SELECT * FROM dialogs WHERE user_id = ? AND folder_id = ? AND pinned = 0 AND (top_message_date, top_message) < (?, ?) ORDER BY top_message_date DESC, top_message DESC LIMIT ?")
+            .bind(user_id)
+            .bind(folder_id)
+            .bind(offset_date)
+            .bind(offset_id)
+            .bind(limit)
+            .try_map(Storage::map_dialog)
+            .fetch_all(&self.db)
+            .await
+    }
+
+    pub async fn get_pinned_dialogs(
+        &self,
+        user_id: i64,
+        folder_id: i32,
+    ) -> Result<Vec<Dialog>, sqlx::Error> {
+        sqlx::query("SELECT * FROM dialogs WHERE user_id = ? AND folder_id = ? AND pinned != 0 ORDER BY pinned")
+            .bind(user_id)
+            .bind(folder_id)
+            .try_map(Storage::map_dialog)
+            .fetch_all(&self.db)
+            .await
+    }
+
+    pub async fn count_dialogs(&self, user_id: i64, folder_id: i32) -> Result<i32, sqlx::Error> {
+        sqlx::query_scalar("SELECT COUNT(*) FROM dialogs WHERE user_id = ? AND folder_id = ?")
+            .bind(user_id)
+            .bind(folder_id)
+            .fetch_one(&self.db)
+            .await
+    }
+
+    pub async fn get_user_state(&self, user_id: i64) -> Result<UserState, sqlx::Error> {
+        Ok(sqlx::query("SELECT * FROM user_state WHERE user_id = ?")
+            .bind(user_id)
+            .map(Storage::map_user_state)
+            .fetch_optional(&self.db)
+            .await?
+            .unwrap_or(UserState {
+                pts: 0,
+                qts: 0,
+                seq: 0,
+                date: 0,
+            }))
+    }
+
+    /// Advances the user's pts by `pts_count` and logs the update built for the new pts,
+    /// so it can be replayed by updates.getDifference
+    pub async fn log_update(
+        &self,
+        user_id: i64,
+        pts_count: i32,
+        update: impl FnOnce(i32) -> UpdateVariant,
+    ) -> Result<i32, sqlx::Error> {
+        let date = time!();
+        let mut tx = self.db.begin().await?;
+        Self::insert_user_state(&mut *tx, user_id).await?;
+        let pts: i32 = sqlx::query_scalar(
+            "UPDATE user_state SET pts = pts + ?, date = ? WHERE user_id = ? RETURNING pts",
+        )
+        .bind(pts_count)
+        .bind(date)
+        .bind(user_id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let mut data = TlBuffer::new(vec![]);
+        update(pts).write(&mut data);
+        sqlx::query("INSERT INTO updates (user_id, pts, pts_count, date, data) VALUES (?, ?, ?, ?, ?)")
+            .bind(user_id)
+            .bind(pts)
+            .bind(pts_count)
+            .bind(date)
+            .bind(data.data())
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(pts)
+    }
+
+    async fn insert_user_state<'e>(
+        executor: impl SqliteExecutor<'e>,
+        user_id: i64,
+    ) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query(
+            "INSERT OR IGNORE INTO user_state (user_id, pts, qts, seq, date, message_id) VALUES (?, 0, 0, 0, 0, 0)",
+        )
+        .bind(user_id)
+        .execute(executor)
+        .await
+    }
+
+    /// Advances the user's seq by one for every update and logs them,
+    /// returns the first and the last seq used
+    pub async fn log_seq_updates(
+        &self,
+        user_id: i64,
+        updates: &[UpdateVariant],
+    ) -> Result<(i32, i32), sqlx::Error> {
+        let date = time!();
+        let mut tx = self.db.begin().await?;
+        Self::insert_user_state(&mut *tx, user_id).await?;
+        let seq: i32 = sqlx::query_scalar(
+            "UPDATE user_state SET seq = seq + ?, date = ? WHERE user_id = ? RETURNING seq",
+        )
+        .bind(updates.len() as i32)
+        .bind(date)
+        .bind(user_id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let seq_start = seq - updates.len() as i32 + 1;
+        for (i, update) in updates.iter().enumerate() {
+            let mut data = TlBuffer::new(vec![]);
+            update.write(&mut data);
+            sqlx::query("INSERT INTO seq_updates (user_id, seq, date, data) VALUES (?, ?, ?, ?)")
+                .bind(user_id)
+                .bind(seq_start + i as i32)
+                .bind(date)
+                .bind(data.data())
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok((seq_start, seq))
+    }
+
+    /// Logged seq updates after `seq` with their seq, oldest first
+    pub async fn get_seq_updates(
+        &self,
+        user_id: i64,
+        seq: i32,
+        limit: i32,
+    ) -> Result<Vec<(i32, UpdateVariant)>, sqlx::Error> {
+        sqlx::query("SELECT seq, data FROM seq_updates WHERE user_id = ? AND seq > ? ORDER BY seq LIMIT ?")
+            .bind(user_id)
+            .bind(seq)
+            .bind(limit)
+            .try_map(|row: SqliteRow| {
+                read_update_variant(&mut TlBuffer::new(row.get("data")))
+                    .map(|update| (row.get("seq"), update))
+                    .map_err(|e| sqlx::Error::Decode(Box::new(e)))
+            })
+            .fetch_all(&self.db)
+            .await
+    }
+
+    pub async fn get_session_seq(&self, session_id: i64) -> Result<i32, sqlx::Error> {
+        sqlx::query_scalar("SELECT seq FROM sessions WHERE id = ?")
+            .bind(session_id)
+            .fetch_one(&self.db)
+            .await
+    }
+
+    /// Remembers the seq the session was given and drops seq updates every session of the user has got
+    pub async fn set_session_seq(
+        &self,
+        user_id: i64,
+        session_id: i64,
+        seq: i32,
+    ) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query("UPDATE sessions SET seq = MAX(seq, ?) WHERE id = ?")
+            .bind(seq)
+            .bind(session_id)
+            .execute(&self.db)
+            .await?;
+        sqlx::query("DELETE FROM seq_updates WHERE user_id = ? AND seq <= (SELECT MIN(seq) FROM sessions WHERE user_id = ?)")
+            .bind(user_id)
+            .bind(user_id)
+            .execute(&self.db)
+            .await
+    }
+
+    /// Logged updates after `pts`, oldest first
+    pub async fn get_updates(
+        &self,
+        user_id: i64,
+        pts: i32,
+        limit: i32,
+    ) -> Result<Vec<LoggedUpdate>, sqlx::Error> {
+        sqlx::query("SELECT * FROM updates WHERE user_id = ? AND pts > ? ORDER BY pts LIMIT ?")
+            .bind(user_id)
+            .bind(pts)
+            .bind(limit)
+            .try_map(Storage::map_logged_update)
+            .fetch_all(&self.db)
             .await
     }
 
     pub fn map_user(row: SqliteRow) -> User {
-        let mut user = User::default();
-        user.id = row.get("id");
-        user.first_name = row.get("first_name");
-        user.last_name = row.get("last_name");
-        user.phone = row.get("phone");
-        user.username = row.get("username");
-        user
-    }
-
-    pub fn map_message(row: SqliteRow) -> Message {
-        let mut message = Message::default();
-        message.id = row.get("message_id");
-        message.message = row.get("message");
-        message.date = row.get("date");
-        message.peer_id = PeerVariant::PeerUser(Box::new(PeerUser {
-            user_id: row.get("peer_id"),
-        }));
-        message
+        let bot: bool = row.get("bot");
+        User {
+            id: row.get("id"),
+            first_name: row.get("first_name"),
+            last_name: row.get("last_name"),
+            phone: row.get("phone"),
+            username: row.get("username"),
+            bot,
+            bot_info_version: bot.then_some(1),
+            ..Default::default()
+        }
+    }
+
+    pub fn map_user_state(row: SqliteRow) -> UserState {
+        UserState {
+            pts: row.get("pts"),
+            qts: row.get("qts"),
+            seq: row.get("seq"),
+            date: row.get("date"),
+        }
+    }
+
+    pub fn map_logged_update(row: SqliteRow) -> Result<LoggedUpdate, sqlx::Error> {
+        let update = read_update_variant(&mut TlBuffer::new(row.get("data")))
+            .map_err(|e| sqlx::Error::Decode(Box::new(e)))?;
+        Ok(LoggedUpdate {
+            pts: row.get("pts"),
+            date: row.get("date"),
+            update,
+        })
+    }
+
+    pub fn map_message_link(row: SqliteRow) -> MessageLink {
+        MessageLink {
+            peer_id: row.get("peer_id"),
+            peer_message_id: row.get("peer_message_id"),
+        }
+    }
+
+    pub fn map_scheduled_message(row: SqliteRow) -> Result<ScheduledMessage, sqlx::Error> {
+        let entities = match row.get::<Option<Vec<u8>>, _>("entities") {
+            Some(data) => {
+                Some(Storage::read_entities(data).map_err(|e| sqlx::Error::Decode(Box::new(e)))?)
+            }
+            None => None,
+        };
+        Ok(ScheduledMessage {
+            id: row.get("id"),
+            peer_id: row.get("peer_id"),
+            message: row.get("message"),
+            entities,
+            reply_to_msg_id: row.get("reply_to_msg_id"),
+            date: row.get("date"),
+            silent: row.get("silent"),
+        })
+    }
+
+    pub fn map_sent_message(row: SqliteRow) -> SentMessage {
+        SentMessage {
+            message_id: row.get("message_id"),
+            pts: row.get("pts"),
+        }
+    }
+
+    pub fn map_phone_code(row: SqliteRow) -> PhoneCode {
+        PhoneCode {
+            code: row.get("code"),
+            expires_at: row.get("expires_at"),
+            verified: row.get("verified"),
+        }
+    }
+
+    pub fn map_user_session(row: SqliteRow) -> UserSession {
+        UserSession {
+            id: row.get("id"),
+            password_pending: row.get("password_pending"),
+            setup_password_required: row.get("setup_password_required"),
+            hash: row.get("hash"),
+            info: ClientInfo {
+                api_id: row.get("api_id"),
+                device_model: row.get("device_model"),
+                platform: row.get("platform"),
+                system_version: row.get("system_version"),
+                app_version: row.get("app_version"),
+                ip: row.get("ip"),
+            },
+            created_at: row.get("created_at"),
+            active_at: row.get("active_at"),
+        }
+    }
+
+    pub fn map_exported_authorization(row: SqliteRow) -> ExportedAuthorization {
+        ExportedAuthorization {
+            bytes: row.get("bytes"),
+            user_id: row.get("user_id"),
+            dc_id: row.get("dc_id"),
+            expires_at: row.get("expires_at"),
+        }
+    }
+
+    pub fn map_login_token(row: SqliteRow) -> LoginToken {
+        LoginToken {
+            auth_key_id: row.get("auth_key_id"),
+            user_id: row.get("user_id"),
+            expires_at: row.get("expires_at"),
+        }
+    }
+
+    pub fn map_password(row: SqliteRow) -> Password {
+        Password {
+            salt1: row.get("salt1"),
+            salt2: row.get("salt2"),
+            verifier: row.get("verifier"),
+            hint: row.get("hint"),
+            email: row.get("email"),
+            failed_attempts: row.get("failed_attempts"),
+            failed_at: row.get("failed_at"),
+        }
+    }
+
+    pub fn map_dialog(row: SqliteRow) -> Result<Dialog, sqlx::Error> {
+        let draft = match row.get::<Option<Vec<u8>>, _>("draft") {
+            Some(data) => Some(
+                read_draft_message_variant(&mut TlBuffer::new(data))
+                    .map_err(|e| sqlx::Error::Decode(Box::new(e)))?,
+            ),
+            None => None,
+        };
+        let folder_id: i32 = row.get("folder_id");
+        Ok(Dialog {
+            pinned: row.get::<i32, _>("pinned") != 0,
+            unread_mark: row.get("unread_mark"),
+            peer: PeerVariant::PeerUser(Box::new(PeerUser {
+                user_id: row.get("peer_id"),
+            })),
+            top_message: row.get("top_message"),
+            read_inbox_max_id: row.get("read_inbox_max_id"),
+            read_outbox_max_id: row.get("read_outbox_max_id"),
+            unread_count: row.get("unread_count"),
+            unread_mentions_count: row.get("unread_mentions_count"),
+            unread_reactions_count: 0,
+            notify_settings: PeerNotifySettings {
+                show_previews: row.get("show_previews"),
+                silent: row.get("silent"),
+                mute_until: row.get("mute_until"),
+                ios_sound: None,
+                android_sound: None,
+                other_sound: None,
+            },
+            pts: None,
+            draft,
+            folder_id: if folder_id != 0 { Some(folder_id) } else { None },
+            ttl_period: None,
+        })
+    }
+
+    pub fn map_message(row: SqliteRow) -> Result<Message, sqlx::Error> {
+        let mut message = Message {
+            id: row.get("id"),
+            out: row.get("out"),
+            mentioned: row.get("mentioned"),
+            media_unread: row.get("media_unread"),
+            message: row.get("message"),
+            date: row.get("date"),
+            edit_date: row.get("edit_date"),
+            pinned: row.get("pinned"),
+            from_scheduled: row.get("from_scheduled"),
+            peer_id: PeerVariant::PeerUser(Box::new(PeerUser {
+                user_id: row.get("peer_id"),
+            })),
+            ..Default::default()
+        };
+        // Incoming private messages are identified by peer_id alone
+        if message.out {
+            message.from_id = Some(PeerVariant::PeerUser(Box::new(PeerUser {
+                user_id: row.get("from_id"),
+            })));
+        }
+        if let Some(reply_to_msg_id) = row.get::<Option<i32>, _>("reply_to_msg_id") {
+            let reply_to_top_id: Option<i32> = row.get("reply_to_top_id");
+            message.reply_to = Some(MessageReplyHeader {
+                reply_to_scheduled: false,
+                forum_topic: false,
+                reply_to_msg_id,
+                reply_to_peer_id: None,
+                reply_to_top_id: reply_to_top_id.filter(|&top_id| top_id != reply_to_msg_id),
+            });
+        }
+        if let Some(data) = row.get::<Option<Vec<u8>>, _>("fwd_from") {
+            let mut data = TlBuffer::new(data);
+            // Skip the constructor id
+            data.read_int()
+                .map_err(|e| sqlx::Error::Decode(Box::new(e)))?;
+            message.fwd_from = Some(
+                read_message_fwd_header(&mut data)
+                    .map_err(|e| sqlx::Error::Decode(Box::new(e)))?,
+            );
+        }
+        if let Some(data) = row.get::<Option<Vec<u8>>, _>("entities") {
+            message.entities = Some(
+                Storage::read_entities(data).map_err(|e| sqlx::Error::Decode(Box::new(e)))?,
+            );
+        }
+        Ok(message)
+    }
+
+    pub fn map_message_variant(row: SqliteRow) -> Result<MessageVariant, sqlx::Error> {
+        let Some(data) = row.get::<Option<Vec<u8>>, _>("action") else {
+            return Ok(MessageVariant::Message(Box::new(Storage::map_message(row)?)));
+        };
+        let mut data = TlBuffer::new(data);
+        let action =
+            read_message_action_variant(&mut data).map_err(|e| sqlx::Error::Decode(Box::new(e)))?;
+        // Service messages share everything but the text with regular ones
+        let message = Storage::map_message(row)?;
+        Ok(MessageVariant::MessageService(Box::new(MessageService {
+            out: message.out,
+            mentioned: message.mentioned,
+            media_unread: message.media_unread,
+            silent: message.silent,
+            post: false,
+            legacy: false,
+            id: message.id,
+            from_id: message.from_id,
+            peer_id: message.peer_id,
+            reply_to: message.reply_to,
+            date: message.date,
+            action,
+            ttl_period: None,
+        })))
+    }
+
+    /// Entities are stored as their count followed by each serialized entity
+    fn write_entities(entities: &[MessageEntityVariant]) -> Vec<u8> {
+        let mut data = TlBuffer::new(vec![]);
+        data.write_int(entities.len() as i32);
+        for entity in entities {
+            entity.write(&mut data);
+        }
+        data.data().to_vec()
+    }
+
+    fn has_url(entities: Option<&[MessageEntityVariant]>) -> bool {
+        entities.into_iter().flatten().any(|entity| {
+            matches!(
+                entity,
+                MessageEntityVariant::MessageEntityUrl(_)
+                    | MessageEntityVariant::MessageEntityTextUrl(_)
+            )
+        })
+    }
+
+    fn read_entities(data: Vec<u8>) -> Result<Vec<MessageEntityVariant>, TlBufferError> {
+        let mut data = TlBuffer::new(data);
+        let count = data.read_int()?;
+        (0..count)
+            .map(|_| read_message_entity_variant(&mut data))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::v;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// Fresh temporary data directory
+    pub fn data_dir() -> String {
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "cattegram-test-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    /// Storage backed by a fresh database in a temporary directory
+    pub async fn storage() -> Storage {
+        Storage::new(data_dir()).await
+    }
+
+    #[tokio::test]
+    async fn phone_code_is_deleted_after_max_attempts() {
+        let storage = storage().await;
+        storage
+            .insert_phone_code("15550000000", "hash", "12345", 100)
+            .await
+            .unwrap();
+        for _ in 0..4 {
+            assert!(storage.fail_phone_code("hash", 5).await.unwrap());
+        }
+        assert!(storage.get_phone_code("15550000000", "hash").await.is_ok());
+        assert!(!storage.fail_phone_code("hash", 5).await.unwrap());
+        assert!(storage.get_phone_code("15550000000", "hash").await.is_err());
+        assert!(!storage.fail_phone_code("hash", 5).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn phone_code_attempts_survive_resend() {
+        let storage = storage().await;
+        storage
+            .insert_phone_code("15550000000", "hash", "12345", 100)
+            .await
+            .unwrap();
+        assert!(storage.fail_phone_code("hash", 2).await.unwrap());
+        storage
+            .update_phone_code("hash", "54321", 200)
+            .await
+            .unwrap();
+        assert!(!storage.fail_phone_code("hash", 2).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn new_phone_code_replaces_the_old_one() {
+        let storage = storage().await;
+        storage
+            .insert_phone_code("15550000000", "old", "12345", 100)
+            .await
+            .unwrap();
+        storage
+            .insert_phone_code("15550000000", "new", "54321", 100)
+            .await
+            .unwrap();
+        assert!(storage.get_phone_code("15550000000", "old").await.is_err());
+        assert!(!storage.fail_phone_code("old", 5).await.unwrap());
+    }
+
+    #[test]
+    fn phone_code_expires_after_expires_at() {
+        let code = PhoneCode {
+            code: "12345".into(),
+            expires_at: 100,
+            verified: false,
+        };
+        assert!(!code.expired(99));
+        assert!(!code.expired(100));
+        assert!(code.expired(101));
+    }
+
+    #[tokio::test]
+    async fn inactive_sessions_are_deleted_after_ttl() {
+        let storage = storage().await;
+        storage
+            .insert_session(1, 1, false, &ClientInfo::default())
+            .await
+            .unwrap();
+        assert_eq!(storage.delete_inactive_sessions(60).await.unwrap(), 0);
+        assert_eq!(storage.get_user_sessions(1).await.unwrap().len(), 1);
+        assert_eq!(storage.delete_inactive_sessions(-60).await.unwrap(), 1);
+        assert!(storage.get_user_sessions(1).await.unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_updates_get_distinct_pts() {
+        let storage = Arc::new(storage().await);
+        let tasks: Vec<_> = (0..20)
+            .map(|i| {
+                let storage = storage.clone();
+                tokio::spawn(async move {
+                    storage
+                        .log_update(1, 1, |pts| {
+                            v!(UpdateVariant::UpdateDeleteMessages {
+                                messages: vec![i],
+                                pts,
+                                pts_count: 1,
+                            })
+                        })
+                        .await
+                        .unwrap()
+                })
+            })
+            .collect();
+        let mut pts = vec![];
+        for task in tasks {
+            pts.push(task.await.unwrap());
+        }
+        pts.sort();
+        assert_eq!(pts, (1..=20).collect::<Vec<_>>());
+
+        let logged = storage.get_updates(1, 0, 100).await.unwrap();
+        assert_eq!(logged.iter().map(|u| u.pts).collect::<Vec<_>>(), pts);
+        assert_eq!(storage.get_user_state(1).await.unwrap().pts, 20);
+    }
+
+    #[tokio::test]
+    async fn seq_updates_are_kept_until_every_session_got_them() {
+        let storage = storage().await;
+        let status = v!(UpdateVariant::UpdateUserStatus {
+            user_id: 1,
+            status: v!(UserStatusVariant::UserStatusEmpty {}),
+        });
+        for id in [1, 2] {
+            storage
+                .insert_session(id, 1, false, &ClientInfo::default())
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            storage
+                .log_seq_updates(1, &[status.clone(), status.clone(), status])
+                .await
+                .unwrap(),
+            (1, 3)
+        );
+
+        storage.set_session_seq(1, 1, 3).await.unwrap();
+        let seqs = |updates: Vec<(i32, UpdateVariant)>| {
+            updates.into_iter().map(|(seq, _)| seq).collect::<Vec<_>>()
+        };
+        assert_eq!(seqs(storage.get_seq_updates(1, 1, 10).await.unwrap()), [2, 3]);
+
+        storage.set_session_seq(1, 2, 2).await.unwrap();
+        storage.set_session_seq(1, 1, 1).await.unwrap();
+        assert_eq!(storage.get_session_seq(1).await.unwrap(), 3);
+        assert_eq!(seqs(storage.get_seq_updates(1, 0, 10).await.unwrap()), [3]);
+
+        // Sessions logged in later start at the current seq
+        storage
+            .insert_session(3, 1, false, &ClientInfo::default())
+            .await
+            .unwrap();
+        assert_eq!(storage.get_session_seq(3).await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn random_id_can_only_be_reserved_once() {
+        let storage = storage().await;
+        assert!(storage.reserve_random_id(1, 7).await.unwrap());
+        assert!(!storage.reserve_random_id(1, 7).await.unwrap());
+        assert!(storage.reserve_random_id(2, 7).await.unwrap());
+
+        storage.release_random_id(1, 7).await.unwrap();
+        assert!(storage.get_random_id(1, 7).await.unwrap().is_none());
+        assert!(storage.reserve_random_id(1, 7).await.unwrap());
+
+        let sent = SentMessage {
+            message_id: 3,
+            pts: 4,
+        };
+        storage.set_random_id(1, 7, &sent).await.unwrap();
+        // Sent messages keep their random_id
+        storage.release_random_id(1, 7).await.unwrap();
+        let sent = storage.get_random_id(1, 7).await.unwrap().unwrap();
+        assert_eq!((sent.message_id, sent.pts), (3, 4));
+        assert!(!storage.reserve_random_id(1, 7).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn privacy_rules_are_stored_per_key() {
+        let storage = storage().await;
+        let forwards = v!(PrivacyKeyVariant::PrivacyKeyForwards {});
+        let phone = v!(PrivacyKeyVariant::PrivacyKeyPhoneNumber {});
+        assert!(storage.get_privacy_rules(1, &forwards).await.unwrap().is_empty());
+
+        storage
+            .set_privacy_rules(
+                1,
+                &forwards,
+                &[
+                    v!(PrivacyRuleVariant::PrivacyValueAllowUsers { users: vec![2, 3] }),
+                    v!(PrivacyRuleVariant::PrivacyValueDisallowAll {}),
+                ],
+            )
+            .await
+            .unwrap();
+        let rules = storage.get_privacy_rules(1, &forwards).await.unwrap();
+        assert!(matches!(
+            rules.as_slice(),
+            [
+                PrivacyRuleVariant::PrivacyValueAllowUsers(allow),
+                PrivacyRuleVariant::PrivacyValueDisallowAll(_),
+            ] if allow.users == [2, 3]
+        ));
+        assert!(storage.get_privacy_rules(1, &phone).await.unwrap().is_empty());
+        assert!(storage.get_privacy_rules(2, &forwards).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn search_survives_vacuum() {
+        let storage = storage().await;
+        for text in ["hello world", "goodbye", "hello again", "hello there"] {
+            storage
+                .insert_message(
+                    1,
+                    &NewMessage {
+                        peer_id: 2,
+                        from_id: 1,
+                        out: true,
+                        message: text.to_string(),
+                        date: 100,
+                        reply_to_msg_id: None,
+                        reply_to_top_id: None,
+                        fwd_from: None,
+                        entities: None,
+                        from_scheduled: false,
+                        mentioned: false,
+                        media_unread: false,
+                    },
+                )
+                .await
+                .unwrap();
+        }
+        // Leave a gap in the rowids for VACUUM to close
+        sqlx::query("DELETE FROM messages WHERE user_id = 1 AND id = 1")
+            .execute(&storage.db)
+            .await
+            .unwrap();
+        sqlx::query("VACUUM").execute(&storage.db).await.unwrap();
+        storage.edit_message(1, 4, "bye", None, 200).await.unwrap();
+
+        let filter = MessageFilter {
+            max_id: i32::MAX,
+            query: "hello".to_string(),
+            ..Default::default()
+        };
+        let ids = storage
+            .get_messages(1, 0, &filter, 0, 10)
+            .await
+            .unwrap()
+            .iter()
+            .map(|message| match message {
+                MessageVariant::Message(message) => message.id,
+                _ => 0,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(ids, [3]);
+        assert_eq!(storage.count_messages(1, 0, &filter, 0).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn claimed_scheduled_messages_go_back_to_the_box_on_failure() {
+        let storage = storage().await;
+        let scheduled = storage
+            .insert_scheduled_message(
+                1,
+                &ScheduledMessage {
+                    id: 0,
+                    peer_id: 2,
+                    message: "hello".into(),
+                    entities: None,
+                    reply_to_msg_id: None,
+                    date: 100,
+                    silent: false,
+                },
+            )
+            .await
+            .unwrap();
+        let id = scheduled.id;
+        assert!(storage
+            .claim_scheduled_message(1, id)
+            .await
+            .unwrap()
+            .is_some());
+        assert!(storage
+            .claim_scheduled_message(1, id)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(storage
+            .take_scheduled_message(1, id)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(storage
+            .get_due_scheduled_messages(100)
+            .await
+            .unwrap()
+            .is_empty());
+
+        storage.release_scheduled_message(1, id).await.unwrap();
+        assert_eq!(
+            storage.get_due_scheduled_messages(100).await.unwrap(),
+            [(1, id)]
+        );
+
+        assert!(storage
+            .claim_scheduled_message(1, id)
+            .await
+            .unwrap()
+            .is_some());
+        storage.release_scheduled_messages().await.unwrap();
+        assert!(storage
+            .claim_scheduled_message(1, id)
+            .await
+            .unwrap()
+            .is_some());
+        storage.finish_scheduled_message(1, id).await.unwrap();
+        assert!(storage
+            .get_scheduled_message(1, id)
+            .await
+            .unwrap()
+            .is_none());
     }
 }
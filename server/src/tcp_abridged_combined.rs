@@ -9,6 +9,8 @@ pub struct TcpAbridgedCombined {
     socket: TcpStream,
     encrypt: Option<Aes256Ctr>,
     decrypt: Option<Aes256Ctr>,
+    // Already decrypted bytes that are not part of a complete packet yet
+    buffer: Vec<u8>,
 }
 
 impl TcpAbridgedCombined {
@@ -17,53 +19,56 @@ impl TcpAbridgedCombined {
             socket,
             encrypt,
             decrypt,
+            buffer: vec![],
         }
     }
+
+    /// Reads from the socket until at least `length` bytes are buffered,
+    /// partial reads are kept in the buffer so this is cancel safe
+    async fn fill(&mut self, length: usize) -> Result<(), std::io::Error> {
+        let mut chunk = [0u8; 4096];
+        while self.buffer.len() < length {
+            let read = self.socket.read(&mut chunk).await?;
+            if read == 0 {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+
+            if let Some(c) = self.encrypt.as_mut() {
+                c.apply_keystream(&mut chunk[..read]);
+            }
+
+            self.buffer.extend_from_slice(&chunk[..read]);
+        }
+        Ok(())
+    }
 }
 
 #[async_trait]
 impl Transport for TcpAbridgedCombined {
     async fn read(&mut self) -> Result<(Vec<u8>, bool), std::io::Error> {
-        let mut buf = vec![0];
-        self.socket.read_exact(&mut buf[..1]).await?;
-
-        self.encrypt
-            .as_mut()
-            .map(|c| c.apply_keystream(&mut buf[..1]));
-
-        let (length, quick_ack) = if buf[0] == 0x7f {
-            // Extended length
-            let mut lbuf = [0u8; 4];
-            self.socket.read_exact(&mut lbuf[..3]).await?;
-
-            self.encrypt
-                .as_mut()
-                .map(|c| c.apply_keystream(&mut lbuf[..3]));
-
-            ((u32::from_le_bytes(lbuf) as usize) * 4, false)
-        } else if buf[0] == 0xff {
-            // Extended length + Quick ACK
-            let mut lbuf = [0u8; 4];
-            self.socket.read_exact(&mut lbuf[..3]).await?;
-
-            self.encrypt
-                .as_mut()
-                .map(|c| c.apply_keystream(&mut lbuf[..3]));
-
-            ((u32::from_le_bytes(lbuf) as usize) * 4, true)
-        } else if buf[0] & (1 << 7) != 0 {
+        self.fill(1).await?;
+
+        let (header_length, length, quick_ack) = if self.buffer[0] == 0x7f || self.buffer[0] == 0xff
+        {
+            // Extended length, 0xff also requests a Quick ACK
+            self.fill(4).await?;
+            let lbuf = [self.buffer[1], self.buffer[2], self.buffer[3], 0];
+            (
+                4,
+                (u32::from_le_bytes(lbuf) as usize) * 4,
+                self.buffer[0] == 0xff,
+            )
+        } else if self.buffer[0] & (1 << 7) != 0 {
             // Normal length + Quick ACK
-            (usize::from(buf[0] ^ (1 << 7)) * 4, true)
+            (1, usize::from(self.buffer[0] ^ (1 << 7)) * 4, true)
         } else {
             // Normal length
-            (usize::from(buf[0]) * 4, false)
+            (1, usize::from(self.buffer[0]) * 4, false)
         };
-        buf.resize(length, 0);
-        self.socket.read_exact(&mut buf[..length]).await?;
 
-        self.encrypt
-            .as_mut()
-            .map(|c| c.apply_keystream(&mut buf[..length]));
+        self.fill(header_length + length).await?;
+        let buf = self.buffer[header_length..header_length + length].to_vec();
+        self.buffer.drain(..header_length + length);
 
         Ok((buf, quick_ack))
     }
@@ -79,22 +84,22 @@ impl Transport for TcpAbridgedCombined {
         };
         let mut encrypted_data = [length, data.to_vec()].concat();
 
-        self.decrypt
-            .as_mut()
-            .map(|c| c.apply_keystream(&mut encrypted_data));
+        if let Some(c) = self.decrypt.as_mut() {
+            c.apply_keystream(&mut encrypted_data);
+        }
 
-        self.socket.write(&encrypted_data).await?;
+        self.socket.write_all(&encrypted_data).await?;
         Ok(())
     }
 
     async fn write_quick_ack(&mut self, ack_token: u32) -> Result<(), std::io::Error> {
         let mut encrypted_data = ack_token.to_be_bytes();
 
-        self.decrypt
-            .as_mut()
-            .map(|c| c.apply_keystream(&mut encrypted_data));
+        if let Some(c) = self.decrypt.as_mut() {
+            c.apply_keystream(&mut encrypted_data);
+        }
 
-        self.socket.write(&encrypted_data).await?;
+        self.socket.write_all(&encrypted_data).await?;
         Ok(())
     }
 